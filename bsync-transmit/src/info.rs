@@ -0,0 +1,228 @@
+use std::{
+  fs::{self, File},
+  io::{Read, Seek, SeekFrom, Write},
+  os::unix::fs::{FileTypeExt, MetadataExt},
+  path::{Path, PathBuf},
+};
+
+/// Bytes read from the start of the image to find a header with a UUID. The btrfs superblock is
+/// the furthest, at 64KiB.
+const HEADER_LEN: u64 = 0x11000;
+
+/// Prints the size, geometry and identity of the image as `key=value` lines. Keys that aren't
+/// available are left out.
+pub fn print(f: &mut File, out: &mut impl Write) {
+  f.seek(SeekFrom::End(0)).unwrap();
+  let size = f.stream_position().unwrap();
+  writeln!(out, "size={}", size).unwrap();
+
+  let md = f.metadata().unwrap();
+  if md.file_type().is_block_device() {
+    let sys = PathBuf::from(format!(
+      "/sys/dev/block/{}:{}",
+      major(md.rdev()),
+      minor(md.rdev())
+    ));
+    // Partitions share the queue and the device of their disk.
+    let disk_attr =
+      |name: &str| read_attr(&sys.join(name)).or_else(|| read_attr(&sys.join("..").join(name)));
+    let attrs = [
+      ("logical_sector_size", disk_attr("queue/logical_block_size")),
+      (
+        "physical_sector_size",
+        disk_attr("queue/physical_block_size"),
+      ),
+      (
+        "wwid",
+        disk_attr("wwid").or_else(|| disk_attr("device/wwid")),
+      ),
+      ("serial", disk_attr("device/serial")),
+      ("partition", read_attr(&sys.join("partition"))),
+      ("dm_name", read_attr(&sys.join("dm/name"))),
+    ];
+    for (key, value) in attrs.iter() {
+      if let Some(value) = value {
+        writeln!(out, "{}={}", key, value).unwrap();
+      }
+    }
+  }
+
+  let mut header = vec![];
+  f.seek(SeekFrom::Start(0)).unwrap();
+  f.take(HEADER_LEN).read_to_end(&mut header).unwrap();
+  if let Some(uuid) = header_uuid(&header) {
+    writeln!(out, "uuid={}", uuid).unwrap();
+  }
+}
+
+/// Reads a sysfs attribute. Empty values are treated as missing.
+fn read_attr(path: &Path) -> Option<String> {
+  let value = fs::read_to_string(path).ok()?;
+  let value = value.trim();
+  if value.is_empty() || value.contains('\n') {
+    None
+  } else {
+    Some(value.to_string())
+  }
+}
+
+/// Decodes `dev_t` like glibc's `major`: 12 bits at 8, and the rest from 44.
+fn major(dev: u64) -> u64 {
+  ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000)
+}
+
+/// Decodes `dev_t` like glibc's `minor`: 8 bits at 0, and the rest from 20.
+fn minor(dev: u64) -> u64 {
+  (dev & 0xff) | ((dev >> 12) & 0xffff_ff00)
+}
+
+/// Returns the UUID of a LUKS header, a GPT partition table or an XFS, btrfs or ext2/3/4
+/// filesystem at the start of the image, prefixed with its type.
+fn header_uuid(h: &[u8]) -> Option<String> {
+  let at = |offset: usize, len: usize| h.get(offset..offset + len);
+
+  if at(0, 6)? == b"LUKS\xba\xbe" {
+    let uuid = at(168, 40)?;
+    let uuid = &uuid[..uuid.iter().position(|x| *x == 0).unwrap_or(uuid.len())];
+    return Some(format!("luks:{}", String::from_utf8_lossy(uuid)));
+  }
+  if at(0, 4)? == b"XFSB" {
+    return Some(format!("xfs:{}", format_uuid(at(32, 16)?)));
+  }
+  if at(512, 8) == Some(b"EFI PART") {
+    // The disk GUID is stored with its first three fields in little endian.
+    let mut guid = at(512 + 56, 16)?.to_vec();
+    guid[0..4].reverse();
+    guid[4..6].reverse();
+    guid[6..8].reverse();
+    return Some(format!("gpt:{}", format_uuid(&guid)));
+  }
+  if at(0x10040, 8) == Some(b"_BHRfS_M") {
+    return Some(format!("btrfs:{}", format_uuid(at(0x10020, 16)?)));
+  }
+  if at(1024 + 56, 2) == Some(&[0x53, 0xef]) {
+    return Some(format!("ext:{}", format_uuid(at(1024 + 104, 16)?)));
+  }
+  None
+}
+
+fn format_uuid(x: &[u8]) -> String {
+  let hex = x.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+  format!(
+    "{}-{}-{}-{}-{}",
+    &hex[0..8],
+    &hex[8..12],
+    &hex[12..16],
+    &hex[16..20],
+    &hex[20..32]
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const UUID: [u8; 16] = [
+    0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32, 0x10,
+  ];
+
+  fn header(writes: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut h = vec![0u8; HEADER_LEN as usize];
+    for (offset, data) in writes {
+      h[*offset..*offset + data.len()].copy_from_slice(data);
+    }
+    h
+  }
+
+  #[test]
+  fn luks() {
+    let uuid = b"5d8c1a4e-7d0b-4f5e-9a3b-2c1d0e9f8a7b";
+    let h = header(&[(0, b"LUKS\xba\xbe"), (168, uuid)]);
+    assert_eq!(
+      header_uuid(&h).unwrap(),
+      "luks:5d8c1a4e-7d0b-4f5e-9a3b-2c1d0e9f8a7b"
+    );
+    // The field is 40 bytes, and only null-terminated if shorter.
+    let h = header(&[(0, b"LUKS\xba\xbe"), (168, &[b'a'; 40]), (208, b"b")]);
+    assert_eq!(header_uuid(&h).unwrap(), format!("luks:{}", "a".repeat(40)));
+  }
+
+  #[test]
+  fn xfs() {
+    let h = header(&[(0, b"XFSB"), (32, &UUID)]);
+    assert_eq!(
+      header_uuid(&h).unwrap(),
+      "xfs:01234567-89ab-cdef-fedc-ba9876543210"
+    );
+  }
+
+  #[test]
+  fn gpt() {
+    // The on-disk form of 01234567-89ab-cdef-fedc-ba9876543210.
+    let guid = [
+      0x67, 0x45, 0x23, 0x01, 0xab, 0x89, 0xef, 0xcd, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32,
+      0x10,
+    ];
+    let h = header(&[(510, &[0x55, 0xaa]), (512, b"EFI PART"), (568, &guid)]);
+    assert_eq!(
+      header_uuid(&h).unwrap(),
+      "gpt:01234567-89ab-cdef-fedc-ba9876543210"
+    );
+  }
+
+  #[test]
+  fn btrfs() {
+    let h = header(&[(0x10040, b"_BHRfS_M"), (0x10020, &UUID)]);
+    assert_eq!(
+      header_uuid(&h).unwrap(),
+      "btrfs:01234567-89ab-cdef-fedc-ba9876543210"
+    );
+  }
+
+  #[test]
+  fn ext() {
+    let h = header(&[(1080, &[0x53, 0xef]), (1128, &UUID)]);
+    assert_eq!(
+      header_uuid(&h).unwrap(),
+      "ext:01234567-89ab-cdef-fedc-ba9876543210"
+    );
+  }
+
+  #[test]
+  fn no_header() {
+    assert_eq!(header_uuid(&header(&[])), None);
+    assert_eq!(header_uuid(&[0xff; 4096]), None);
+    // Images shorter than the headers.
+    assert_eq!(header_uuid(&[]), None);
+    assert_eq!(header_uuid(b"XFSB"), None);
+    assert_eq!(header_uuid(&header(&[(1080, &[0x53, 0xef])])[..1100]), None);
+  }
+
+  #[test]
+  fn device_numbers() {
+    // The encoding of glibc's `makedev`.
+    fn makedev(major: u64, minor: u64) -> u64 {
+      ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12)
+    }
+    for (ma, mi) in [(8, 1), (259, 0), (253, 3), (0xfff, 0xff), (0x1234, 0x12345)] {
+      let dev = makedev(ma, mi);
+      assert_eq!((major(dev), minor(dev)), (ma, mi));
+    }
+    assert_eq!((major(0x801), minor(0x801)), (8, 1));
+  }
+
+  #[test]
+  fn print_file() {
+    let path = std::env::temp_dir().join(format!("bsync-transmit-info-{}", std::process::id()));
+    let mut content = header(&[(1080, &[0x53, 0xef]), (1128, &UUID)]);
+    content.resize(1 << 20, 0);
+    fs::write(&path, &content).unwrap();
+    let mut out = vec![];
+    print(&mut File::open(&path).unwrap(), &mut out);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "size=1048576\nuuid=ext:01234567-89ab-cdef-fedc-ba9876543210\n"
+    );
+  }
+}
@@ -4,6 +4,84 @@ use std::{
   io::{stdout, BufWriter, Read, Seek, SeekFrom, Write},
 };
 
+mod info;
+
+use sha2::{compress256, digest::generic_array::GenericArray};
+
+const SHA256_IV: [u32; 8] = [
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256 of the image, computed across `hash` invocations. Each one resumes from the state the
+/// previous one printed. Every invocation but the last reads whole chunks, so the state is always
+/// saved at a SHA-256 block boundary.
+struct ImageSha256 {
+  state: [u32; 8],
+  len: u64,
+  tail: Vec<u8>,
+}
+
+impl ImageSha256 {
+  /// Parses the state argument: `-` to start at offset 0, or the state printed by the previous
+  /// invocation, in hex.
+  fn resume(arg: &str, offset: u64) -> Self {
+    let state = if arg == "-" {
+      assert_eq!(offset, 0);
+      SHA256_IV
+    } else {
+      assert_eq!(arg.len(), 64);
+      let mut state = [0u32; 8];
+      for (i, x) in state.iter_mut().enumerate() {
+        *x = u32::from_str_radix(&arg[i * 8..i * 8 + 8], 16).expect("bad sha256 state");
+      }
+      state
+    };
+    Self {
+      state,
+      len: offset,
+      tail: vec![],
+    }
+  }
+
+  /// Only the last call may pass a length that isn't a multiple of 64.
+  fn update(&mut self, data: &[u8]) {
+    assert!(self.tail.is_empty());
+    let blocks = data.chunks_exact(64);
+    self.tail = blocks.remainder().to_vec();
+    let blocks: Vec<_> = blocks.map(|x| *GenericArray::from_slice(x)).collect();
+    compress256(&mut self.state, &blocks);
+    self.len += data.len() as u64;
+  }
+
+  fn state(&self) -> [u8; 32] {
+    assert!(self.tail.is_empty());
+    words_to_bytes(&self.state)
+  }
+
+  fn finish(mut self) -> [u8; 32] {
+    let mut padding = std::mem::take(&mut self.tail);
+    padding.push(0x80);
+    while padding.len() % 64 != 56 {
+      padding.push(0);
+    }
+    padding.extend_from_slice(&(self.len * 8).to_be_bytes());
+    let blocks: Vec<_> = padding
+      .chunks_exact(64)
+      .map(|x| *GenericArray::from_slice(x))
+      .collect();
+    compress256(&mut self.state, &blocks);
+    words_to_bytes(&self.state)
+  }
+}
+
+fn words_to_bytes(words: &[u32; 8]) -> [u8; 32] {
+  let mut out = [0u8; 32];
+  for (x, w) in out.chunks_exact_mut(4).zip(words) {
+    x.copy_from_slice(&w.to_be_bytes());
+  }
+  out
+}
+
 fn main() {
   let mut args = std::env::args();
   args.next().unwrap();
@@ -33,7 +111,21 @@ fn main() {
         .unwrap();
       let chunk_count: usize = args.next().expect("expecting chunk count").parse().unwrap();
       assert!(chunk_count > 0);
-      assert!(initial_offset % chunk_size == 0);
+      // `sha256 <state>` also hashes the bytes read, and prints the state to resume from after
+      // the block hashes, or the final digest if the end of the image was reached.
+      let mut sha256 = match args.next().as_deref() {
+        Some("sha256") => Some(ImageSha256::resume(
+          &args.next().expect("expecting sha256 state"),
+          initial_offset as u64,
+        )),
+        None => None,
+        Some(x) => panic!("bad option: {}", x),
+      };
+      // `is_multiple_of` needs Rust 1.87, which is newer than some of the toolchains transmit is
+      // built with.
+      #[allow(clippy::manual_is_multiple_of)]
+      let aligned = initial_offset % chunk_size == 0;
+      assert!(aligned);
       let end_offset = initial_offset
         .checked_add(chunk_size.checked_mul(chunk_count).unwrap())
         .unwrap();
@@ -49,11 +141,22 @@ fn main() {
         let read_len = end_offset.checked_sub(offset).unwrap();
         assert!(read_len > 0);
         f.read_exact(&mut buf[..read_len]).unwrap();
+        if let Some(x) = &mut sha256 {
+          x.update(&buf[..read_len]);
+        }
         buf[read_len..].fill(0);
 
         let hash: [u8; 32] = blake3::hash(&buf).into();
         stdout.write_all(&hash[..]).unwrap();
       }
+      if let Some(x) = sha256 {
+        let out = if end_offset as u64 == file_len {
+          x.finish()
+        } else {
+          x.state()
+        };
+        stdout.write_all(&out).unwrap();
+      }
     }
     "dump" => {
       let offset_list: Vec<usize> = args
@@ -79,7 +182,88 @@ fn main() {
         encoder.write_all(&buf).unwrap();
       }
     }
+    "info" => info::print(&mut f, &mut stdout),
     _ => panic!("bad op: {}", op),
   }
   stdout.flush().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+  use sha2::{Digest, Sha256};
+
+  use super::*;
+
+  fn hex(x: &[u8]) -> String {
+    x.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  /// Hashes `data` like a pull does: one invocation per `chunk` bytes, each resuming from the
+  /// state printed by the previous one.
+  fn resumed(data: &[u8], chunk: usize) -> [u8; 32] {
+    let mut arg = "-".to_string();
+    let mut offset = 0;
+    loop {
+      let end = (offset + chunk).min(data.len());
+      let mut h = ImageSha256::resume(&arg, offset as u64);
+      h.update(&data[offset..end]);
+      if end == data.len() {
+        return h.finish();
+      }
+      arg = hex(&h.state());
+      offset = end;
+    }
+  }
+
+  #[test]
+  fn matches_sha256() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    for len in [
+      0,
+      1,
+      55,
+      56,
+      63,
+      64,
+      65,
+      127,
+      128,
+      1000,
+      4096,
+      4096 + 17,
+      10_000,
+    ] {
+      let data = &data[..len];
+      let expected: [u8; 32] = Sha256::digest(data).into();
+      for chunk in [64, 128, 192, 1024, 4096, 1 << 20] {
+        assert_eq!(
+          resumed(data, chunk),
+          expected,
+          "len {} chunk {}",
+          len,
+          chunk
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn state_round_trip() {
+    let data = [0x5a; 256];
+    let mut h = ImageSha256::resume("-", 0);
+    h.update(&data[..128]);
+    let state = h.state();
+    let resumed = ImageSha256::resume(&hex(&state), 128);
+    assert_eq!(resumed.state(), state);
+    assert_eq!(resumed.len, 128);
+  }
+
+  #[test]
+  #[should_panic]
+  fn state_needs_whole_blocks() {
+    // Only the last invocation may end inside a SHA-256 block.
+    let mut h = ImageSha256::resume("-", 0);
+    h.update(&[0; 100]);
+    h.state();
+  }
+}
@@ -0,0 +1,73 @@
+use std::{borrow::Cow, collections::HashSet, time::Duration};
+
+use anyhow::Result;
+use shell_escape::unix::escape;
+use thiserror::Error;
+
+use crate::{
+  config::{AttachmentConfig, AttachmentSource},
+  interrupt,
+  output::say,
+  remote::Remote,
+};
+
+#[derive(Error, Debug)]
+#[error("bad attachment name: {0:?}")]
+pub struct BadAttachmentName(pub String);
+
+/// Checks that `name` can be used as a file name.
+pub fn check_name(name: &str) -> Result<()> {
+  if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+    return Err(BadAttachmentName(name.to_string()).into());
+  }
+  Ok(())
+}
+
+/// Captures `attachments` from the remote, and returns them as `(name, content)` pairs.
+///
+/// Each one is bounded by `timeout`, like the scripts. An attachment that can't be captured, e.g.
+/// because its command failed or timed out, doesn't fail the pull. It is left out and a warning is
+/// added to `warnings`.
+pub fn capture(
+  sess: &mut Remote,
+  attachments: &[AttachmentConfig],
+  image: &str,
+  timeout: Option<Duration>,
+  warnings: &mut Vec<String>,
+) -> Result<Vec<(String, Vec<u8>)>> {
+  let mut names = HashSet::new();
+  for x in attachments {
+    check_name(&x.name)?;
+    if !names.insert(&x.name) {
+      return Err(BadAttachmentName(x.name.clone()).into());
+    }
+  }
+
+  let mut out = vec![];
+  for x in attachments {
+    let cmd = match &x.source {
+      AttachmentSource::Command(cmd) => format!(
+        "export BSYNC_IMAGE={}\n{}",
+        escape(Cow::Borrowed(image)),
+        cmd
+      ),
+      AttachmentSource::File(path) => format!("cat -- {}", escape(Cow::Borrowed(path))),
+    };
+    match sess.exec_script_bin(&cmd, timeout) {
+      Ok(content) => {
+        log::info!("Captured attachment {} ({} bytes).", x.name, content.len());
+        out.push((x.name.clone(), content));
+      }
+      Err(e) => {
+        interrupt::check()?;
+        let msg = format!("cannot capture attachment {}: {:#}", x.name, e);
+        log::warn!("{}", msg);
+        warnings.push(msg);
+      }
+    }
+  }
+  if !out.is_empty() {
+    say!("Captured {} attachments.", out.len());
+  }
+  Ok(out)
+}
@@ -3,7 +3,7 @@ use phf::phf_map;
 
 use crate::config::LOG_BLOCK_SIZE;
 
-static X86_64_BLKXMIT: &'static [u8] =
+static X86_64_BLKXMIT: &[u8] =
   include_bytes!("../bsync-transmit-dist/bsync-transmit.x86_64-unknown-linux-musl");
 
 pub static ARCH_BLKXMIT: phf::Map<&'static str, &'static [u8]> = phf_map! {
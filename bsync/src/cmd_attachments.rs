@@ -0,0 +1,124 @@
+use std::{
+  io::Write,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use prettytable::{row, Table};
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  attachment,
+  db::{AttachmentInfo, Database, NotConsistentPoint},
+  output::{self, say},
+};
+
+#[derive(Error, Debug)]
+#[error("version {0} has no attachment {1:?}")]
+struct NoSuchAttachment(u64, String);
+
+/// List or extract the command outputs and files captured from the remote with a version (see
+/// `remote.attachments` in the config).
+#[derive(Debug, StructOpt)]
+pub struct Attachmentscmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// The LSN of the version.
+  #[structopt(long)]
+  lsn: u64,
+
+  /// Write the content of this attachment to stdout, or to `--output`.
+  #[structopt(long)]
+  extract: Option<String>,
+
+  /// With `--extract`, the file to write to. Otherwise, a directory to extract all attachments to.
+  #[structopt(short, long)]
+  output: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct AttachmentsSummary {
+  lsn: u64,
+  attachments: Vec<AttachmentInfo>,
+  /// Attachments written to files.
+  extracted: Vec<String>,
+}
+
+impl Attachmentscmd {
+  /// Whether the command writes an attachment to stdout, where no summary can follow it.
+  pub fn writes_to_stdout(&self) -> bool {
+    self.extract.is_some() && self.output.is_none()
+  }
+
+  pub fn run(&self) -> Result<()> {
+    let db = Database::open_file(&self.db, false)?;
+    if !db.list_consistent_point().iter().any(|x| x.lsn == self.lsn) {
+      return Err(NotConsistentPoint("lsn").into());
+    }
+    let mut summary = AttachmentsSummary {
+      lsn: self.lsn,
+      attachments: db.list_attachments(self.lsn),
+      extracted: vec![],
+    };
+
+    match (&self.extract, &self.output) {
+      (Some(name), output) => {
+        let content = db
+          .read_attachment(self.lsn, name)?
+          .ok_or_else(|| NoSuchAttachment(self.lsn, name.clone()))?;
+        match output {
+          Some(path) => {
+            std::fs::write(path, &content)?;
+            say!("Attachment {} written to {}.", name, path.to_string_lossy());
+            summary.extracted.push(name.clone());
+          }
+          None => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&content)?;
+            stdout.flush()?;
+            return Ok(());
+          }
+        }
+      }
+      (None, Some(dir)) => {
+        std::fs::create_dir_all(dir)?;
+        for x in &summary.attachments {
+          // Names are checked when captured, but the database may have been edited since.
+          attachment::check_name(&x.name)?;
+          let content = db
+            .read_attachment(self.lsn, &x.name)?
+            .ok_or_else(|| NoSuchAttachment(self.lsn, x.name.clone()))?;
+          std::fs::write(Path::new(dir).join(&x.name), &content)?;
+          summary.extracted.push(x.name.clone());
+        }
+        say!(
+          "{} attachments written to {}.",
+          summary.extracted.len(),
+          dir.to_string_lossy()
+        );
+      }
+      (None, None) => {
+        if !output::is_json() {
+          let mut table = Table::new();
+          table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+          table.set_titles(row!["NAME", "SIZE", "STORED"]);
+          for x in &summary.attachments {
+            table.add_row(row![
+              x.name,
+              format!("{}B", SizeFormatterBinary::new(x.size)),
+              format!("{}B", SizeFormatterBinary::new(x.stored_size)),
+            ]);
+          }
+          table.print_tty(false)?;
+        }
+      }
+    }
+    output::summary(&summary);
+    Ok(())
+  }
+}
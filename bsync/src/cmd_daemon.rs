@@ -0,0 +1,304 @@
+use std::{
+  os::unix::process::CommandExt,
+  path::{Path, PathBuf},
+  process::{Child, Command, Stdio},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{Local, NaiveDateTime};
+use parking_lot::Mutex;
+use rand::Rng;
+use signal_hook::consts::SIGHUP;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  config::{BackupConfig, ScheduleConfig},
+  interrupt, metrics,
+  output::{say, EXIT_GUARD, EXIT_HOST_KEY, EXIT_IDENTITY_CHANGED, EXIT_INTERRUPTED},
+  schedule::Schedule,
+};
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Run pulls on the schedules in their configs.
+#[derive(Debug, StructOpt)]
+pub struct Daemoncmd {
+  /// Path to a job config. Can be repeated.
+  #[structopt(short, long)]
+  config: Vec<PathBuf>,
+
+  /// Directory of job configs. Every `*.yaml` and `*.yml` file in it is loaded.
+  #[structopt(long)]
+  config_dir: Option<PathBuf>,
+
+  /// Maximum number of concurrent pulls.
+  #[structopt(long, default_value = "4")]
+  max_concurrent: usize,
+
+  /// Serve the metrics of all jobs at `http://<metrics-listen>/metrics`.
+  #[structopt(long)]
+  metrics_listen: Option<String>,
+}
+
+/// A scheduled pull, identified by the path of its config.
+struct Job {
+  path: PathBuf,
+  db: PathBuf,
+  cron: String,
+  schedule: Schedule,
+  jitter: u64,
+  retries: u32,
+  retry_delay: u64,
+
+  next_run: Option<NaiveDateTime>,
+  /// Failed attempts of the current run.
+  failures: u32,
+  child: Option<Child>,
+  last_success: Option<bool>,
+}
+
+impl Job {
+  fn load(path: &Path) -> Result<Option<Self>> {
+    let config = BackupConfig::load_from_file(path)?;
+    let sc: ScheduleConfig = match config.schedule {
+      Some(x) => x,
+      None => {
+        log::warn!("{:?} has no schedule - skipping.", path);
+        return Ok(None);
+      }
+    };
+    Ok(Some(Self {
+      path: path.to_path_buf(),
+      db: PathBuf::from(config.local.db),
+      schedule: sc.cron.parse()?,
+      cron: sc.cron,
+      jitter: sc.jitter,
+      retries: sc.retries,
+      retry_delay: sc.retry_delay,
+      next_run: None,
+      failures: 0,
+      child: None,
+      last_success: None,
+    }))
+  }
+
+  fn schedule_next(&mut self, now: NaiveDateTime) {
+    let jitter = rand::thread_rng().gen_range(0..=self.jitter);
+    self.next_run = self
+      .schedule
+      .next_after(now)
+      .map(|x| x + chrono::Duration::seconds(jitter as i64));
+    self.failures = 0;
+    match self.next_run {
+      Some(x) => log::info!("Next run of {:?} at {}.", self.path, x),
+      None => log::warn!("{:?} never runs again.", self.path),
+    }
+  }
+
+  fn start(&mut self) -> Result<()> {
+    say!("Starting pull of {:?}.", self.path);
+    let child = Command::new(std::env::current_exe()?)
+      .arg("pull")
+      .arg("-c")
+      .arg(&self.path)
+      .stdin(Stdio::null())
+      // The daemon forwards termination signals itself, so that a pull only gets one and has a
+      // chance to clean up.
+      .process_group(0)
+      .spawn()?;
+    self.child = Some(child);
+    self.next_run = None;
+    Ok(())
+  }
+
+  /// Checks whether the running pull has finished, and schedules the next run if so.
+  fn poll(&mut self, now: NaiveDateTime) -> Result<()> {
+    let status = match self.child.as_mut().map(|x| x.try_wait()).transpose()? {
+      Some(Some(x)) => x,
+      _ => return Ok(()),
+    };
+    self.child = None;
+    self.last_success = Some(status.success());
+    if status.success() {
+      say!("Pull of {:?} succeeded.", self.path);
+      self.schedule_next(now);
+      return Ok(());
+    }
+
+    // Don't retry failures that won't go away by themselves.
+    let retryable = !matches!(
+      status.code(),
+      Some(EXIT_HOST_KEY) | Some(EXIT_IDENTITY_CHANGED) | Some(EXIT_GUARD) | Some(EXIT_INTERRUPTED)
+    );
+    if retryable && self.failures < self.retries {
+      let delay = self.retry_delay.saturating_mul(1 << self.failures.min(16));
+      self.failures += 1;
+      say!(
+        "Pull of {:?} failed ({}) - retry {}/{} in {}s.",
+        self.path,
+        status,
+        self.failures,
+        self.retries,
+        delay
+      );
+      self.next_run = Some(now + chrono::Duration::seconds(delay as i64));
+    } else {
+      say!("Pull of {:?} failed ({}).", self.path, status);
+      self.schedule_next(now);
+    }
+    Ok(())
+  }
+}
+
+impl Daemoncmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    #[error("no job has a schedule")]
+    struct NoJobs;
+
+    interrupt::install()?;
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone())?;
+
+    let now = Local::now().naive_local();
+    let mut jobs = self.load_jobs()?;
+    if jobs.is_empty() {
+      return Err(NoJobs.into());
+    }
+    for job in &mut jobs {
+      job.schedule_next(now);
+    }
+    // Pulls of jobs removed by a reload, which are left to finish.
+    let mut orphans: Vec<Child> = vec![];
+
+    let repos = Arc::new(Mutex::new(vec![]));
+    if let Some(listen) = self.metrics_listen.clone() {
+      let repos = repos.clone();
+      std::thread::spawn(move || {
+        if let Err(e) = metrics::serve(&listen, || Ok(metrics::render_all(&repos.lock()))) {
+          log::error!("metrics server failed: {:?}", e);
+        }
+      });
+    }
+
+    say!("Scheduling {} jobs.", jobs.len());
+    while interrupt::check().is_ok() {
+      let now = Local::now().naive_local();
+
+      if reload.swap(false, Ordering::Relaxed) {
+        match self.load_jobs() {
+          Ok(new_jobs) => {
+            say!("Reloaded {} jobs.", new_jobs.len());
+            jobs = merge_jobs(jobs, new_jobs, &mut orphans, now);
+          }
+          Err(e) => log::error!("reload failed, keeping the current jobs: {:?}", e),
+        }
+      }
+
+      for job in &mut jobs {
+        if let Err(e) = job.poll(now) {
+          log::error!("cannot check pull of {:?}: {:?}", job.path, e);
+        }
+      }
+      orphans.retain_mut(|x| !matches!(x.try_wait(), Ok(Some(_))));
+
+      // Start due jobs, the most overdue first.
+      let mut running = jobs.iter().filter(|x| x.child.is_some()).count() + orphans.len();
+      let mut due: Vec<&mut Job> = jobs
+        .iter_mut()
+        .filter(|x| x.child.is_none() && matches!(x.next_run, Some(t) if t <= now))
+        .collect();
+      due.sort_by_key(|x| x.next_run);
+      for job in due {
+        if running >= self.max_concurrent {
+          break;
+        }
+        match job.start() {
+          Ok(()) => running += 1,
+          Err(e) => {
+            log::error!("cannot start pull of {:?}: {:?}", job.path, e);
+            job.schedule_next(now);
+          }
+        }
+      }
+
+      *repos.lock() = jobs
+        .iter()
+        .map(|x| (x.db.clone(), x.last_success))
+        .collect();
+      std::thread::sleep(TICK);
+    }
+
+    say!("Stopping - waiting for running pulls to clean up.");
+    let children = jobs
+      .iter_mut()
+      .filter_map(|x| x.child.as_mut())
+      .chain(orphans.iter_mut());
+    for child in children {
+      unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+      }
+      let _ = child.wait();
+    }
+    Ok(())
+  }
+
+  fn load_jobs(&self) -> Result<Vec<Job>> {
+    let mut paths = self.config.clone();
+    if let Some(dir) = &self.config_dir {
+      let mut dir_paths = vec![];
+      for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if matches!(
+          path.extension().and_then(|x| x.to_str()),
+          Some("yaml" | "yml")
+        ) {
+          dir_paths.push(path);
+        }
+      }
+      dir_paths.sort();
+      paths.extend(dir_paths);
+    }
+    let mut jobs = vec![];
+    for path in paths {
+      jobs.extend(Job::load(&path)?);
+    }
+    Ok(jobs)
+  }
+}
+
+/// Carries the state of running and scheduled pulls over to the reloaded jobs.
+fn merge_jobs(
+  old: Vec<Job>,
+  new: Vec<Job>,
+  orphans: &mut Vec<Child>,
+  now: NaiveDateTime,
+) -> Vec<Job> {
+  let mut old = old;
+  let mut jobs = vec![];
+  for mut job in new {
+    match old.iter().position(|x| x.path == job.path) {
+      Some(i) => {
+        let prev = old.swap_remove(i);
+        job.child = prev.child;
+        job.last_success = prev.last_success;
+        if prev.cron == job.cron && prev.jitter == job.jitter {
+          job.next_run = prev.next_run;
+          job.failures = prev.failures;
+        } else if job.child.is_none() {
+          job.schedule_next(now);
+        }
+      }
+      None => job.schedule_next(now),
+    }
+    jobs.push(job);
+  }
+  orphans.extend(old.into_iter().filter_map(|x| x.child));
+  jobs
+}
@@ -0,0 +1,69 @@
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  db::Database,
+  output::{self, say},
+};
+
+/// Delete a single version, including the latest one.
+#[derive(Debug, StructOpt)]
+pub struct Deletecmd {
+  /// LSN of the version to delete.
+  #[structopt(long)]
+  lsn: u64,
+
+  /// Data loss confirmation.
+  #[structopt(long)]
+  data_loss: bool,
+
+  /// Vacuum the database after deleting.
+  #[structopt(long)]
+  vacuum: bool,
+
+  /// Allow removing versions younger than the repository's `min_version_age_days`.
+  #[structopt(long)]
+  override_min_age: bool,
+
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+}
+
+#[derive(Serialize)]
+struct DeleteSummary {
+  lsn: u64,
+  duration_ms: u64,
+}
+
+impl Deletecmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    enum E {
+      #[error("delete removes history - please confirm by adding the flag `--data-loss`.")]
+      DataLoss,
+    }
+
+    let db = Database::open_file(&self.db, false)?;
+    if !self.data_loss {
+      return Err(E::DataLoss.into());
+    }
+
+    let start = Instant::now();
+    db.delete_consistent_point(self.lsn, self.override_min_age)?;
+    db.cas_gc();
+    if self.vacuum {
+      db.vacuum();
+    }
+    output::summary(&DeleteSummary {
+      lsn: self.lsn,
+      duration_ms: start.elapsed().as_millis() as u64,
+    });
+    say!("Deleted version {}.", self.lsn);
+    Ok(())
+  }
+}
@@ -0,0 +1,126 @@
+use std::{collections::HashMap, path::PathBuf, time::Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::{
+  blob::ZERO_BLOCK_HASH,
+  db::{block_count, ConsistentPoint, Database, NotConsistentPoint, LEASE_READ},
+  lease::Lease,
+  merkle::CHUNK_BLOCKS,
+  output::{self, say},
+  util::{block_ranges, format_ranges},
+};
+
+/// Show which blocks differ between two versions. The Merkle trees of the versions are walked to
+/// skip unchanged ranges, so only the blocks under changed nodes are compared. Versions pulled by
+/// an older bsync are compared block by block.
+#[derive(Debug, StructOpt)]
+pub struct Diffcmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// LSN of the first version.
+  #[structopt(long)]
+  from: u64,
+
+  /// LSN of the second version.
+  #[structopt(long)]
+  to: u64,
+}
+
+#[derive(Serialize)]
+struct DiffSummary {
+  from: u64,
+  to: u64,
+  from_size: u64,
+  to_size: u64,
+  /// Whether both versions have a Merkle tree.
+  merkle: bool,
+  /// Blocks whose hashes were compared.
+  compared_blocks: u64,
+  changed_blocks: u64,
+  /// Inclusive ranges of the blocks that differ, including blocks only one version has.
+  ranges: Vec<(u64, u64)>,
+  duration_ms: u64,
+}
+
+impl Diffcmd {
+  pub fn run(&self) -> Result<()> {
+    let start = Instant::now();
+    let db = Database::open_file(&self.db, false)?;
+    let cp_list = db.list_consistent_point();
+    let find = |lsn: u64| -> Result<ConsistentPoint> {
+      Ok(
+        cp_list
+          .iter()
+          .find(|x| x.lsn == lsn)
+          .cloned()
+          .ok_or(NotConsistentPoint("lsn"))?,
+      )
+    };
+    let (from, to) = (find(self.from)?, find(self.to)?);
+    let _leases = [
+      Lease::acquire(&db, LEASE_READ, Some(from.lsn), "diff")?,
+      Lease::acquire(&db, LEASE_READ, Some(to.lsn), "diff")?,
+    ];
+
+    let (from_blocks, to_blocks) = (block_count(from.size), block_count(to.size));
+    let trees = db
+      .merkle_tree(from.lsn, from.size)
+      .zip(db.merkle_tree(to.lsn, to.size));
+    let chunks = match &trees {
+      Some((a, b)) => a.changed_chunks(b),
+      None => (0..from_blocks.max(to_blocks).div_ceil(CHUNK_BLOCKS)).collect(),
+    };
+
+    let mut compared_blocks = 0;
+    let mut changed = vec![];
+    for chunk in chunks {
+      let first = chunk * CHUNK_BLOCKS;
+      let end = (first + CHUNK_BLOCKS).min(from_blocks.max(to_blocks));
+      let a: HashMap<u64, [u8; 32]> = db.block_hashes(from.lsn, first, end).into_iter().collect();
+      let b: HashMap<u64, [u8; 32]> = db.block_hashes(to.lsn, first, end).into_iter().collect();
+      for block_id in first..end {
+        let hash = |blocks: u64, hashes: &HashMap<u64, [u8; 32]>| {
+          if block_id < blocks {
+            Some(*hashes.get(&block_id).unwrap_or(&ZERO_BLOCK_HASH))
+          } else {
+            None
+          }
+        };
+        if hash(from_blocks, &a) != hash(to_blocks, &b) {
+          changed.push(block_id);
+        }
+      }
+      compared_blocks += end - first;
+    }
+
+    let ranges = block_ranges(&changed);
+    if changed.is_empty() {
+      say!("Versions {} and {} are identical.", from.lsn, to.lsn);
+    } else {
+      say!(
+        "{} blocks differ between versions {} and {}: {}",
+        changed.len(),
+        from.lsn,
+        to.lsn,
+        format_ranges(&ranges)
+      );
+    }
+    output::summary(&DiffSummary {
+      from: from.lsn,
+      to: to.lsn,
+      from_size: from.size,
+      to_size: to.size,
+      merkle: trees.is_some(),
+      compared_blocks,
+      changed_blocks: changed.len() as u64,
+      ranges,
+      duration_ms: start.elapsed().as_millis() as u64,
+    });
+    Ok(())
+  }
+}
@@ -1,12 +1,17 @@
 use serde::Serialize;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use chrono::NaiveDateTime;
-use prettytable::{cell, row, Table};
+use prettytable::{row, Table};
+use size_format::SizeFormatterBinary;
 use structopt::StructOpt;
 
-use crate::db::Database;
+use crate::{
+  db::{Database, PullMeta},
+  output,
+  source::SourceInfo,
+};
 
 /// List all consistent points.
 #[derive(Debug, StructOpt)]
@@ -18,6 +23,10 @@ pub struct Listcmd {
   /// Print in json.
   #[structopt(long)]
   json: bool,
+
+  /// Show how each version was produced, including the output of the scripts.
+  #[structopt(short, long)]
+  verbose: bool,
 }
 
 #[derive(Serialize)]
@@ -25,33 +34,196 @@ struct OutputEntry {
   lsn: u64,
   created_at: u64,
   size: u64,
+
+  /// Size of the previous version, if the image was resized since then.
+  resized_from: Option<u64>,
+
+  /// SHA-256 of the image, if the pull computed it.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  sha256: Option<String>,
+
+  /// Whether the image changed while this version was being pulled.
+  fuzzy: bool,
+
+  /// Why the guard flagged the pull of this version, if it did.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  suspect: Option<String>,
+
+  /// Geometry and identity of the remote image. Missing for versions pulled by older versions of
+  /// bsync.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  source: Option<SourceInfo>,
+
+  /// How this version was produced. Missing for versions pulled by older versions of bsync.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  meta: Option<PullMeta>,
+
+  /// Why this version can't be removed, if it can't.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  protected: Option<String>,
 }
 
 impl Listcmd {
   pub fn run(&self) -> Result<()> {
     let db = Database::open_file(&self.db, false)?;
     let cp_list = db.list_consistent_point();
+    let mut meta: HashMap<u64, PullMeta> = db
+      .list_pull_meta()
+      .into_iter()
+      .map(|x| (x.lsn, x))
+      .collect();
+    let mut protected = db.protected_versions(false);
+    let mut source: HashMap<u64, SourceInfo> = db.list_source_info().into_iter().collect();
 
-    if self.json {
+    let resized_from = |i: usize| {
+      let prev = cp_list[..i].last()?;
+      if prev.size != cp_list[i].size {
+        Some(prev.size)
+      } else {
+        None
+      }
+    };
+
+    if self.json || output::is_json() {
       let out: Vec<OutputEntry> = cp_list
         .iter()
-        .map(|x| OutputEntry {
+        .enumerate()
+        .map(|(i, x)| OutputEntry {
           lsn: x.lsn,
           created_at: x.created_at,
           size: x.size,
+          resized_from: resized_from(i),
+          sha256: x.sha256.map(hex::encode),
+          fuzzy: x.fuzzy,
+          suspect: x.suspect.clone(),
+          source: source.remove(&x.lsn),
+          meta: meta.remove(&x.lsn),
+          protected: protected.remove(&x.lsn),
         })
         .collect();
       println!("{}", serde_json::to_string_pretty(&out)?);
+    } else if self.verbose {
+      for (i, cp) in cp_list.iter().enumerate() {
+        println!("LSN {}", cp.lsn);
+        println!(
+          "  Created:        {}",
+          NaiveDateTime::from_timestamp(cp.created_at as i64, 0)
+        );
+        println!("  Size:           {}B", SizeFormatterBinary::new(cp.size));
+        if let Some(x) = resized_from(i) {
+          println!("  Resized from:   {}B", SizeFormatterBinary::new(x));
+        }
+        if cp.fuzzy {
+          println!("  Fuzzy:          the image changed during the pull");
+        }
+        if let Some(x) = &cp.suspect {
+          println!("  Suspect:        {}", x);
+        }
+        if let Some(x) = protected.get(&cp.lsn) {
+          println!("  Protected:      {}", x);
+        }
+        if let Some(x) = source.get(&cp.lsn) {
+          print_source(x);
+        }
+        if let Some(m) = meta.get(&cp.lsn) {
+          print_meta(m);
+        }
+        println!();
+      }
     } else {
       let mut table = Table::new();
       table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
-      table.set_titles(row!["LSN", "CREATED"]);
-      for cp in &cp_list {
+      table.set_titles(row![
+        "LSN",
+        "CREATED",
+        "SIZE",
+        "RESIZED FROM",
+        "FUZZY",
+        "SUSPECT",
+        "PROTECTED"
+      ]);
+      for (i, cp) in cp_list.iter().enumerate() {
         let created_at = NaiveDateTime::from_timestamp(cp.created_at as i64, 0);
-        table.add_row(row![cp.lsn, created_at]);
+        let resized_from = resized_from(i)
+          .map(|x| format!("{}B", SizeFormatterBinary::new(x)))
+          .unwrap_or_default();
+        table.add_row(row![
+          cp.lsn,
+          created_at,
+          format!("{}B", SizeFormatterBinary::new(cp.size)),
+          resized_from,
+          if cp.fuzzy { "yes" } else { "" },
+          if cp.suspect.is_some() { "yes" } else { "" },
+          protected
+            .get(&cp.lsn)
+            .map(|x| x.as_str())
+            .unwrap_or_default()
+        ]);
       }
-      table.print_tty(false);
+      table.print_tty(false)?;
     }
     Ok(())
   }
 }
+
+fn print_source(x: &SourceInfo) {
+  if let (Some(logical), Some(physical)) = (x.logical_sector_size, x.physical_sector_size) {
+    println!(
+      "  Sector size:    {} logical, {} physical",
+      logical, physical
+    );
+  }
+  let fields = [
+    ("WWID:          ", &x.wwid),
+    ("Serial:        ", &x.serial),
+    ("UUID:          ", &x.uuid),
+    ("DM name:       ", &x.dm_name),
+  ];
+  for (name, value) in fields.iter() {
+    if let Some(value) = value {
+      println!("  {} {}", name, value);
+    }
+  }
+  if let Some(x) = x.partition {
+    println!("  Partition:      {}", x);
+  }
+}
+
+fn print_meta(m: &PullMeta) {
+  let ts =
+    |ms: u64| NaiveDateTime::from_timestamp((ms / 1000) as i64, ((ms % 1000) * 1_000_000) as u32);
+  println!("  Pull started:   {}", ts(m.started_at));
+  println!("  Pull finished:  {}", ts(m.finished_at));
+  println!(
+    "  Duration:       {:?}",
+    Duration::from_millis(m.finished_at.saturating_sub(m.started_at))
+  );
+  println!("  Remote host:    {}", m.remote_host);
+  println!("  Image:          {}", m.image);
+  println!(
+    "  Downloaded:     {}B",
+    SizeFormatterBinary::new(m.downloaded_bytes)
+  );
+  println!(
+    "  Reused:         {}B",
+    SizeFormatterBinary::new(m.reused_bytes)
+  );
+  println!(
+    "  Changed blocks: {} of {}",
+    m.changed_blocks, m.total_blocks
+  );
+  println!("  bsync version:  {}", m.bsync_version);
+  for (name, output) in [
+    ("local pre_pull", &m.local_pre_pull_output),
+    ("pre_pull", &m.pre_pull_output),
+    ("post_pull", &m.post_pull_output),
+    ("local post_pull", &m.local_post_pull_output),
+  ] {
+    if let Some(output) = output.as_deref().filter(|x| !x.is_empty()) {
+      println!("  {} output:", name);
+      for line in output.lines() {
+        println!("    | {}", line);
+      }
+    }
+  }
+}
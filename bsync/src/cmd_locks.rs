@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use prettytable::{row, Table};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::{
+  db::{Database, LeaseInfo},
+  output,
+};
+
+/// Show the leases held on the database: running pulls, and versions being replayed or served.
+#[derive(Debug, StructOpt)]
+pub struct Lockscmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+}
+
+#[derive(Serialize)]
+struct LocksSummary {
+  leases: Vec<LeaseInfo>,
+}
+
+impl Lockscmd {
+  pub fn run(&self) -> Result<()> {
+    let db = Database::open_file(&self.db, false)?;
+    let summary = LocksSummary {
+      leases: db.list_leases(),
+    };
+
+    if !output::is_json() {
+      let time = |x: u64| NaiveDateTime::from_timestamp(x as i64, 0);
+      let mut table = Table::new();
+      table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+      table.set_titles(row!["ID", "KIND", "LSN", "HOLDER", "ACQUIRED", "EXPIRES"]);
+      for x in &summary.leases {
+        table.add_row(row![
+          x.id,
+          x.kind,
+          x.lsn.map(|x| x.to_string()).unwrap_or_default(),
+          format!("{} (pid {} on {})", x.holder, x.pid, x.hostname),
+          time(x.acquired_at),
+          time(x.expires_at),
+        ]);
+      }
+      table.print_tty(false)?;
+    }
+    output::summary(&summary);
+    Ok(())
+  }
+}
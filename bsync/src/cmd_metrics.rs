@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crate::{
+  db::Database,
+  metrics::{render, serve, write_textfile},
+  output::say,
+};
+
+/// Print repository metrics in the Prometheus text format, or serve them over HTTP.
+#[derive(Debug, StructOpt)]
+pub struct Metricscmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// Serve metrics at `http://<listen>/metrics` instead of printing them. Example: `127.0.0.1:9732`
+  #[structopt(short, long)]
+  listen: Option<String>,
+
+  /// Write metrics to this file instead of printing them, for the node_exporter textfile
+  /// collector.
+  #[structopt(long)]
+  textfile: Option<PathBuf>,
+}
+
+impl Metricscmd {
+  pub fn run(&self) -> Result<()> {
+    let db = Database::open_file(&self.db, false)?;
+    if let Some(listen) = &self.listen {
+      let db_path = self.db.clone();
+      say!("Serving metrics on http://{}/metrics.", listen);
+      serve(listen, move || render(&db, &db_path, None))
+    } else if let Some(path) = &self.textfile {
+      write_textfile(path, &render(&db, &self.db, None)?)
+    } else {
+      print!("{}", render(&db, &self.db, None)?);
+      Ok(())
+    }
+  }
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  db::Database,
+  output::{self, say},
+};
+
+/// Manage Reed-Solomon parity over the blobs in the database. With parity, `verify`, `replay`,
+/// `serve` and `repair` rebuild damaged blobs without the remote. Without options, show how much
+/// of the database is covered.
+#[derive(Debug, StructOpt)]
+pub struct Paritycmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// Enable parity, and compute it for all blobs that don't have any yet. `pull` and `gc` keep it
+  /// up to date afterwards.
+  #[structopt(long)]
+  enable: bool,
+
+  /// Disable parity and delete it.
+  #[structopt(long)]
+  disable: bool,
+
+  /// Number of blobs in a parity group.
+  #[structopt(long, default_value = "16")]
+  data_shards: u32,
+
+  /// Number of parity shards per group - the number of damaged blobs each group can rebuild. The
+  /// space overhead is `parity-shards / data-shards`.
+  #[structopt(long, default_value = "2")]
+  parity_shards: u32,
+}
+
+impl Paritycmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    enum E {
+      #[error("`--enable` and `--disable` can't be used together")]
+      Conflict,
+
+      #[error("need at least one data and one parity shard, and at most 256 shards in total")]
+      BadShards,
+    }
+
+    let db = Database::open_file(&self.db, false)?;
+
+    if self.enable && self.disable {
+      return Err(E::Conflict.into());
+    }
+    if self.enable {
+      if self.data_shards == 0
+        || self.parity_shards == 0
+        || self.data_shards + self.parity_shards > 256
+      {
+        return Err(E::BadShards.into());
+      }
+      db.set_parity_settings(Some((self.data_shards, self.parity_shards)));
+      say!(
+        "Enabled parity with {} data and {} parity shards.",
+        self.data_shards,
+        self.parity_shards
+      );
+      let grouped = db.update_parity()?;
+      say!("Computed parity for {} blobs.", grouped);
+    }
+    if self.disable {
+      db.set_parity_settings(None);
+      say!("Disabled parity.");
+    }
+
+    let stats = db.parity_stats();
+    if stats.data_shards != 0 {
+      say!(
+        "Parity: {} data and {} parity shards per group.",
+        stats.data_shards,
+        stats.parity_shards
+      );
+    } else {
+      say!("Parity is disabled.");
+    }
+    say!(
+      "{} groups cover {} blobs, {} blobs are not covered. Parity uses {}B.",
+      stats.groups,
+      stats.protected_blobs,
+      stats.unprotected_blobs,
+      SizeFormatterBinary::new(stats.parity_bytes)
+    );
+    output::summary(&stats);
+    Ok(())
+  }
+}
@@ -0,0 +1,142 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  db::{Database, Protection},
+  output::{self, say},
+};
+
+/// Protect versions from `squash`, `prune` and `delete`. Without options, show the protected
+/// versions.
+#[derive(Debug, StructOpt)]
+pub struct Protectcmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// LSN of the version to pin, unpin or lock.
+  #[structopt(long)]
+  lsn: Option<u64>,
+
+  /// Pin the version. Requires `--reason`.
+  #[structopt(long)]
+  pin: bool,
+
+  /// Remove the pin of the version.
+  #[structopt(long)]
+  unpin: bool,
+
+  /// Lock the version until this time: `YYYY-MM-DD` (UTC) or RFC 3339. Until then, the version
+  /// can't be removed, and the lock can be extended but not shortened or removed.
+  #[structopt(long)]
+  lock_until: Option<String>,
+
+  /// Why the version is pinned or locked.
+  #[structopt(long)]
+  reason: Option<String>,
+
+  /// Refuse to remove versions younger than this many days, unless `--override-min-age` is passed
+  /// to the removing command. 0 disables the check.
+  #[structopt(long)]
+  min_age_days: Option<u64>,
+
+  /// Allow lowering `--min-age-days`.
+  #[structopt(long)]
+  override_min_age: bool,
+}
+
+#[derive(Serialize)]
+struct ProtectSummary {
+  min_version_age_days: u64,
+  protection: Vec<Protection>,
+}
+
+impl Protectcmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    enum E {
+      #[error("`--pin`, `--unpin` and `--lock-until` need `--lsn`")]
+      MissingLsn,
+
+      #[error("a pin needs a `--reason`")]
+      MissingReason,
+
+      #[error("cannot parse `{0}` as `YYYY-MM-DD` or RFC 3339")]
+      BadTime(String),
+
+      #[error("lowering the minimum age from {0} to {1} days needs `--override-min-age`")]
+      MinAgeLowered(u64, u64),
+    }
+
+    let db = Database::open_file(&self.db, false)?;
+
+    if self.pin || self.unpin || self.lock_until.is_some() {
+      let lsn = self.lsn.ok_or(E::MissingLsn)?;
+      if self.pin {
+        let reason = self.reason.as_deref().ok_or(E::MissingReason)?;
+        db.pin(lsn, reason)?;
+        say!("Pinned version {}.", lsn);
+      }
+      if self.unpin {
+        db.unpin(lsn)?;
+        say!("Unpinned version {}.", lsn);
+      }
+      if let Some(x) = &self.lock_until {
+        let until = parse_time(x).ok_or_else(|| E::BadTime(x.clone()))?;
+        db.lock_version(
+          lsn,
+          until.timestamp() as u64,
+          self.reason.as_deref().unwrap_or(""),
+        )?;
+        say!("Locked version {} until {}.", lsn, until);
+      }
+    }
+
+    if let Some(days) = self.min_age_days {
+      let current = db.min_version_age_days();
+      if days < current && !self.override_min_age {
+        return Err(E::MinAgeLowered(current, days).into());
+      }
+      db.set_min_version_age_days(days);
+      say!(
+        "Set the minimum age of removable versions to {} days.",
+        days
+      );
+    }
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    let summary = ProtectSummary {
+      min_version_age_days: db.min_version_age_days(),
+      protection: db
+        .list_protection()
+        .into_iter()
+        .filter(|x| x.is_active(now))
+        .collect(),
+    };
+    if summary.min_version_age_days != 0 {
+      say!(
+        "Versions younger than {} days are protected.",
+        summary.min_version_age_days
+      );
+    }
+    for p in &summary.protection {
+      say!("{}: {}", p.lsn, p.describe(now).unwrap_or_default());
+    }
+    output::summary(&summary);
+    Ok(())
+  }
+}
+
+fn parse_time(x: &str) -> Option<NaiveDateTime> {
+  if let Ok(x) = DateTime::parse_from_rfc3339(x) {
+    return Some(x.naive_utc());
+  }
+  NaiveDate::parse_from_str(x, "%Y-%m-%d")
+    .ok()
+    .map(|x| x.and_hms(0, 0, 0))
+}
@@ -0,0 +1,179 @@
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  config::BackupConfig,
+  db::Database,
+  output::{self, say},
+  retention,
+};
+
+/// Remove versions according to the retention policy in the config.
+#[derive(Debug, StructOpt)]
+pub struct Prunecmd {
+  /// Path to config.
+  #[structopt(short, long)]
+  config: PathBuf,
+
+  /// Print what would be removed, without changing anything.
+  #[structopt(long)]
+  dry_run: bool,
+
+  /// Vacuum the database after pruning.
+  #[structopt(long)]
+  vacuum: bool,
+
+  /// Allow removing versions younger than the repository's `min_version_age_days`.
+  #[structopt(long)]
+  override_min_age: bool,
+}
+
+#[derive(Serialize, Default)]
+struct PruneSummary {
+  dry_run: bool,
+  kept_versions: Vec<u64>,
+  removed_versions: Vec<u64>,
+  /// Versions kept by the periods but removed to fit `max_db_size_mib`.
+  evicted_versions: Vec<u64>,
+  squash_ranges: Vec<(u64, u64)>,
+  cas_bytes_before: u64,
+  /// Estimated with `--dry-run`.
+  cas_bytes_after: u64,
+  duration_ms: u64,
+}
+
+impl Prunecmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    enum E {
+      #[error("the config has no `retention` policy")]
+      NoRetention,
+
+      #[error("the retention policy keeps only the latest version - set at least one `keep_*`")]
+      EmptyPolicy,
+    }
+
+    let start = Instant::now();
+    let config = BackupConfig::must_load_from_file(&self.config);
+    let policy = config.retention.as_ref().ok_or(E::NoRetention)?;
+    if policy.is_empty() {
+      return Err(E::EmptyPolicy.into());
+    }
+    let db = Database::open_file(&PathBuf::from(&config.local.db), false)?;
+    let cp_list = db.list_consistent_point();
+    let mut reasons = retention::keep_reasons(policy, &cp_list);
+    let protected = db.protected_versions(self.override_min_age);
+    for (cp, reasons) in cp_list.iter().zip(&mut reasons) {
+      if protected.contains_key(&cp.lsn) {
+        reasons.push("protected");
+      }
+    }
+    let (_, cas_bytes_before) = db.cas_stats();
+    let mut summary = PruneSummary {
+      dry_run: self.dry_run,
+      cas_bytes_before,
+      ..Default::default()
+    };
+
+    // Versions kept by the policy, oldest first. The latest version and protected versions are
+    // never evicted.
+    let kept: Vec<usize> = (0..cp_list.len().saturating_sub(1))
+      .filter(|&i| !reasons[i].is_empty() && !protected.contains_key(&cp_list[i].lsn))
+      .collect();
+    let cas_bytes_after = |evicted: usize| {
+      let mut keep: Vec<bool> = reasons.iter().map(|x| !x.is_empty()).collect();
+      for &i in &kept[..evicted] {
+        keep[i] = false;
+      }
+      let ranges = retention::squash_ranges(&cp_list, &keep);
+      cas_bytes_before - db.squash_preview(&ranges).cas_bytes
+    };
+    let mut evicted = 0;
+    if let Some(max_bytes) = policy.max_db_size_mib.map(|x| x * 1024 * 1024) {
+      if cas_bytes_after(0) > max_bytes {
+        // Removing more versions never grows the CAS, so search for the fewest to evict.
+        let fits = |n: usize| cas_bytes_after(n) <= max_bytes;
+        let (mut lo, mut hi) = (1, kept.len());
+        if !fits(hi) {
+          log::warn!(
+            "The latest and protected versions alone exceed `max_db_size_mib` ({} MiB).",
+            max_bytes / 1024 / 1024
+          );
+          lo = hi;
+        }
+        while lo < hi {
+          let mid = (lo + hi) / 2;
+          if fits(mid) {
+            hi = mid;
+          } else {
+            lo = mid + 1;
+          }
+        }
+        evicted = lo;
+      }
+    }
+    let cas_bytes_after = cas_bytes_after(evicted);
+    for &i in &kept[..evicted] {
+      reasons[i].clear();
+      summary.evicted_versions.push(cp_list[i].lsn);
+    }
+
+    for (cp, reasons) in cp_list.iter().zip(&reasons) {
+      let created_at = Local
+        .timestamp(cp.created_at as i64, 0)
+        .format("%Y-%m-%d %H:%M:%S");
+      if !reasons.is_empty() {
+        say!("keep   {} ({}): {}", cp.lsn, created_at, reasons.join(", "));
+        summary.kept_versions.push(cp.lsn);
+      } else {
+        let why = if summary.evicted_versions.contains(&cp.lsn) {
+          " (size limit)"
+        } else if cp.suspect.is_some() {
+          " (suspect)"
+        } else {
+          ""
+        };
+        say!("remove {} ({}){}", cp.lsn, created_at, why);
+        summary.removed_versions.push(cp.lsn);
+      }
+    }
+    let keep: Vec<bool> = reasons.iter().map(|x| !x.is_empty()).collect();
+    summary.squash_ranges = retention::squash_ranges(&cp_list, &keep);
+
+    if self.dry_run {
+      summary.cas_bytes_after = cas_bytes_after;
+      say!(
+        "Dry run: would remove {} versions in {} squashes, shrinking the CAS from {}B to {}B.",
+        summary.removed_versions.len(),
+        summary.squash_ranges.len(),
+        SizeFormatterBinary::new(cas_bytes_before),
+        SizeFormatterBinary::new(cas_bytes_after),
+      );
+    } else {
+      for &(start_lsn, end_lsn) in &summary.squash_ranges {
+        log::info!("Squashing ({}, {}).", start_lsn, end_lsn);
+        db.squash(start_lsn, end_lsn, self.override_min_age)?;
+      }
+      db.cas_gc();
+      if self.vacuum {
+        db.vacuum();
+      }
+      summary.cas_bytes_after = db.cas_stats().1;
+      say!(
+        "Removed {} versions. The CAS shrank from {}B to {}B.",
+        summary.removed_versions.len(),
+        SizeFormatterBinary::new(cas_bytes_before),
+        SizeFormatterBinary::new(summary.cas_bytes_after),
+      );
+    }
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    output::summary(&summary);
+    Ok(())
+  }
+}
@@ -1,33 +1,61 @@
 use std::{
   borrow::Cow,
-  collections::HashSet,
+  collections::{HashMap, HashSet},
   convert::TryFrom,
   fs::OpenOptions,
-  io::{BufRead, BufReader, Read, Write},
-  net::{IpAddr, SocketAddr, TcpStream},
+  io::Write,
   path::{Path, PathBuf},
-  str::FromStr,
+  time::Duration,
 };
 
 use anyhow::Result;
+use ed25519_dalek::SigningKey;
 use fs2::FileExt;
-use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
+use serde::Serialize;
 use shell_escape::unix::escape;
 use size_format::SizeFormatterBinary;
-use ssh2::{Channel, CheckResult, KnownHostFileKind, Session};
 use structopt::StructOpt;
 use thiserror::Error;
 
 use crate::{
-  blob::{ARCH_BLKXMIT, ZERO_BLOCK_HASH},
-  config::{BackupConfig, HostVerification, LOG_BLOCK_SIZE},
-  db::{Database, RedoContentOrHash},
-  util::sha256hash,
+  attachment,
+  blob::{ARCH_BLKXMIT, ZERO_BLOCK, ZERO_BLOCK_HASH},
+  config::{BackupConfig, SourceChangeAction, LOG_BLOCK_SIZE},
+  db::{block_count, Database, PullMeta, RedoContentOrHash, LEASE_PULL},
+  guard::{Guard, GuardTriggered},
+  hooks::{self, HookEnv, HookStatus},
+  interrupt,
+  lease::Lease,
+  merkle, metrics,
+  notify::{self, Notification},
+  output::{self, say, Progress},
+  remote::Remote,
+  source::{SourceIdentityChanged, SourceInfo},
+  util::{sha256hash, unix_millis},
 };
 
-const DIFF_BATCH_SIZE: usize = 16384;
-const DATA_FETCH_BATCH_SIZE: usize = 256; // 16MiB batches
+pub(crate) const DIFF_BATCH_SIZE: usize = 16384;
+pub(crate) const DATA_FETCH_BATCH_SIZE: usize = 256; // 64MiB batches
+
+/// Default memory ceiling of a pull, in MiB.
+const DEFAULT_MAX_MEMORY_MIB: u64 = 1024;
+
+/// Estimated in-memory cost of tracking one block in a diff window: the fetch list entry and the
+/// dedup set entry.
+const DIFF_STATE_BYTES_PER_BLOCK: u64 = 128;
+
+#[derive(Error, Debug)]
+#[error("expecting {0} bytes from remote, got {1}")]
+struct ByteCountMismatch(usize, usize);
+
+#[derive(Error, Debug)]
+#[error("cannot acquire pull lock on {0}: {1}")]
+pub struct LockAcquire(String, std::io::Error);
+
+#[derive(Error, Debug)]
+#[error("block at offset {0} changed during the pull")]
+pub struct SourceChanged(usize);
 
 /// Incrementally pull updates from a remote image.
 #[derive(Debug, StructOpt)]
@@ -35,59 +63,58 @@ pub struct Pullcmd {
   /// Path to the config.
   #[structopt(short, long)]
   config: PathBuf,
+
+  /// Pull even if the remote image doesn't look like the device of the previous version, e.g.
+  /// after replacing a disk.
+  #[structopt(long)]
+  allow_identity_change: bool,
 }
 
+/// A changed block, with its hash seen while diffing.
 enum FetchOrAssumeExist {
-  Fetch(usize),
+  Fetch(usize, [u8; 32]),
   AssumeExistWithHash(usize, [u8; 32]),
 }
 
+/// Window and batch sizes (in blocks) derived from the memory ceiling of a pull.
+struct PullBudget {
+  diff_window: usize,
+  fetch_batch: usize,
+}
+
+impl PullBudget {
+  fn new(max_memory_mib: Option<u64>) -> Self {
+    let limit = max_memory_mib.unwrap_or(DEFAULT_MAX_MEMORY_MIB) * 1048576;
+
+    // A fetch batch is held twice: once as the decoded transfer and once while being compressed.
+    // Give it at most a quarter of the budget.
+    let fetch_batch_cost = 2 * LOG_BLOCK_SIZE as u64;
+    let fetch_batch = (limit / 4 / fetch_batch_cost).clamp(1, DATA_FETCH_BATCH_SIZE as u64);
+    let remaining = limit.saturating_sub(fetch_batch * fetch_batch_cost);
+    let diff_window = (remaining / DIFF_STATE_BYTES_PER_BLOCK).max(DIFF_BATCH_SIZE as u64);
+    Self {
+      diff_window: diff_window as usize,
+      fetch_batch: fetch_batch as usize,
+    }
+  }
+}
+
 impl Pullcmd {
   pub fn run(&self) -> Result<()> {
-    #[derive(Error, Debug)]
-    #[error("received invalid hash from remote: {0}")]
-    struct InvalidRemoteHash(String);
-    #[derive(Error, Debug)]
-    #[error("expecting {0} bytes from remote, got {1}")]
-    struct ByteCountMismatch(usize, usize);
-    #[derive(Error, Debug)]
-    #[error("total size mismatch - expecting {0}, got {1}")]
-    struct TotalSizeMismatch(u64, u64);
-    #[derive(Error, Debug)]
-    #[error("remote architecture not supported: {0}")]
-    struct ArchNotSupported(String);
-    #[derive(Error, Debug)]
-    #[error("remote os not supported: {0}")]
-    struct OsNotSupported(String);
-
-    #[derive(Error, Debug)]
-    #[error("`remote.scripts` requested but `local.pull_lock` is not set. If this is really the intended config, set `remote.scripts.no_pull_lock` to `true`.")]
-    struct PullLockRequired;
-
-    #[derive(Error, Debug)]
-    #[error("cannot acquire pull lock on {0}: {1}")]
-    struct LockAcquire(String, std::io::Error);
-
-    #[derive(Error, Debug)]
-    #[error("no host key")]
-    struct NoHostKey;
-
-    #[derive(Error, Debug)]
-    #[error("host key verification error: {0}")]
-    struct HostKeyVerifyError(&'static str);
-
     let config = BackupConfig::must_load_from_file(&self.config);
-    let remote = &config.remote;
+    let signing_key = config
+      .local
+      .signing_key
+      .as_deref()
+      .map(merkle::load_signing_key)
+      .transpose()?;
 
-    // Unique access.
-    if let Some(scripts) = &config.remote.scripts {
-      if !scripts.no_pull_lock.unwrap_or(false) && config.local.pull_lock.is_none() {
-        return Err(PullLockRequired.into());
-      }
-    }
+    // The database lease below gives unique access to the database. The lock file is only needed to
+    // serialize pulls of different databases, e.g. ones sharing a remote snapshot.
     let _pull_lock_file = if let Some(path) = &config.local.pull_lock {
       let f = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true)
         .write(true)
         .open(path)?;
@@ -99,72 +126,273 @@ impl Pullcmd {
       None
     };
 
-    // Establish SSH session.
-    let addr = SocketAddr::new(IpAddr::from_str(&remote.server)?, remote.port.unwrap_or(22));
-    let tcp = TcpStream::connect(addr).unwrap();
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-
-    let (host_key, _host_key_type) = sess.host_key().ok_or(NoHostKey)?;
-    match config.remote.verify {
-      HostVerification::Insecure => {
-        log::warn!("`remote.verify` is set to `insecure`, skipping host key verification");
-      }
-      HostVerification::Known => {
-        let mut known_hosts = sess.known_hosts()?;
-        if let Some(home) = dirs::home_dir() {
-          let _ = known_hosts.read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH);
-        }
-        match known_hosts.check(&remote.server, host_key) {
-          CheckResult::Match => {}
-          CheckResult::NotFound => {
-            return Err(
-              HostKeyVerifyError("not found - please connect to the remote host once").into(),
-            );
-          }
-          CheckResult::Mismatch => {
-            return Err(HostKeyVerifyError("mismatch - possible mitm").into());
-          }
-          CheckResult::Failure => {
-            return Err(HostKeyVerifyError("unknown").into());
-          }
-        }
+    // Give the scripts a chance to clean up on Ctrl-C.
+    interrupt::install()?;
+
+    let db = Database::open_file(Path::new(&config.local.db), true)?;
+    let _lease = Lease::acquire(&db, LEASE_PULL, None, "pull")?;
+    let mut stats = PullStats::new(&config.remote.image);
+    let scripts = config.local.scripts.as_ref();
+    let timeout = scripts.and_then(|x| x.timeout).map(Duration::from_secs);
+    let res = (|| {
+      if let Some(script) = scripts.and_then(|x| x.pre_pull.as_ref()) {
+        let out = hooks::run_local(
+          "pre_pull",
+          script,
+          &stats.hook_env(HookStatus::Running, None),
+          timeout,
+        )?;
+        stats.apply_image_override(&out);
+        stats.local_pre_pull_output = Some(out);
       }
-      HostVerification::Dnssec => {
-        return Err(HostKeyVerifyError("dnssec not yet implemented").into());
+      with_remote(
+        &config,
+        &db,
+        &mut stats,
+        |sess, transmit_filename, stats| {
+          transfer(
+            sess,
+            &db,
+            transmit_filename,
+            &config,
+            self.allow_identity_change,
+            signing_key.as_ref(),
+            stats,
+          )
+        },
+      )
+    })();
+    let res = run_cleanup_hooks(
+      res,
+      &stats,
+      scripts.and_then(|x| x.on_failure.as_deref()),
+      scripts.and_then(|x| x.post_pull.as_deref()),
+      |name, script, env| hooks::run_local(name, script, env, timeout),
+    );
+
+    // The consistent point exists even if a `post_pull` script failed.
+    if let Some(lsn) = stats.lsn {
+      let local_post_pull_output = res.as_ref().ok().cloned().flatten();
+      db.add_pull_meta(&stats.pull_meta(lsn, local_post_pull_output));
+    }
+    if stats.retries != 0 {
+      stats.warnings.push(format!("{} retries", stats.retries));
+    }
+    let summary = PullSummary {
+      lsn: stats.lsn,
+      image: &stats.image,
+      remote_host: &stats.remote_host,
+      downloaded_bytes: stats.downloaded_bytes,
+      reused_bytes: stats.reused_bytes,
+      changed_blocks: stats.changed_blocks,
+      total_blocks: stats.total_blocks,
+      retries: stats.retries,
+      source_changed_blocks: stats.source_changed_blocks,
+      fuzzy: stats.fuzzy,
+      suspect: stats.suspect.as_deref(),
+      started_at: stats.started_at,
+      duration_ms: unix_millis().saturating_sub(stats.started_at),
+      warnings: &stats.warnings,
+    };
+    output::summary(&summary);
+    notify::send_all(
+      &config.notify,
+      &Notification::new(&summary, res.as_ref().err(), &stats.warnings),
+      timeout,
+    );
+
+    if let Some(path) = &config.local.metrics_textfile {
+      let res = metrics::render(&db, Path::new(&config.local.db), Some(res.is_ok()))
+        .and_then(|x| metrics::write_textfile(Path::new(path), &x));
+      if let Err(e) = res {
+        log::error!("cannot write metrics to {}: {:?}", path, e);
       }
     }
+    res.map(|_| ())
+  }
+}
 
-    if let Some(x) = &remote.key {
-      sess.userauth_pubkey_file(&remote.user, None, Path::new(x), None)?;
-    } else {
-      sess.userauth_agent(&remote.user)?;
+#[derive(Serialize)]
+struct PullSummary<'a> {
+  /// LSN of the new version. Set if a consistent point was written, even if a script failed
+  /// afterwards.
+  lsn: Option<u64>,
+  image: &'a str,
+  remote_host: &'a str,
+  downloaded_bytes: u64,
+  reused_bytes: u64,
+  changed_blocks: u64,
+  total_blocks: u64,
+  retries: u64,
+  /// Fetched blocks that didn't match the hash seen while diffing, and blocks that changed by the
+  /// time the pull was verified.
+  source_changed_blocks: u64,
+  /// Whether the new version was marked fuzzy because the image changed during the pull.
+  fuzzy: bool,
+  /// Why the guard flagged the new version, if it did.
+  suspect: Option<&'a str>,
+  started_at: u64,
+  duration_ms: u64,
+  warnings: &'a [String],
+}
+
+/// Progress of a pull, reported to the scripts and recorded as the metadata of the new version.
+pub(crate) struct PullStats {
+  pub(crate) image: String,
+  lsn: Option<u64>,
+  pub(crate) downloaded_bytes: u64,
+  reused_bytes: u64,
+  changed_blocks: u64,
+  total_blocks: u64,
+  pub(crate) retries: u64,
+  source_changed_blocks: u64,
+  fuzzy: bool,
+  suspect: Option<String>,
+  started_at: u64,
+  finished_at: u64,
+  remote_host: String,
+  pre_pull_output: Option<String>,
+  post_pull_output: Option<String>,
+  local_pre_pull_output: Option<String>,
+  warnings: Vec<String>,
+}
+
+impl PullStats {
+  pub(crate) fn new(image: &str) -> Self {
+    Self {
+      image: image.to_string(),
+      lsn: None,
+      downloaded_bytes: 0,
+      reused_bytes: 0,
+      changed_blocks: 0,
+      total_blocks: 0,
+      retries: 0,
+      source_changed_blocks: 0,
+      fuzzy: false,
+      suspect: None,
+      started_at: unix_millis(),
+      finished_at: 0,
+      remote_host: String::new(),
+      pre_pull_output: None,
+      post_pull_output: None,
+      local_pre_pull_output: None,
+      warnings: vec![],
     }
+  }
 
-    let db = Database::open_file(Path::new(&config.local.db), true)?;
+  fn hook_env(&self, status: HookStatus, error: Option<&anyhow::Error>) -> HookEnv<'_> {
+    HookEnv {
+      status,
+      image: &self.image,
+      lsn: self.lsn,
+      bytes: self.downloaded_bytes,
+      error: error.map(|x| format!("{:#}", x)),
+    }
+  }
 
-    let remote_uname = exec_oneshot(&mut sess, "uname -m; uname -s")?;
-    let mut remote_uname_segs = remote_uname.split("\n");
-    let remote_arch = remote_uname_segs.next().unwrap_or("");
-    let remote_os = remote_uname_segs.next().unwrap_or("");
+  fn pull_meta(&self, lsn: u64, local_post_pull_output: Option<String>) -> PullMeta {
+    PullMeta {
+      lsn,
+      started_at: self.started_at,
+      finished_at: self.finished_at,
+      remote_host: self.remote_host.clone(),
+      image: self.image.clone(),
+      downloaded_bytes: self.downloaded_bytes,
+      reused_bytes: self.reused_bytes,
+      changed_blocks: self.changed_blocks,
+      total_blocks: self.total_blocks,
+      bsync_version: env!("CARGO_PKG_VERSION").to_string(),
+      pre_pull_output: self.pre_pull_output.clone(),
+      post_pull_output: self.post_pull_output.clone(),
+      local_pre_pull_output: self.local_pre_pull_output.clone(),
+      local_post_pull_output,
+    }
+  }
+
+  fn apply_image_override(&mut self, output: &str) {
+    if let Some(image) = hooks::image_override(output) {
+      log::info!("Using image path {} from pre_pull.", image);
+      self.image = image.to_string();
+    }
+  }
+}
 
-    if remote_os != "Linux" && remote_os != "FreeBSD" {
-      return Err(OsNotSupported(remote_os.to_string()).into());
+/// Runs `on_failure` if `res` is an error, and then `post_pull`. Returns the output of
+/// `post_pull` on success.
+///
+/// On failure, errors from the scripts are logged and the original error is returned.
+fn run_cleanup_hooks(
+  res: Result<()>,
+  stats: &PullStats,
+  on_failure: Option<&str>,
+  post_pull: Option<&str>,
+  mut run: impl FnMut(&str, &str, &HookEnv) -> Result<String>,
+) -> Result<Option<String>> {
+  match res {
+    Ok(()) => post_pull
+      .map(|script| {
+        run(
+          "post_pull",
+          script,
+          &stats.hook_env(HookStatus::Success, None),
+        )
+      })
+      .transpose(),
+    Err(e) => {
+      let env = stats.hook_env(HookStatus::Failure, Some(&e));
+      if let Some(script) = on_failure {
+        if let Err(e) = run("on_failure", script, &env) {
+          log::error!("on_failure script failed: {:?}", e);
+        }
+      }
+      if let Some(script) = post_pull {
+        if let Err(e) = run("post_pull", script, &env) {
+          log::error!("post_pull script failed: {:?}", e);
+        }
+      }
+      Err(e)
     }
+  }
+}
+
+/// Connects to the remote, installs transmit and runs `f` between the remote `pre_pull` and
+/// `post_pull` scripts. `f` gets the session and the file name of transmit in `~/.bsync`.
+pub(crate) fn with_remote(
+  config: &BackupConfig,
+  db: &Database,
+  stats: &mut PullStats,
+  f: impl FnOnce(&mut Remote, &str, &mut PullStats) -> Result<()>,
+) -> Result<()> {
+  #[derive(Error, Debug)]
+  #[error("remote architecture not supported: {0}")]
+  struct ArchNotSupported(String);
+  #[derive(Error, Debug)]
+  #[error("remote os not supported: {0}")]
+  struct OsNotSupported(String);
 
-    log::info!("Remote platform: {}/{}", remote_arch, remote_os);
+  // Establish SSH session.
+  let mut sess = Remote::connect(&config.remote)?;
 
-    let transmit_image = *ARCH_BLKXMIT
-      .get(&remote_arch)
-      .ok_or_else(|| ArchNotSupported(remote_arch.to_string()))?;
-    let transmit_sha256 = hex::encode(sha256hash(transmit_image));
-    let transmit_filename = format!("transmit.{}.{}", db.instance_id(), transmit_sha256);
+  let remote_uname = sess.exec("uname -m; uname -s; uname -n")?;
+  let mut remote_uname_segs = remote_uname.split('\n');
+  let remote_arch = remote_uname_segs.next().unwrap_or("");
+  let remote_os = remote_uname_segs.next().unwrap_or("");
+  stats.remote_host = remote_uname_segs.next().unwrap_or("").to_string();
+
+  if remote_os != "Linux" && remote_os != "FreeBSD" {
+    return Err(OsNotSupported(remote_os.to_string()).into());
+  }
 
-    let maybe_upload_path: String = exec_oneshot(
-      &mut sess,
-      &format!(
-        r#"
+  log::info!("Remote platform: {}/{}", remote_arch, remote_os);
+
+  let transmit_image = *ARCH_BLKXMIT
+    .get(remote_arch)
+    .ok_or_else(|| ArchNotSupported(remote_arch.to_string()))?;
+  let transmit_sha256 = hex::encode(sha256hash(transmit_image));
+  let transmit_filename = format!("transmit.{}.{}", db.instance_id(), transmit_sha256);
+
+  let maybe_upload_path: String = sess.exec(&format!(
+    r#"
 if [ -f ~/.bsync/{filename} ]; then
   echo {hash} ~/.bsync/{filename} | sha256sum -c - > /dev/null
   if [ $? -eq 0 ]; then
@@ -174,101 +402,185 @@ fi
 mkdir -p ~/.bsync
 echo -n "$HOME/.bsync"
 "#,
-        filename = escape(Cow::Borrowed(transmit_filename.as_str())),
-        hash = escape(Cow::Borrowed(transmit_sha256.as_str()))
-      ),
+    filename = escape(Cow::Borrowed(transmit_filename.as_str())),
+    hash = escape(Cow::Borrowed(transmit_sha256.as_str()))
+  ))?;
+
+  if !maybe_upload_path.is_empty() {
+    let upload_path = format!("{}/{}", maybe_upload_path, transmit_filename);
+    let mut remote_file = sess.session().scp_send(
+      Path::new(&upload_path),
+      0o755,
+      transmit_image.len() as u64,
+      None,
     )?;
+    remote_file.write_all(transmit_image)?;
+    remote_file.send_eof()?;
+    remote_file.wait_eof()?;
+    remote_file.close()?;
+    remote_file.wait_close()?;
+    say!("Installed transmit on remote host at {}.", upload_path);
+  }
 
-    if !maybe_upload_path.is_empty() {
-      let upload_path = format!("{}/{}", maybe_upload_path, transmit_filename);
-      let mut remote_file = sess.scp_send(
-        Path::new(&upload_path),
-        0o755,
-        transmit_image.len() as u64,
-        None,
+  let scripts = config.remote.scripts.as_ref();
+  let timeout = scripts.and_then(|x| x.timeout).map(Duration::from_secs);
+  let res = (|| {
+    if let Some(script) = scripts.and_then(|x| x.pre_pull.as_ref()) {
+      let out = hooks::run_remote(
+        &mut sess,
+        "pre_pull",
+        script,
+        &stats.hook_env(HookStatus::Running, None),
+        timeout,
       )?;
-      remote_file.write_all(transmit_image)?;
-      remote_file.send_eof()?;
-      remote_file.wait_eof()?;
-      remote_file.close()?;
-      remote_file.wait_close()?;
-      println!("Installed transmit on remote host at {}.", upload_path);
+      stats.apply_image_override(&out);
+      stats.pre_pull_output = Some(out);
     }
+    f(&mut sess, &transmit_filename, stats)
+  })();
 
-    if let Some(script) = config
+  // The session may be broken after a failure - reconnect before running the cleanup scripts.
+  let mut needs_reconnect = res.is_err();
+  stats.post_pull_output = run_cleanup_hooks(
+    res,
+    stats,
+    scripts.and_then(|x| x.on_failure.as_deref()),
+    scripts.and_then(|x| x.post_pull.as_deref()),
+    |name, script, env| {
+      if needs_reconnect {
+        sess.reconnect()?;
+        needs_reconnect = false;
+      }
+      let ret = hooks::run_remote(&mut sess, name, script, env, timeout);
+      needs_reconnect = ret.is_err();
+      ret
+    },
+  )?;
+  Ok(())
+}
+
+/// Diffs the remote image against the latest local version and fetches the changes.
+fn transfer(
+  sess: &mut Remote,
+  db: &Database,
+  transmit_filename: &str,
+  config: &BackupConfig,
+  allow_identity_change: bool,
+  signing_key: Option<&SigningKey>,
+  stats: &mut PullStats,
+) -> Result<()> {
+  let checksum = config.remote.checksum;
+  let source_change = &config.remote.source_change;
+
+  // Get the size and identity of the remote image.
+  //
+  // The image might be created by `pre_pull`.
+  let source = SourceInfo::fetch(sess, transmit_filename, &stats.image)?;
+  let remote_image_size = source.size;
+  if let Some((prev_lsn, prev)) = db.list_source_info().pop() {
+    let changes = source.identity_changes(&prev, &config.remote.ignore_identity);
+    if !changes.is_empty() {
+      let msg = changes.join(", ");
+      if !allow_identity_change {
+        return Err(SourceIdentityChanged(prev_lsn, msg).into());
+      }
+      let msg = format!("remote image identity changed: {}", msg);
+      log::warn!("{}", msg);
+      stats.warnings.push(msg);
+    }
+  }
+
+  let prev_size = db.list_consistent_point().pop().map(|x| x.size);
+  if let Some(prev_size) = prev_size {
+    if prev_size != remote_image_size {
+      let msg = format!(
+        "remote image resized from {} to {} bytes",
+        prev_size, remote_image_size
+      );
+      log::warn!("{}", msg);
+      stats.warnings.push(msg);
+    }
+  }
+
+  let attachments = attachment::capture(
+    sess,
+    &config.remote.attachments,
+    &stats.image,
+    config
       .remote
       .scripts
       .as_ref()
-      .and_then(|x| x.pre_pull.as_ref())
-    {
-      log::info!("Running pre_pull script.");
-      let out = exec_oneshot(&mut sess, script)?;
-      log::info!("pre_pull output: {}", out);
-      println!("Finished running pre_pull script.");
-    }
+      .and_then(|x| x.timeout)
+      .map(Duration::from_secs),
+    &mut stats.warnings,
+  )?;
 
-    // Get the size of the remote image.
-    //
-    // The image might be created by `pre_pull`.
-    let remote_image_size: u64 = exec_oneshot(
-      &mut sess,
-      &format!(
-        "blockdev --getsize64 {} || stat -c \"%s\" {}",
-        escape(Cow::Borrowed(remote.image.as_str())),
-        escape(Cow::Borrowed(remote.image.as_str())),
-      ),
-    )?
-    .trim()
-    .parse()?;
-    log::info!("Remote image size is {} bytes.", remote_image_size);
-
-    let mut lsn = db.max_lsn();
-    let snapshot = db.snapshot(lsn)?;
-    log::info!("Starting from LSN {}.", lsn);
-
-    let mut fetch_list: Vec<FetchOrAssumeExist> = vec![];
-
-    let gen_pb_style = |name: &str| {
-      ProgressStyle::default_bar().template(
-        &format!("{{spinner:.green}} {} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}}", name),
-      )
-      .progress_chars("#>-")
-    };
+  // A failed pull leaves the blocks it fetched after the latest version. Starting from them would
+  // hide its changes from this pull, and so from the guard.
+  let discarded = db.discard_incomplete_pull();
+  if discarded != 0 {
+    log::info!(
+      "Discarded {} redo log entries left by an unfinished pull.",
+      discarded
+    );
+  }
+  let base_lsn = db.max_lsn();
+  let mut lsn = base_lsn;
+
+  // Not bounded by size: blocks past the end of the image are needed to tombstone them.
+  let snapshot = db.snapshot(lsn, None)?;
+  log::info!("Starting from LSN {}.", lsn);
+
+  let budget = PullBudget::new(config.local.max_memory_mib);
+  log::info!(
+    "Using diff windows of {} blocks and fetch batches of {} blocks.",
+    budget.diff_window,
+    budget.fetch_batch
+  );
 
-    let bar = ProgressBar::new(remote_image_size);
-    bar.set_style(gen_pb_style("Diff"));
+  let mut guard = config
+    .guard
+    .as_ref()
+    .map(|x| Guard::new(x, db, block_count(remote_image_size)));
 
-    // XXX: This may become large if we are synchronizing a big block device -
-    // should we store this in SQLite instead?
-    let mut seen_hashes: HashSet<[u8; 32]> = HashSet::new();
+  let bar = Progress::new(
+    "sync",
+    remote_image_size,
+    "{spinner:.green} Sync [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {msg}",
+  );
 
-    for chunk in &(0usize..remote_image_size as usize)
-      .step_by(LOG_BLOCK_SIZE)
-      .chunks(DIFF_BATCH_SIZE)
-    {
+  // The image is diffed and fetched one window at a time so that memory usage doesn't grow with
+  // the size of the image. Blocks fetched in previous windows are already in the CAS, so only the
+  // hashes seen in the current window need to be tracked here.
+  let mut fetch_list: Vec<FetchOrAssumeExist> = vec![];
+  let mut seen_hashes: HashSet<[u8; 32]> = HashSet::new();
+  // Hashes of blocks that changed before they were fetched. They never made it into the CAS, so
+  // blocks deduplicated against them must be fetched too.
+  let mut lost_hashes: HashSet<[u8; 32]> = HashSet::new();
+  // The hash batches cover the image in order, so transmit can carry a SHA-256 across them.
+  let mut sha256_state: Option<[u8; 32]> = None;
+
+  for window in &(0usize..remote_image_size as usize)
+    .step_by(LOG_BLOCK_SIZE)
+    .chunks(budget.diff_window)
+  {
+    fetch_list.clear();
+    seen_hashes.clear();
+
+    for chunk in &window.chunks(DIFF_BATCH_SIZE) {
+      interrupt::check()?;
       let chunk = chunk.collect_vec();
-      let mut microprogress: usize = 0;
       bar.set_position(chunk[0] as u64);
-      let script = format!(
-        "~/.bsync/{} {} {} hash {} {}",
-        escape(Cow::Borrowed(transmit_filename.as_str())),
-        escape(Cow::Borrowed(remote.image.as_str())),
-        LOG_BLOCK_SIZE,
+      let output = hash_blocks(
+        sess,
+        transmit_filename,
+        &stats.image,
         chunk[0],
         chunk.len(),
-      );
-      let output = exec_oneshot_bin(
-        &mut sess,
-        &script,
-        |inc| {
-          microprogress += inc;
-          bar.set_position(chunk[0] as u64 + (microprogress as u64 / 32) * LOG_BLOCK_SIZE as u64);
-        },
-        |x| Box::new(x),
+        checksum.then_some(&mut sha256_state),
+        &mut stats.retries,
+        |n| bar.set_position(chunk[0] as u64 + (n * LOG_BLOCK_SIZE) as u64),
       )?;
-      if output.len() != chunk.len() * 32 {
-        return Err(ByteCountMismatch(chunk.len() * 32, output.len()).into());
-      }
       let remote_hashes = output.chunks(32);
       let local_hashes = chunk.iter().map(|x| {
         snapshot
@@ -282,72 +594,91 @@ echo -n "$HOME/.bsync"
           if seen_hashes.contains(&rh) || db.exists_in_cas(&rh) {
             fetch_list.push(FetchOrAssumeExist::AssumeExistWithHash(offset, rh));
           } else {
-            fetch_list.push(FetchOrAssumeExist::Fetch(offset));
+            fetch_list.push(FetchOrAssumeExist::Fetch(offset, rh));
           }
           seen_hashes.insert(rh);
         }
       }
     }
-    bar.finish();
-    drop(bar);
 
-    log::info!("{} blocks changed. Fetching changes.", fetch_list.len());
-    let bar = ProgressBar::new(
-      fetch_list
-        .iter()
-        .filter(|x| matches!(x, FetchOrAssumeExist::Fetch(_)))
-        .count() as u64
-        * LOG_BLOCK_SIZE as u64,
+    log::info!(
+      "{} blocks changed in window. Fetching changes.",
+      fetch_list.len()
     );
-    bar.set_style(gen_pb_style("Fetch"));
-    let mut total_download_bytes: usize = 0;
-    let mut total_reuse_bytes: usize = 0;
-    for chunk in &fetch_list.iter().chunks(DATA_FETCH_BATCH_SIZE) {
+    stats.changed_blocks += fetch_list.len() as u64;
+    // Stop before fetching anything more if the pull is going to be aborted anyway.
+    if let Some(guard) = guard.as_ref().filter(|x| x.aborts()) {
+      if let Some(reason) = guard.check_changes(stats.changed_blocks) {
+        db.discard_incomplete_pull();
+        return Err(GuardTriggered(reason).into());
+      }
+    }
+
+    for chunk in &fetch_list.iter().chunks(budget.fetch_batch) {
+      interrupt::check()?;
       let chunk = chunk.collect_vec();
-      let fetch_chunk = chunk
+      let mut fetch_chunk = chunk
         .iter()
         .filter_map(|x| {
-          if let FetchOrAssumeExist::Fetch(x) = x {
-            Some(*x)
+          if let FetchOrAssumeExist::Fetch(x, h) = x {
+            Some((*x, *h))
           } else {
             None
           }
         })
         .collect_vec();
 
-      // Don't pass empty string to remote.
-      let output: Vec<u8> = if fetch_chunk.len() == 0 {
-        vec![]
-      } else {
-        let script = format!(
-          "~/.bsync/{} {} {} dump {}",
-          escape(Cow::Borrowed(transmit_filename.as_str())),
-          escape(Cow::Borrowed(remote.image.as_str())),
-          LOG_BLOCK_SIZE,
-          fetch_chunk.iter().map(|x| format!("{}", x)).join(","),
-        );
-        exec_oneshot_bin(
-          &mut sess,
-          &script,
-          |inc| bar.inc(inc as u64),
-          |x| Box::new(snap::read::FrameDecoder::new(x)),
-        )?
-      };
-      if output.len() != fetch_chunk.len() * LOG_BLOCK_SIZE {
-        return Err(ByteCountMismatch(fetch_chunk.len() * LOG_BLOCK_SIZE, output.len()).into());
+      let downloaded_bytes = stats.downloaded_bytes;
+      let mut output = fetch_checked(
+        sess,
+        transmit_filename,
+        &fetch_chunk,
+        source_change.on_mismatch,
+        &mut lost_hashes,
+        stats,
+        |n| bar.set_transferred(downloaded_bytes + n as u64),
+      )?;
+      let orphans = chunk
+        .iter()
+        .filter_map(|x| match x {
+          FetchOrAssumeExist::AssumeExistWithHash(x, h) if lost_hashes.contains(h) => {
+            Some((*x, *h))
+          }
+          _ => None,
+        })
+        .collect_vec();
+      if !orphans.is_empty() {
+        let downloaded_bytes = stats.downloaded_bytes;
+        output.extend(fetch_checked(
+          sess,
+          transmit_filename,
+          &orphans,
+          source_change.on_mismatch,
+          &mut lost_hashes,
+          stats,
+          |n| bar.set_transferred(downloaded_bytes + n as u64),
+        )?);
+        fetch_chunk.extend(orphans);
       }
-      let mut output_chunks = output.chunks(LOG_BLOCK_SIZE);
+
+      let mut fetched: HashMap<usize, &[u8]> = fetch_chunk
+        .iter()
+        .map(|x| x.0)
+        .zip(output.chunks(LOG_BLOCK_SIZE))
+        .collect();
       lsn = db.write_redo(
         lsn,
         chunk
           .iter()
           .copied()
           .map(|x| match x {
-            FetchOrAssumeExist::Fetch(x) => (
-              *x,
-              RedoContentOrHash::Content(output_chunks.next().unwrap()),
-            ),
-            FetchOrAssumeExist::AssumeExistWithHash(x, h) => (*x, RedoContentOrHash::Hash(*h)),
+            FetchOrAssumeExist::Fetch(x, _) => {
+              (*x, RedoContentOrHash::Content(fetched.remove(x).unwrap()))
+            }
+            FetchOrAssumeExist::AssumeExistWithHash(x, h) => match fetched.remove(x) {
+              Some(content) => (*x, RedoContentOrHash::Content(content)),
+              None => (*x, RedoContentOrHash::Hash(*h)),
+            },
           })
           .map(|(offset, data)| ((offset / LOG_BLOCK_SIZE) as u64, data)),
       )?;
@@ -358,98 +689,351 @@ echo -n "$HOME/.bsync"
         output.len(),
         lsn,
       );
-      total_download_bytes += output.len();
-      total_reuse_bytes += (chunk.len() - fetch_chunk.len()) * LOG_BLOCK_SIZE;
+      stats.reused_bytes += ((chunk.len() - fetch_chunk.len()) * LOG_BLOCK_SIZE) as u64;
+      if let Some(guard) = &mut guard {
+        guard.record_fetched(db, &snapshot, &fetch_chunk);
+      }
     }
-    bar.finish();
-    drop(bar);
-
-    db.add_consistent_point(lsn, remote_image_size);
-    println!(
-      "Downloaded {}B and reused {}B.",
-      SizeFormatterBinary::new(total_download_bytes as u64),
-      SizeFormatterBinary::new(total_reuse_bytes as u64),
-    );
+  }
+  bar.finish();
+  drop(bar);
+  log::info!("{} blocks changed in total.", stats.changed_blocks);
 
-    if let Some(script) = config
-      .remote
-      .scripts
-      .as_ref()
-      .and_then(|x| x.post_pull.as_ref())
-    {
-      log::info!("Running post_pull script.");
-      let out = exec_oneshot(&mut sess, script)?;
-      log::info!("post_pull output: {}", out);
-      println!("Finished running post_pull script.");
+  // Tombstone blocks past the end of a shrunk image, so that stale data never reappears if the
+  // image grows again.
+  let new_block_count = block_count(remote_image_size);
+  stats.total_blocks = new_block_count;
+  let mut tombstone_start = new_block_count;
+  loop {
+    let stale = snapshot.list_nonzero_blocks(tombstone_start, budget.fetch_batch);
+    if stale.is_empty() {
+      break;
     }
-    Ok(())
+    tombstone_start = stale.last().unwrap().0 + 1;
+    lsn = db.write_redo(
+      lsn,
+      stale
+        .iter()
+        .map(|&(block_id, _)| (block_id, RedoContentOrHash::Content(&ZERO_BLOCK[..]))),
+    )?;
+    log::info!(
+      "Tombstoned {} blocks past the end of the image. Last LSN is {}.",
+      stale.len(),
+      lsn
+    );
   }
-}
-
-fn exec_oneshot(sess: &mut Session, cmd: &str) -> Result<String> {
-  let mut channel = sess.channel_session()?;
-  exec_oneshot_in(&mut channel, cmd)
-}
 
-fn exec_oneshot_bin<D: for<'a> FnMut(&'a mut dyn Read) -> Box<dyn Read + 'a>>(
-  sess: &mut Session,
-  cmd: &str,
-  progress: impl FnMut(usize),
-  decoder_gen: D,
-) -> Result<Vec<u8>> {
-  let mut channel = sess.channel_session()?;
-  exec_oneshot_bin_in(&mut channel, cmd, progress, decoder_gen)
-}
+  // Consistent points are identified by their LSN - make sure a resized image gets a new one even
+  // if no block changed.
+  if prev_size != Some(remote_image_size) && lsn == base_lsn {
+    let last_block = new_block_count.saturating_sub(1);
+    let hash = snapshot
+      .read_block_hash(last_block)
+      .unwrap_or(*ZERO_BLOCK_HASH);
+    let body = if hash == *ZERO_BLOCK_HASH {
+      RedoContentOrHash::Content(&ZERO_BLOCK[..])
+    } else {
+      RedoContentOrHash::Hash(hash)
+    };
+    lsn = db.write_redo(lsn, std::iter::once((last_block, body)))?;
+  }
 
-fn exec_oneshot_in(channel: &mut Channel, cmd: &str) -> Result<String> {
-  exec_oneshot_bin_in(channel, cmd, |_| (), |x| Box::new(x))
-    .and_then(|x| String::from_utf8(x).map_err(anyhow::Error::from))
+  // After the last batch, transmit returned the digest instead of a state. It describes the image
+  // as it was hashed, so it's useless if blocks changed before they were fetched.
+  let sha256 = match sha256_state {
+    _ if !checksum || stats.source_changed_blocks != 0 => None,
+    Some(x) => Some(x),
+    None => Some(sha256hash(&[])),
+  };
+  let mut suspect = None;
+  if let Some(guard) = &guard {
+    if let Some(reason) = guard.verdict(stats.changed_blocks) {
+      if guard.aborts() {
+        db.discard_incomplete_pull();
+        return Err(GuardTriggered(reason).into());
+      }
+      suspect = Some(reason);
+    }
+  }
+  let tree = db
+    .snapshot(lsn, Some(remote_image_size))?
+    .merkle_tree(remote_image_size);
+  let root = tree.root();
+  // Blobs assumed to exist may have been deleted if the lease was lost.
+  interrupt::check()?;
+  db.add_consistent_point(
+    lsn,
+    remote_image_size,
+    sha256,
+    &tree,
+    signing_key.map(|x| merkle::sign(x, lsn, &root)),
+  )?;
+  log::info!("Merkle root of version {}: {}", lsn, hex::encode(root));
+  if let Some(reason) = suspect {
+    let msg = format!("version {} is suspect: {}", lsn, reason);
+    log::warn!("{}", msg);
+    stats.warnings.push(msg);
+    db.mark_suspect(lsn, &reason);
+    stats.suspect = Some(reason);
+  }
+  db.add_source_info(lsn, &source);
+  db.add_attachments(lsn, &attachments)?;
+  if stats.source_changed_blocks != 0 {
+    let msg = format!(
+      "{} blocks changed while they were being pulled - version {} is fuzzy",
+      stats.source_changed_blocks, lsn
+    );
+    log::warn!("{}", msg);
+    stats.warnings.push(msg);
+    db.mark_fuzzy(lsn);
+    stats.fuzzy = true;
+  }
+  if source_change.verify {
+    let drifted = count_drifted_blocks(sess, transmit_filename, db, lsn, remote_image_size, stats)?;
+    if drifted != 0 {
+      stats.source_changed_blocks += drifted;
+      // A pull that didn't change anything ends at an existing version, which may be exact.
+      if lsn != base_lsn {
+        db.mark_fuzzy(lsn);
+        stats.fuzzy = true;
+      }
+      let msg = format!(
+        "{} blocks of the remote image no longer match version {}",
+        drifted, lsn
+      );
+      log::warn!("{}", msg);
+      stats.warnings.push(msg);
+    } else {
+      say!("The remote image still matches version {}.", lsn);
+    }
+  }
+  if let Err(e) = db.update_parity() {
+    log::error!("Cannot update parity: {:?}", e);
+  }
+  stats.lsn = Some(lsn);
+  stats.finished_at = unix_millis();
+  say!(
+    "Downloaded {}B and reused {}B with {} retries.",
+    SizeFormatterBinary::new(stats.downloaded_bytes),
+    SizeFormatterBinary::new(stats.reused_bytes),
+    stats.retries,
+  );
+  Ok(())
 }
 
-fn exec_oneshot_bin_in<D: for<'a> FnMut(&'a mut dyn Read) -> Box<dyn Read + 'a>>(
-  channel: &mut Channel,
-  cmd: &str,
-  mut progress: impl FnMut(usize),
-  mut decoder_gen: D,
+/// Fetches `blocks` and checks each one against its hash seen while diffing. Returns their
+/// content, and adds the fetched bytes to `stats`.
+///
+/// What happens to a block that doesn't match depends on `action`. If it's stored anyway, its
+/// expected hash is added to `lost_hashes`, and removed again once a block with that hash is
+/// fetched.
+fn fetch_checked(
+  sess: &mut Remote,
+  transmit_filename: &str,
+  blocks: &[(usize, [u8; 32])],
+  action: SourceChangeAction,
+  lost_hashes: &mut HashSet<[u8; 32]>,
+  stats: &mut PullStats,
+  progress: impl FnMut(usize),
 ) -> Result<Vec<u8>> {
-  #[derive(Debug, Error)]
-  #[error("remote returned error {0}")]
-  struct RemoteError(i32);
+  // Don't pass empty string to remote.
+  if blocks.is_empty() {
+    return Ok(vec![]);
+  }
+  let offsets = blocks.iter().map(|x| x.0).collect_vec();
+  let mut output = dump_blocks(
+    sess,
+    transmit_filename,
+    &stats.image,
+    &offsets,
+    &mut stats.retries,
+    progress,
+  )?;
+  stats.downloaded_bytes += output.len() as u64;
 
-  channel.exec(cmd)?;
-  let mut data = Vec::new();
-  {
-    let mut reader = decoder_gen(&mut *channel);
-    let mut reader = BufReader::new(&mut *reader);
-    loop {
-      let buf = reader.fill_buf()?;
-      if buf.len() == 0 {
-        break;
+  let matches = |i: usize, content: &[u8]| <[u8; 32]>::from(blake3::hash(content)) == blocks[i].1;
+  let mut changed = output
+    .chunks(LOG_BLOCK_SIZE)
+    .enumerate()
+    .filter(|&(i, x)| !matches(i, x))
+    .map(|(i, _)| i)
+    .collect_vec();
+  if !changed.is_empty() {
+    match action {
+      SourceChangeAction::Fail => return Err(SourceChanged(blocks[changed[0]].0).into()),
+      SourceChangeAction::Refetch => {
+        log::info!("Refetching {} blocks that changed.", changed.len());
+        let offsets = changed.iter().map(|&i| blocks[i].0).collect_vec();
+        let refetched = dump_blocks(
+          sess,
+          transmit_filename,
+          &stats.image,
+          &offsets,
+          &mut stats.retries,
+          |_| {},
+        )?;
+        stats.downloaded_bytes += refetched.len() as u64;
+        // Keep the latest content even if it still doesn't match.
+        for (&i, content) in changed.iter().zip(refetched.chunks(LOG_BLOCK_SIZE)) {
+          output[i * LOG_BLOCK_SIZE..(i + 1) * LOG_BLOCK_SIZE].copy_from_slice(content);
+        }
+        changed.retain(|&i| !matches(i, &output[i * LOG_BLOCK_SIZE..(i + 1) * LOG_BLOCK_SIZE]));
       }
-      data.extend_from_slice(buf);
-      let len = buf.len();
-      reader.consume(len);
-      progress(len);
+      SourceChangeAction::Warn => {}
     }
   }
-  channel.wait_close()?;
 
-  let sig = channel.exit_signal()?;
-  let status = channel.exit_status()?;
-  let mut msg = String::new();
-  channel.stderr().read_to_string(&mut msg)?;
+  for (i, (offset, hash)) in blocks.iter().enumerate() {
+    if changed.contains(&i) {
+      log::warn!("Block at offset {} changed during the pull.", offset);
+      lost_hashes.insert(*hash);
+    } else {
+      lost_hashes.remove(hash);
+    }
+  }
+  stats.source_changed_blocks += changed.len() as u64;
+  Ok(output)
+}
 
-  // We get `status == 0` if the program is killed by a signal - so do another check here.
-  if let Some(sig) = sig.exit_signal {
-    log::error!("remote signal: {}, stderr: {}", sig, msg);
-    return Err(RemoteError(1).into());
+/// Hashes the remote image again and returns the number of blocks that differ from version `lsn`.
+/// A resize counts as a change of every block past the shorter end.
+fn count_drifted_blocks(
+  sess: &mut Remote,
+  transmit_filename: &str,
+  db: &Database,
+  lsn: u64,
+  size: u64,
+  stats: &mut PullStats,
+) -> Result<u64> {
+  let remote_size = SourceInfo::fetch(sess, transmit_filename, &stats.image)?.size;
+  let snapshot = db.snapshot(lsn, Some(size))?;
+  let common_size = size.min(remote_size);
+  let mut drifted = block_count(size.max(remote_size)) - block_count(common_size);
+  let bar = Progress::new(
+    "verify",
+    common_size,
+    "{spinner:.green} Verify [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
+  );
+  for chunk in &(0usize..common_size as usize)
+    .step_by(LOG_BLOCK_SIZE)
+    .chunks(DIFF_BATCH_SIZE)
+  {
+    interrupt::check()?;
+    let chunk = chunk.collect_vec();
+    let output = hash_blocks(
+      sess,
+      transmit_filename,
+      &stats.image,
+      chunk[0],
+      chunk.len(),
+      None,
+      &mut stats.retries,
+      |n| bar.set_position((chunk[0] + n * LOG_BLOCK_SIZE) as u64),
+    )?;
+    for (&offset, rh) in chunk.iter().zip(output.chunks(32)) {
+      let lh = snapshot
+        .read_block_hash((offset / LOG_BLOCK_SIZE) as u64)
+        .unwrap_or(*ZERO_BLOCK_HASH);
+      if lh != rh {
+        log::debug!("block at offset {} changed after it was pulled", offset);
+        drifted += 1;
+      }
+    }
   }
+  bar.finish();
+  Ok(drifted)
+}
 
-  if status != 0 {
-    log::error!("remote returned error {}, stderr: {}", status, msg);
-    return Err(RemoteError(status).into());
+/// Returns the BLAKE3 hashes of `count` blocks of the remote image, starting at byte `offset`.
+/// `progress` is called with the number of blocks hashed so far.
+///
+/// With `sha256_state`, the bytes read are also added to a SHA-256 of the image, starting from
+/// the state of the previous batch, or from scratch if it's `None`. It's replaced with the new
+/// state, or with the digest if the batch reached the end of the image.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn hash_blocks(
+  sess: &mut Remote,
+  transmit_filename: &str,
+  image: &str,
+  offset: usize,
+  count: usize,
+  mut sha256_state: Option<&mut Option<[u8; 32]>>,
+  retries: &mut u64,
+  mut progress: impl FnMut(usize),
+) -> Result<Vec<u8>> {
+  let mut script = format!(
+    "~/.bsync/{} {} {} hash {} {}",
+    escape(Cow::Borrowed(transmit_filename)),
+    escape(Cow::Borrowed(image)),
+    LOG_BLOCK_SIZE,
+    offset,
+    count,
+  );
+  let expected_len = match &sha256_state {
+    Some(state) => {
+      script += &format!(
+        " sha256 {}",
+        state.map(hex::encode).unwrap_or_else(|| "-".into())
+      );
+      count * 32 + 32
+    }
+    None => count * 32,
+  };
+  let output = sess.with_retry("hash batch", retries, |sess| {
+    let mut microprogress: usize = 0;
+    let output = sess.exec_bin(
+      &script,
+      |inc| {
+        microprogress += inc;
+        progress(microprogress / 32);
+      },
+      |x| Box::new(x),
+    )?;
+    if output.len() != expected_len {
+      return Err(ByteCountMismatch(expected_len, output.len()).into());
+    }
+    sess.keepalive()?;
+    Ok(output)
+  })?;
+  let mut output = output;
+  if let Some(state) = &mut sha256_state {
+    **state = Some(<[u8; 32]>::try_from(output.split_off(count * 32)).unwrap());
   }
+  Ok(output)
+}
 
-  log::debug!("remote stderr: {}", msg);
-  Ok(data)
+/// Fetches the blocks of the remote image at the byte `offsets`. `progress` is called with the
+/// number of bytes received so far.
+pub(crate) fn dump_blocks(
+  sess: &mut Remote,
+  transmit_filename: &str,
+  image: &str,
+  offsets: &[usize],
+  retries: &mut u64,
+  mut progress: impl FnMut(usize),
+) -> Result<Vec<u8>> {
+  let script = format!(
+    "~/.bsync/{} {} {} dump {}",
+    escape(Cow::Borrowed(transmit_filename)),
+    escape(Cow::Borrowed(image)),
+    LOG_BLOCK_SIZE,
+    offsets.iter().map(|x| format!("{}", x)).join(","),
+  );
+  let output = sess.with_retry("dump batch", retries, |sess| {
+    let mut microprogress: usize = 0;
+    let output = sess.exec_bin(
+      &script,
+      |inc| {
+        microprogress += inc;
+        progress(microprogress);
+      },
+      |x| Box::new(snap::read::FrameDecoder::new(x)),
+    )?;
+    if output.len() != offsets.len() * LOG_BLOCK_SIZE {
+      return Err(ByteCountMismatch(offsets.len() * LOG_BLOCK_SIZE, output.len()).into());
+    }
+    sess.keepalive()?;
+    Ok(output)
+  })?;
+  Ok(output)
 }
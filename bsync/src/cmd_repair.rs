@@ -0,0 +1,171 @@
+use std::{
+  collections::HashSet,
+  convert::TryInto,
+  path::{Path, PathBuf},
+  time::Instant,
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+
+use crate::{
+  cmd_pull::{
+    dump_blocks, hash_blocks, with_remote, PullStats, DATA_FETCH_BATCH_SIZE, DIFF_BATCH_SIZE,
+  },
+  cmd_verify::{damaged_versions, DamagedVersion, RepositoryDamaged},
+  config::{BackupConfig, LOG_BLOCK_SIZE},
+  db::{DamagedBlobInfo, Database, LEASE_PULL},
+  interrupt,
+  lease::Lease,
+  output::{self, say, Progress},
+  source::SourceInfo,
+};
+
+/// Refetch the blobs that `bsync verify` found damaged or missing from the remote image, wherever
+/// a block of the image still has the same content.
+///
+/// The remote `pre_pull` and `post_pull` scripts run as for a pull.
+#[derive(Debug, StructOpt)]
+pub struct Repaircmd {
+  /// Path to config.
+  #[structopt(short, long)]
+  config: PathBuf,
+}
+
+#[derive(Serialize, Default)]
+struct RepairSummary {
+  repaired_blobs: u64,
+  downloaded_bytes: u64,
+  /// Blobs that are still damaged, because no block of the remote image has their content anymore.
+  remaining_blobs: Vec<DamagedBlobInfo>,
+  unrecoverable_versions: Vec<DamagedVersion>,
+  duration_ms: u64,
+}
+
+impl Repaircmd {
+  pub fn run(&self) -> Result<()> {
+    let start = Instant::now();
+    let config = BackupConfig::must_load_from_file(&self.config);
+    interrupt::install()?;
+    let db = Database::open_file(Path::new(&config.local.db), false)?;
+    // Repairs use the remote scripts like a pull, so they can't run at the same time.
+    let _lease = Lease::acquire(&db, LEASE_PULL, None, "repair")?;
+
+    // Blobs covered by parity don't need the remote.
+    let damaged: HashSet<[u8; 32]> = db
+      .list_damaged_blobs()
+      .iter()
+      .map(|x| hex::decode(&x.hash).unwrap().try_into().unwrap())
+      .filter(|x: &[u8; 32]| db.heal_blob(x).is_none())
+      .collect();
+    let mut summary = RepairSummary::default();
+    if damaged.is_empty() {
+      say!("No damaged blobs are known. Run `bsync verify` to look for them.");
+      output::summary(&summary);
+      return Ok(());
+    }
+    say!(
+      "Looking for {} damaged blobs in the remote image.",
+      damaged.len()
+    );
+
+    let mut stats = PullStats::new(&config.remote.image);
+    with_remote(
+      &config,
+      &db,
+      &mut stats,
+      |sess, transmit_filename, stats| {
+        let size = SourceInfo::fetch(sess, transmit_filename, &stats.image)?.size;
+        let bar = Progress::new(
+        "repair",
+        size,
+        "{spinner:.green} Repair [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
+      );
+
+        // Only the first block with each hash needs to be fetched.
+        let mut wanted = damaged.clone();
+        let mut found: Vec<(usize, [u8; 32])> = vec![];
+        for chunk in &(0usize..size as usize)
+          .step_by(LOG_BLOCK_SIZE)
+          .chunks(DIFF_BATCH_SIZE)
+        {
+          if wanted.is_empty() {
+            break;
+          }
+          interrupt::check()?;
+          let chunk = chunk.collect_vec();
+          let hashes = hash_blocks(
+            sess,
+            transmit_filename,
+            &stats.image,
+            chunk[0],
+            chunk.len(),
+            None,
+            &mut stats.retries,
+            |n| bar.set_position((chunk[0] + n * LOG_BLOCK_SIZE) as u64),
+          )?;
+          for (&offset, hash) in chunk.iter().zip(hashes.chunks(32)) {
+            let hash: [u8; 32] = hash.try_into()?;
+            if wanted.remove(&hash) {
+              found.push((offset, hash));
+            }
+          }
+        }
+        bar.finish();
+        log::info!("{} damaged blobs found in the remote image.", found.len());
+
+        for batch in found.chunks(DATA_FETCH_BATCH_SIZE) {
+          interrupt::check()?;
+          let offsets = batch.iter().map(|x| x.0).collect_vec();
+          let output = dump_blocks(
+            sess,
+            transmit_filename,
+            &stats.image,
+            &offsets,
+            &mut stats.retries,
+            |_| {},
+          )?;
+          stats.downloaded_bytes += output.len() as u64;
+          for ((offset, hash), content) in batch.iter().zip(output.chunks(LOG_BLOCK_SIZE)) {
+            if db.repair_blob(hash, content)? {
+              summary.repaired_blobs += 1;
+            } else {
+              log::warn!(
+                "Block at offset {} changed between hashing and fetching - not repaired.",
+                offset
+              );
+            }
+          }
+        }
+        Ok(())
+      },
+    )?;
+    summary.downloaded_bytes = stats.downloaded_bytes;
+    db.update_parity()?;
+
+    summary.remaining_blobs = db.list_damaged_blobs();
+    summary.unrecoverable_versions = damaged_versions(&db)?;
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    say!(
+      "Repaired {} of {} damaged blobs, downloading {}B.",
+      summary.repaired_blobs,
+      damaged.len(),
+      SizeFormatterBinary::new(summary.downloaded_bytes)
+    );
+    output::summary(&summary);
+    if !summary.remaining_blobs.is_empty() {
+      return Err(
+        RepositoryDamaged(format!(
+          "{} blobs can't be repaired from the remote image, {} versions are unrecoverable",
+          summary.remaining_blobs.len(),
+          summary.unrecoverable_versions.len()
+        ))
+        .into(),
+      );
+    }
+    Ok(())
+  }
+}
@@ -3,19 +3,30 @@ use std::{
   io::{Seek, SeekFrom, Write},
   os::unix::prelude::FileTypeExt,
   path::{Path, PathBuf},
+  time::Instant,
 };
 
 use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use structopt::StructOpt;
 use thiserror::Error;
 
 use crate::{
   blob::ZERO_BLOCK,
   config::LOG_BLOCK_SIZE,
-  db::{ConsistentPoint, Database},
+  db::{ConsistentPoint, DamagedBlob, Database, NotConsistentPoint, LEASE_READ},
+  lease::Lease,
+  output::{self, say, Progress},
+  util::{block_ranges, format_ranges},
 };
 
-/// Replay logs and build an image of the block device at a given point in time.
+#[derive(Error, Debug)]
+#[error("the SHA-256 of the image is {1}, but the pull recorded {0}")]
+pub struct ImageChecksumMismatch(pub String, pub String);
+
+/// Replay logs and build an image of the block device at a given point in time. If the pull
+/// recorded a checksum of the image, the output is checked against it.
 #[derive(Debug, StructOpt)]
 pub struct Replaycmd {
   /// Path to the output file.
@@ -29,29 +40,88 @@ pub struct Replaycmd {
   /// Path to the database.
   #[structopt(long)]
   db: PathBuf,
+
+  /// Write zeros for blocks whose blob is missing or damaged, and list them, instead of failing.
+  #[structopt(long)]
+  allow_missing: bool,
 }
 
 impl Replaycmd {
   pub fn run(&self) -> Result<()> {
-    #[derive(Error, Debug)]
-    enum E {
-      #[error("the provided LSN is not a consistent point")]
-      Inconsistent,
-    }
-
     let db = Database::open_file(&self.db, false)?;
     let cp_list = db.list_consistent_point();
     let cp = match cp_list.iter().find(|x| x.lsn == self.lsn) {
       Some(x) => x,
-      None => return Err(E::Inconsistent.into()),
+      None => return Err(NotConsistentPoint("lsn").into()),
     };
-    write_snapshot(&db, cp, &self.output)?;
+    let _lease = Lease::acquire(&db, LEASE_READ, Some(cp.lsn), "replay")?;
+    if let Some(reason) = &cp.suspect {
+      log::warn!("Version {} is suspect: {}", cp.lsn, reason);
+    }
+    if cp.fuzzy {
+      log::warn!(
+        "Version {} is fuzzy: the image changed while it was being pulled.",
+        cp.lsn
+      );
+    }
+    let start = Instant::now();
+    let (missing, sha256) = write_snapshot(&db, cp, &self.output, self.allow_missing)?;
+    let missing_blocks = block_ranges(&missing);
+    if !missing.is_empty() {
+      say!(
+        "{} blocks are missing and were written as zeros: {}",
+        missing.len(),
+        format_ranges(&missing_blocks)
+      );
+    }
+    // An image with zeros in place of missing blocks can't match.
+    let checksum_verified = cp.sha256.is_some() && missing.is_empty();
+    output::summary(&ReplaySummary {
+      lsn: cp.lsn,
+      size: cp.size,
+      output: &self.output,
+      duration_ms: start.elapsed().as_millis() as u64,
+      missing_blocks,
+      sha256: hex::encode(sha256),
+      checksum_verified,
+      fuzzy: cp.fuzzy,
+    });
+    match cp.sha256 {
+      Some(expected) if checksum_verified && expected != sha256 => {
+        return Err(ImageChecksumMismatch(hex::encode(expected), hex::encode(sha256)).into());
+      }
+      Some(_) if checksum_verified => say!("The image matches the checksum of the pull."),
+      _ => {}
+    }
     Ok(())
   }
 }
 
-fn write_snapshot(db: &Database, cp: &ConsistentPoint, path: &Path) -> Result<()> {
-  let snapshot = db.snapshot(cp.lsn)?;
+#[derive(Serialize)]
+struct ReplaySummary<'a> {
+  lsn: u64,
+  size: u64,
+  output: &'a Path,
+  duration_ms: u64,
+  /// Inclusive ranges of the blocks written as zeros because of `--allow-missing`.
+  missing_blocks: Vec<(u64, u64)>,
+  /// SHA-256 of the image written.
+  sha256: String,
+  /// Whether `sha256` was checked against the checksum recorded by the pull.
+  checksum_verified: bool,
+  /// Whether the image changed while this version was being pulled.
+  fuzzy: bool,
+}
+
+/// Writes the image of `cp` to `path`. Returns the blocks that were missing with `allow_missing`,
+/// and the SHA-256 of the image.
+fn write_snapshot(
+  db: &Database,
+  cp: &ConsistentPoint,
+  path: &Path,
+  allow_missing: bool,
+) -> Result<(Vec<u64>, [u8; 32])> {
+  let snapshot = db.snapshot(cp.lsn, Some(cp.size))?;
   let mut output = OpenOptions::new()
     .create(true)
     .write(true)
@@ -60,31 +130,54 @@ fn write_snapshot(db: &Database, cp: &ConsistentPoint, path: &Path) -> Result<()
   let output_md = output.metadata()?;
   let blkdev = output_md.file_type().is_block_device();
   let mut last_is_seek = false;
+  let mut missing = vec![];
+  let mut sha256 = Sha256::new();
+  let progress = Progress::new(
+    "replay",
+    cp.size,
+    "{spinner:.green} Replay [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
+  );
   for offset in (0usize..cp.size as usize).step_by(LOG_BLOCK_SIZE) {
+    progress.set_position(offset as u64);
     let write_len = (offset + LOG_BLOCK_SIZE)
       .min(cp.size as usize)
       .checked_sub(offset)
       .unwrap();
     assert!(write_len > 0);
-    if let Some(block) = snapshot.read_block(offset as u64 / LOG_BLOCK_SIZE as u64) {
+    let block_id = offset as u64 / LOG_BLOCK_SIZE as u64;
+    let block = match snapshot.read_block(block_id) {
+      Err(e) if allow_missing && e.is::<DamagedBlob>() => {
+        log::warn!("Block {}: {}", block_id, e);
+        missing.push(block_id);
+        None
+      }
+      x => x?,
+    };
+    if let Some(block) = block {
       assert_eq!(block.len(), LOG_BLOCK_SIZE);
       output.write_all(&block[..write_len])?;
+      sha256.update(&block[..write_len]);
       last_is_seek = false;
     } else if blkdev {
-      output.write_all(&ZERO_BLOCK)?;
+      output.write_all(&ZERO_BLOCK[..write_len])?;
+      sha256.update(&ZERO_BLOCK[..write_len]);
       last_is_seek = false;
     } else {
       output.seek(SeekFrom::Current(write_len as i64)).unwrap();
+      sha256.update(&ZERO_BLOCK[..write_len]);
       last_is_seek = true;
     }
   }
 
+  progress.set_position(cp.size);
+  progress.finish();
+
   // It seems that seeking without writing doesn't enlarge the file
   if last_is_seek {
     output.seek(SeekFrom::Current(-1)).unwrap();
     output.write_all(&[0])?;
   }
   drop(output);
-  println!("Image written to {}.", path.to_string_lossy());
-  Ok(())
+  say!("Image written to {}.", path.to_string_lossy());
+  Ok((missing, sha256.finalize().into()))
 }
@@ -0,0 +1,302 @@
+use std::{
+  collections::{HashMap, HashSet},
+  path::PathBuf,
+  time::Instant,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  db::{Database, MerkleRoot, Protection, SalvageSource, SalvagedAttachment, SalvagedBlob},
+  output::{self, say},
+  util::{block_ranges, format_ranges},
+};
+
+/// Copy what can still be read from a damaged database into a new one.
+///
+/// Blobs are copied if they match their hash. A version is kept if all of its redo entries could
+/// be read, even if some of its blocks are missing: `bsync replay --allow-missing` restores it
+/// with zeros in their place, and `bsync repair` may refetch them from the source. The metadata
+/// of the kept versions, including their pins and locks, is copied as far as it can be read.
+#[derive(Debug, StructOpt)]
+pub struct Salvagecmd {
+  /// Path to the damaged database.
+  #[structopt(long)]
+  from: PathBuf,
+
+  /// Path to the new database. Must not exist.
+  #[structopt(long)]
+  to: PathBuf,
+}
+
+#[derive(Serialize, Default)]
+struct SalvageSummary {
+  copied_blobs: u64,
+  /// Blobs that could be read but don't match their hash.
+  damaged_blobs: u64,
+  copied_redo_entries: u64,
+  /// Inclusive LSN ranges of the redo entries that couldn't be read.
+  unreadable_redo_lsns: Vec<(i64, i64)>,
+  /// Inclusive LSN ranges of the consistent points that couldn't be read. Versions there are lost.
+  unreadable_consistent_point_lsns: Vec<(i64, i64)>,
+  complete_versions: Vec<u64>,
+  partial_versions: Vec<PartialVersion>,
+  /// Versions that weren't copied, because some of their redo entries couldn't be read and the
+  /// blocks they changed are unknown.
+  lost_versions: Vec<u64>,
+  /// Inclusive LSN ranges of the Merkle roots that couldn't be read. The trees of copied versions
+  /// there are rebuilt, but their signatures, if any, are lost.
+  unreadable_merkle_root_lsns: Vec<(i64, i64)>,
+  /// Copied versions whose signatures were dropped, because their rebuilt tree doesn't match the
+  /// signed root, or the root couldn't be read.
+  unsigned_versions: Vec<u64>,
+  /// Inclusive LSN ranges of the remote image info that couldn't be read. The next pull can't
+  /// check that it pulls the same device as versions there.
+  unreadable_source_info_lsns: Vec<(i64, i64)>,
+  /// Inclusive LSN ranges of the pull metadata that couldn't be read. The guard has less history
+  /// to compare new pulls with.
+  unreadable_pull_meta_lsns: Vec<(i64, i64)>,
+  /// Pins and locks of the copied versions.
+  copied_protection: u64,
+  /// Inclusive LSN ranges of the pins and locks that couldn't be read. Versions there may have
+  /// been protected, and are no longer.
+  unreadable_protection_lsns: Vec<(i64, i64)>,
+  /// The copied `min_version_age_days`, if it was set.
+  min_version_age_days: Option<u64>,
+  /// Whether `min_version_age_days` couldn't be read. It's unset in the new database.
+  min_version_age_unreadable: bool,
+  copied_attachments: u64,
+  /// Attachments that could be read but don't decompress to their size.
+  damaged_attachments: u64,
+  /// Inclusive rowid ranges of the attachments that couldn't be read.
+  unreadable_attachment_rowids: Vec<(i64, i64)>,
+  duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct PartialVersion {
+  lsn: u64,
+  /// Inclusive block ranges.
+  missing_blocks: Vec<(u64, u64)>,
+}
+
+impl Salvagecmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    #[error("{0} already exists")]
+    struct TargetExists(String);
+
+    let start = Instant::now();
+    if self.to.exists() {
+      return Err(TargetExists(self.to.to_string_lossy().into_owned()).into());
+    }
+    let src = SalvageSource::open(&self.from)?;
+    let dst = Database::open_file(&self.to, true)?;
+    let mut summary = SalvageSummary::default();
+
+    let unreadable_blobs = src.blobs(|batch| {
+      let (good, bad): (Vec<SalvagedBlob>, Vec<SalvagedBlob>) =
+        batch.into_iter().partition(|x| match x.verify() {
+          Ok(()) => true,
+          Err(e) => {
+            log::warn!("Blob {} is damaged: {}.", hex::encode(&x.hash), e);
+            false
+          }
+        });
+      summary.copied_blobs += good.len() as u64;
+      summary.damaged_blobs += bad.len() as u64;
+      dst.import_blobs(&good);
+    });
+    if !unreadable_blobs.is_empty() {
+      log::warn!("Some rows of cas_v1 can't be read: {:?}", unreadable_blobs);
+    }
+    say!(
+      "Copied {} blobs. {} blobs were damaged.",
+      summary.copied_blobs,
+      summary.damaged_blobs
+    );
+
+    summary.unreadable_redo_lsns = src.redo(|batch| {
+      summary.copied_redo_entries += batch.len() as u64;
+      dst.import_redo(&batch);
+    });
+    say!("Copied {} redo log entries.", summary.copied_redo_entries);
+
+    let (cp_list, unreadable_cps) = src.consistent_points();
+    summary.unreadable_consistent_point_lsns = unreadable_cps;
+    let (kept, lost): (Vec<_>, Vec<_>) = cp_list.into_iter().partition(|cp| {
+      !summary
+        .unreadable_redo_lsns
+        .iter()
+        .any(|&(first, _)| first as u64 <= cp.lsn)
+    });
+    summary.lost_versions = lost.iter().map(|x| x.lsn).collect();
+    dst.import_consistent_points(&kept);
+
+    let kept_lsns: HashSet<u64> = kept.iter().map(|x| x.lsn).collect();
+    summary.unreadable_source_info_lsns = src.source_info(|batch| {
+      for (lsn, info) in batch.iter().filter(|x| kept_lsns.contains(&x.0)) {
+        dst.add_source_info(*lsn, info);
+      }
+    });
+
+    summary.unreadable_pull_meta_lsns = src.pull_meta(|batch| {
+      for x in batch.iter().filter(|x| kept_lsns.contains(&x.lsn)) {
+        dst.add_pull_meta(x);
+      }
+    });
+
+    summary.unreadable_protection_lsns = src.protection(|batch| {
+      let batch: Vec<Protection> = batch
+        .into_iter()
+        .filter(|x| kept_lsns.contains(&x.lsn))
+        .collect();
+      summary.copied_protection += batch.len() as u64;
+      dst.import_protection(&batch);
+    });
+    match src.min_version_age_days() {
+      Ok(x) => {
+        if let Some(days) = x {
+          dst.set_min_version_age_days(days);
+        }
+        summary.min_version_age_days = x;
+      }
+      Err(e) => {
+        log::warn!("Cannot read min_version_age_days: {}", e);
+        summary.min_version_age_unreadable = true;
+      }
+    }
+    say!("Copied {} pins and locks.", summary.copied_protection);
+
+    summary.unreadable_attachment_rowids = src.attachments(|batch| {
+      let (good, bad): (Vec<SalvagedAttachment>, Vec<SalvagedAttachment>) = batch
+        .into_iter()
+        .filter(|x| kept_lsns.contains(&x.lsn))
+        .partition(|x| match x.decode() {
+          Ok(_) => true,
+          Err(e) => {
+            log::warn!(
+              "Attachment {} of version {} is damaged: {}.",
+              x.name,
+              x.lsn,
+              e
+            );
+            false
+          }
+        });
+      summary.copied_attachments += good.len() as u64;
+      summary.damaged_attachments += bad.len() as u64;
+      dst.import_attachments(&good);
+    });
+    say!(
+      "Copied {} attachments. {} attachments were damaged.",
+      summary.copied_attachments,
+      summary.damaged_attachments
+    );
+
+    let mut roots: HashMap<u64, MerkleRoot> = HashMap::new();
+    let unreadable_roots = src.merkle_roots(|x| roots.extend(x));
+
+    // Referenced blobs that weren't copied are recorded as damaged for `bsync repair`.
+    dst.find_missing_blobs();
+    for cp in &kept {
+      let snapshot = dst.snapshot(cp.lsn, Some(cp.size))?;
+
+      // Trees are rebuilt from the copied redo log, and keep their signature if they still match
+      // it. Versions pulled by older versions of bsync have neither.
+      let root_unreadable = unreadable_roots
+        .iter()
+        .any(|&(first, last)| (first..=last).contains(&(cp.lsn as i64)));
+      let root = roots.get(&cp.lsn);
+      if root.is_some() || root_unreadable {
+        let tree = snapshot.merkle_tree(cp.size);
+        let signature = match root {
+          Some(x) if x.root == tree.root() => x.public_key.clone().zip(x.signature.clone()),
+          Some(_) => {
+            log::warn!(
+              "The rebuilt Merkle tree of version {} doesn't match its root.",
+              cp.lsn
+            );
+            None
+          }
+          None => None,
+        };
+        let was_signed = root_unreadable || matches!(root, Some(x) if x.signature.is_some());
+        if signature.is_none() && was_signed {
+          summary.unsigned_versions.push(cp.lsn);
+        }
+        dst.import_merkle_tree(cp.lsn, &tree, signature);
+      }
+
+      let missing = snapshot.unreadable_blocks();
+      if missing.is_empty() {
+        summary.complete_versions.push(cp.lsn);
+      } else {
+        summary.partial_versions.push(PartialVersion {
+          lsn: cp.lsn,
+          missing_blocks: block_ranges(&missing),
+        });
+      }
+    }
+
+    for lsn in &summary.complete_versions {
+      say!("complete {}", lsn);
+    }
+    for x in &summary.partial_versions {
+      say!(
+        "partial  {}: missing blocks {}",
+        x.lsn,
+        format_ranges(&x.missing_blocks)
+      );
+    }
+    for lsn in &summary.lost_versions {
+      say!("lost     {}", lsn);
+    }
+    if !summary.unreadable_consistent_point_lsns.is_empty() {
+      say!(
+        "Consistent points in these LSN ranges couldn't be read: {:?}",
+        summary.unreadable_consistent_point_lsns
+      );
+    }
+    if !unreadable_roots.is_empty() {
+      say!(
+        "Merkle roots in these LSN ranges couldn't be read: {:?}",
+        unreadable_roots
+      );
+    }
+    summary.unreadable_merkle_root_lsns = unreadable_roots;
+    if !summary.unreadable_source_info_lsns.is_empty() {
+      say!(
+        "Remote image info in these LSN ranges couldn't be read: {:?}",
+        summary.unreadable_source_info_lsns
+      );
+    }
+    if !summary.unreadable_pull_meta_lsns.is_empty() {
+      say!(
+        "Pull metadata in these LSN ranges couldn't be read: {:?}",
+        summary.unreadable_pull_meta_lsns
+      );
+    }
+    if !summary.unreadable_protection_lsns.is_empty() {
+      say!(
+        "Pins and locks in these LSN ranges couldn't be read - check `bsync protect`: {:?}",
+        summary.unreadable_protection_lsns
+      );
+    }
+    if summary.min_version_age_unreadable {
+      say!("The minimum version age couldn't be read - set it again with `bsync protect`.");
+    }
+    if !summary.unreadable_attachment_rowids.is_empty() {
+      say!(
+        "Attachments in these rowid ranges couldn't be read: {:?}",
+        summary.unreadable_attachment_rowids
+      );
+    }
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    output::summary(&summary);
+    Ok(())
+  }
+}
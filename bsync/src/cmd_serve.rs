@@ -1,9 +1,10 @@
 use std::{
-  io::{Read, Seek, SeekFrom, Write},
+  io::{ErrorKind, Read, Seek, SeekFrom, Write},
   net::TcpListener,
   os::unix::net::UnixListener,
   path::PathBuf,
   sync::Arc,
+  time::Duration,
 };
 
 use anyhow::Result;
@@ -12,15 +13,21 @@ use nbd::{
   server::{handshake, transmission},
   Export,
 };
+use serde::Serialize;
 use structopt::StructOpt;
-use thiserror::Error;
 
 use crate::{
   blob::ZERO_BLOCK,
   config::LOG_BLOCK_SIZE,
-  db::{Database, Snapshot},
+  db::{Database, NotConsistentPoint, Snapshot, LEASE_READ},
+  interrupt::{self, Interrupted},
+  lease::{self, Lease},
+  output::{self, say},
 };
 
+/// How often the server checks whether to stop while waiting for connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
 /// Start a read-only NBD server for the version at the given LSN.
 #[derive(Debug, StructOpt)]
 pub struct Servecmd {
@@ -44,25 +51,35 @@ struct Service {
 }
 
 impl Service {
-  fn read_block<'a>(&'a mut self, index: usize) -> &'a [u8] {
+  fn read_block(&mut self, index: usize) -> std::io::Result<&[u8]> {
     let cache = &mut self.cache;
 
+    // Once the lease is lost, the version may be squashed away.
+    lease::check().map_err(|e| std::io::Error::other(e.to_string()))?;
+
     // XXX: Matching with `Some(x)` gives lifetime errors
     if cache.peek(&index).is_some() {
-      return cache.get(&index).unwrap();
-    } else if let Some(x) = self.snapshot.read_block(index as u64) {
-      cache.put(index, x);
-      cache.peek(&index).unwrap()
-    } else {
-      &ZERO_BLOCK[..]
+      return Ok(cache.get(&index).unwrap());
     }
+    // The client sees an I/O error on the damaged block, like on a failing disk.
+    let block = self.snapshot.read_block(index as u64).map_err(|e| {
+      log::error!("{}", e);
+      std::io::Error::other(e.to_string())
+    })?;
+    Ok(match block {
+      Some(x) => {
+        cache.put(index, x);
+        cache.peek(&index).unwrap()
+      }
+      None => &ZERO_BLOCK[..],
+    })
   }
 }
 
 impl Read for Service {
   fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
     let start_pos = self.cursor as usize;
-    let end_pos = start_pos as usize + buf.len();
+    let end_pos = start_pos + buf.len();
     let start_block = start_pos / LOG_BLOCK_SIZE;
     let end_block = (end_pos - 1) / LOG_BLOCK_SIZE;
 
@@ -70,7 +87,7 @@ impl Read for Service {
     log::trace!("requested read with pos {} len {}", current_pos, buf.len());
 
     for blkid in start_block..=end_block {
-      let blk = self.read_block(blkid);
+      let blk = self.read_block(blkid)?;
       let blk = &blk[current_pos % LOG_BLOCK_SIZE..];
       let buf_offset = current_pos - start_pos;
       let buf_copy_len = buf.len().checked_sub(buf_offset).unwrap().min(blk.len());
@@ -95,10 +112,7 @@ impl Read for Service {
 
 impl Write for Service {
   fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
-    Err(std::io::Error::new(
-      std::io::ErrorKind::Other,
-      "read only block device",
-    ))
+    Err(std::io::Error::other("read only block device"))
   }
 
   fn flush(&mut self) -> std::io::Result<()> {
@@ -120,23 +134,35 @@ impl Seek for Service {
 
 impl Servecmd {
   pub fn run(&self) -> Result<()> {
-    #[derive(Error, Debug)]
-    enum E {
-      #[error("the provided LSN is not a consistent point")]
-      Inconsistent,
-    }
-
+    interrupt::install()?;
     let db = Database::open_file(&self.db, false)?;
     let cp_list = db.list_consistent_point();
     let cp = match cp_list.iter().find(|x| x.lsn == self.lsn) {
       Some(x) => x,
-      None => return Err(E::Inconsistent.into()),
+      None => return Err(NotConsistentPoint("lsn").into()),
     };
-    let snapshot = Arc::new(db.snapshot(cp.lsn)?);
+    // Held until the server exits, so the version can't be squashed away while it's served.
+    let _lease = Lease::acquire(&db, LEASE_READ, Some(cp.lsn), "serve")?;
+    let snapshot = Arc::new(db.snapshot(cp.lsn, Some(cp.size))?);
 
     let listener = do_listen(&self.listen)?;
-    for conn in listener.incoming() {
-      let mut conn = conn?;
+    let summary = ServeSummary {
+      lsn: cp.lsn,
+      size: cp.size,
+      listen: &self.listen,
+    };
+    // The summary is only printed when the server exits, so also tell about the start.
+    output::print_started("serve", &summary);
+    output::event("listening", &summary);
+    output::summary(&summary);
+    say!("Serving LSN {} on {}.", cp.lsn, self.listen);
+    loop {
+      let mut conn = match listener.accept() {
+        Ok(x) => x,
+        // A signal is how a server is stopped.
+        Err(e) if e.is::<Interrupted>() => break,
+        Err(e) => return Err(e),
+      };
       let svc = Service {
         cache: LruCache::new(100),
         snapshot: snapshot.clone(),
@@ -154,10 +180,18 @@ impl Servecmd {
         }
       });
     }
+    say!("Stopped serving LSN {}.", cp.lsn);
     Ok(())
   }
 }
 
+#[derive(Serialize)]
+struct ServeSummary<'a> {
+  lsn: u64,
+  size: u64,
+  listen: &'a str,
+}
+
 trait ReadAndWrite: Read + Write + Send {}
 
 impl<T: Read + Write + Send> ReadAndWrite for T {}
@@ -168,29 +202,37 @@ enum GenericListener {
 }
 
 impl GenericListener {
-  fn incoming<'a>(
-    &'a self,
-  ) -> Box<dyn Iterator<Item = Result<Box<dyn ReadAndWrite>, std::io::Error>> + 'a> {
-    match self {
-      Self::Tcp(lis) => Box::new(
-        lis
-          .incoming()
-          .map(|x| x.map(|x| Box::new(x) as Box<dyn ReadAndWrite>)),
-      ),
-      Self::Unix(lis) => Box::new(
-        lis
-          .incoming()
-          .map(|x| x.map(|x| Box::new(x) as Box<dyn ReadAndWrite>)),
-      ),
+  /// Waits for a connection, checking every `ACCEPT_POLL_INTERVAL` whether to stop.
+  fn accept(&self) -> Result<Box<dyn ReadAndWrite>> {
+    loop {
+      interrupt::check()?;
+      let res = match self {
+        Self::Tcp(lis) => lis.accept().and_then(|(x, _)| {
+          x.set_nonblocking(false)?;
+          Ok(Box::new(x) as Box<dyn ReadAndWrite>)
+        }),
+        Self::Unix(lis) => lis.accept().and_then(|(x, _)| {
+          x.set_nonblocking(false)?;
+          Ok(Box::new(x) as Box<dyn ReadAndWrite>)
+        }),
+      };
+      match res {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL_INTERVAL),
+        res => return Ok(res?),
+      }
     }
   }
 }
 
 fn do_listen(addr: &str) -> Result<GenericListener, std::io::Error> {
   if let Some(path) = addr.strip_prefix("unix:") {
-    let _ = std::fs::remove_file(&path);
-    Ok(GenericListener::Unix(UnixListener::bind(path)?))
+    let _ = std::fs::remove_file(path);
+    let lis = UnixListener::bind(path)?;
+    lis.set_nonblocking(true)?;
+    Ok(GenericListener::Unix(lis))
   } else {
-    Ok(GenericListener::Tcp(TcpListener::bind(addr)?))
+    let lis = TcpListener::bind(addr)?;
+    lis.set_nonblocking(true)?;
+    Ok(GenericListener::Tcp(lis))
   }
 }
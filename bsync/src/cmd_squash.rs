@@ -1,10 +1,15 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Instant};
 
 use anyhow::Result;
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
 use structopt::StructOpt;
 use thiserror::Error;
 
-use crate::db::Database;
+use crate::{
+  db::{Database, NotConsistentPoint, SquashPreview},
+  output::{self, say},
+};
 
 /// Squash logs.
 #[derive(Debug, StructOpt)]
@@ -21,10 +26,18 @@ pub struct SquashCmd {
   #[structopt(long)]
   data_loss: bool,
 
+  /// Print what would be deleted, without changing anything. Doesn't need `--data-loss`.
+  #[structopt(long)]
+  dry_run: bool,
+
   /// Vacuum the database after squash.
   #[structopt(long)]
   vacuum: bool,
 
+  /// Allow removing versions younger than the repository's `min_version_age_days`.
+  #[structopt(long)]
+  override_min_age: bool,
+
   /// Path to the database.
   #[structopt(long)]
   db: PathBuf,
@@ -34,12 +47,6 @@ impl SquashCmd {
   pub fn run(&self) -> Result<()> {
     #[derive(Error, Debug)]
     enum E {
-      #[error("the provided `start_lsn` is not a consistent point")]
-      InconsistentStart,
-
-      #[error("the provided `end_lsn` is not a consistent point")]
-      InconsistentEnd,
-
       #[error("squash removes history - please confirm by adding the flag `--data-loss`.")]
       DataLoss,
     }
@@ -49,25 +56,74 @@ impl SquashCmd {
     if self.start_lsn != 0 {
       match cp_list.iter().find(|x| x.lsn == self.start_lsn) {
         Some(_) => {}
-        None => return Err(E::InconsistentStart.into()),
+        None => return Err(NotConsistentPoint("start_lsn").into()),
       }
     }
     match cp_list.iter().find(|x| x.lsn == self.end_lsn) {
       Some(_) => {}
-      None => return Err(E::InconsistentEnd.into()),
+      None => return Err(NotConsistentPoint("end_lsn").into()),
     };
 
+    let start = Instant::now();
+    let removed_versions = cp_list
+      .iter()
+      .filter(|x| x.lsn > self.start_lsn && x.lsn < self.end_lsn)
+      .count();
+
+    // Checked again by `Database::squash`. This is for `--dry-run`.
+    db.check_removable(self.start_lsn, self.end_lsn, self.override_min_age)?;
+
+    if self.dry_run {
+      let preview = db.squash_preview(&[(self.start_lsn, self.end_lsn)]);
+      say!(
+        "Dry run: would remove {} versions, {} redo log entries and {} blobs, reclaiming {}B.",
+        removed_versions,
+        preview.redo_rows,
+        preview.cas_blobs,
+        SizeFormatterBinary::new(preview.cas_bytes)
+      );
+      output::summary(&SquashSummary {
+        start_lsn: self.start_lsn,
+        end_lsn: self.end_lsn,
+        dry_run: true,
+        removed_versions,
+        deleted: Some(preview),
+        duration_ms: start.elapsed().as_millis() as u64,
+      });
+      return Ok(());
+    }
+
     if !self.data_loss {
       return Err(E::DataLoss.into());
     }
 
-    db.squash(self.start_lsn, self.end_lsn)?;
+    db.squash(self.start_lsn, self.end_lsn, self.override_min_age)?;
     db.cas_gc();
     if self.vacuum {
       db.vacuum();
     }
-    println!("Success.");
+    output::summary(&SquashSummary {
+      start_lsn: self.start_lsn,
+      end_lsn: self.end_lsn,
+      dry_run: false,
+      removed_versions,
+      deleted: None,
+      duration_ms: start.elapsed().as_millis() as u64,
+    });
+    say!("Success.");
 
     Ok(())
   }
 }
+
+#[derive(Serialize)]
+struct SquashSummary {
+  start_lsn: u64,
+  end_lsn: u64,
+  dry_run: bool,
+  removed_versions: usize,
+  /// Only computed with `--dry-run`.
+  #[serde(flatten)]
+  deleted: Option<SquashPreview>,
+  duration_ms: u64,
+}
@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use prettytable::{row, Table};
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+
+use crate::{config::LOG_BLOCK_SIZE, db::Database, output};
+
+/// Show how much space the versions take.
+#[derive(Debug, StructOpt)]
+pub struct Statscmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+}
+
+#[derive(Serialize)]
+struct StatsSummary {
+  versions: usize,
+  /// Sum of the image sizes of all versions.
+  logical_bytes: u64,
+  cas_blobs: u64,
+  /// Uncompressed size of the blobs.
+  unique_bytes: u64,
+  /// Compressed size of the blobs.
+  stored_bytes: u64,
+  dedup_ratio: f64,
+  compression_ratio: f64,
+  per_version: Vec<VersionStats>,
+}
+
+#[derive(Serialize)]
+struct VersionStats {
+  lsn: u64,
+  created_at: u64,
+  size: u64,
+  /// Blobs that only this version references, and their stored size. Removing only this version
+  /// frees them.
+  unique_blobs: u64,
+  unique_stored_bytes: u64,
+}
+
+impl Statscmd {
+  pub fn run(&self) -> Result<()> {
+    let db = Database::open_file(&self.db, false)?;
+    let cp_list = db.list_consistent_point();
+    let unique = db.unique_blobs_per_version();
+    let (cas_blobs, stored_bytes) = db.cas_stats();
+
+    let logical_bytes: u64 = cp_list.iter().map(|x| x.size).sum();
+    let unique_bytes = cas_blobs * LOG_BLOCK_SIZE as u64;
+    let ratio = |a: u64, b: u64| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+    let summary = StatsSummary {
+      versions: cp_list.len(),
+      logical_bytes,
+      cas_blobs,
+      unique_bytes,
+      stored_bytes,
+      dedup_ratio: ratio(logical_bytes, unique_bytes),
+      compression_ratio: ratio(unique_bytes, stored_bytes),
+      per_version: cp_list
+        .iter()
+        .map(|cp| {
+          let (unique_blobs, unique_stored_bytes) =
+            unique.get(&cp.lsn).copied().unwrap_or_default();
+          VersionStats {
+            lsn: cp.lsn,
+            created_at: cp.created_at,
+            size: cp.size,
+            unique_blobs,
+            unique_stored_bytes,
+          }
+        })
+        .collect(),
+    };
+
+    if !output::is_json() {
+      let size = |x: u64| format!("{}B", SizeFormatterBinary::new(x));
+      println!("Versions:          {}", summary.versions);
+      println!(
+        "Logical size:      {} (sum of the image sizes)",
+        size(logical_bytes)
+      );
+      println!(
+        "Unique blocks:     {} ({} uncompressed)",
+        cas_blobs,
+        size(unique_bytes)
+      );
+      println!("Stored size:       {}", size(stored_bytes));
+      println!("Dedup ratio:       {:.2}", summary.dedup_ratio);
+      println!("Compression ratio: {:.2}", summary.compression_ratio);
+      println!();
+
+      let mut table = Table::new();
+      table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+      table.set_titles(row![
+        "LSN",
+        "CREATED",
+        "SIZE",
+        "UNIQUE BLOBS",
+        "UNIQUE STORED"
+      ]);
+      for v in &summary.per_version {
+        table.add_row(row![
+          v.lsn,
+          NaiveDateTime::from_timestamp(v.created_at as i64, 0),
+          size(v.size),
+          v.unique_blobs,
+          size(v.unique_stored_bytes),
+        ]);
+      }
+      table.print_tty(false)?;
+    }
+    output::summary(&summary);
+    Ok(())
+  }
+}
@@ -0,0 +1,208 @@
+use std::{
+  path::PathBuf,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  db::{DamagedBlobInfo, Database},
+  output::{self, say, Progress},
+};
+
+/// Number of blobs checked between two progress updates.
+const VERIFY_BATCH_BLOBS: u64 = 256;
+
+#[derive(Error, Debug)]
+#[error("the repository is damaged: {0}")]
+pub struct RepositoryDamaged(pub String);
+
+/// Check that every blob in the database is readable and matches its hash, that every version
+/// can be restored, and that the SQLite file is consistent.
+///
+/// With `--scrub-fraction` or `--time-budget`, only check the next slice of blobs, continuing
+/// where the previous run stopped. Running it regularly checks every blob over time.
+#[derive(Debug, StructOpt)]
+pub struct Verifycmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// Check this fraction of the blobs (0 to 1).
+  #[structopt(long)]
+  scrub_fraction: Option<f64>,
+
+  /// Stop checking blobs after this many seconds.
+  #[structopt(long)]
+  time_budget: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct VerifySummary {
+  scrub: bool,
+  blobs_checked: u64,
+  bytes_checked: u64,
+  /// Damaged blobs that were rebuilt from parity while checking blobs.
+  healed_blobs: u64,
+  /// Damaged parity shards. Their groups are rebuilt. Only checked without `--scrub-fraction`
+  /// and `--time-budget`.
+  damaged_parity_shards: u64,
+  /// All blobs known to be damaged, including ones found by earlier scrubs.
+  damaged_blobs: Vec<DamagedBlobInfo>,
+  /// Versions with blocks that can't be read. Only checked without `--scrub-fraction` and
+  /// `--time-budget`.
+  damaged_versions: Vec<DamagedVersion>,
+  /// Problems reported by SQLite's `integrity_check`.
+  integrity_errors: Vec<String>,
+  duration_ms: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DamagedVersion {
+  lsn: u64,
+  unreadable_blocks: u64,
+}
+
+/// Returns the versions with blocks whose blob is missing or known to be damaged.
+pub(crate) fn damaged_versions(db: &Database) -> Result<Vec<DamagedVersion>> {
+  let mut out = vec![];
+  for cp in db.list_consistent_point() {
+    let snapshot = db.snapshot(cp.lsn, Some(cp.size))?;
+    let unreadable = snapshot.unreadable_blocks();
+    if !unreadable.is_empty() {
+      log::error!(
+        "Version {} has {} unreadable blocks.",
+        cp.lsn,
+        unreadable.len()
+      );
+      out.push(DamagedVersion {
+        lsn: cp.lsn,
+        unreadable_blocks: unreadable.len() as u64,
+      });
+    }
+  }
+  Ok(out)
+}
+
+impl Verifycmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    #[error("`--scrub-fraction` must be greater than 0 and at most 1")]
+    struct BadFraction;
+
+    let start = Instant::now();
+    let db = Database::open_file(&self.db, false)?;
+    let scrub = self.scrub_fraction.is_some() || self.time_budget.is_some();
+    if matches!(self.scrub_fraction, Some(x) if !(x > 0.0 && x <= 1.0)) {
+      return Err(BadFraction.into());
+    }
+    let mut summary = VerifySummary {
+      scrub,
+      ..Default::default()
+    };
+
+    // Blobs.
+    let (total_blobs, _) = db.cas_stats();
+    let target = match self.scrub_fraction {
+      Some(x) => ((total_blobs as f64 * x).ceil() as u64).max(1),
+      None => total_blobs,
+    };
+    let deadline = self.time_budget.map(|x| start + Duration::from_secs(x));
+    let start_cursor = if scrub { db.scrub_cursor() } else { 0 };
+    let mut cursor = start_cursor;
+    let mut wrapped = false;
+    let progress = Progress::new(
+      "verify",
+      target,
+      "{spinner:.green} Verify [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} blobs",
+    );
+    while summary.blobs_checked < target && deadline.map(|x| Instant::now() < x).unwrap_or(true) {
+      let limit = VERIFY_BATCH_BLOBS.min(target - summary.blobs_checked);
+      let scan = db.verify_blobs(cursor, limit);
+      summary.blobs_checked += scan.blobs;
+      summary.bytes_checked += scan.bytes;
+      summary.healed_blobs += scan.healed;
+      for x in &scan.damaged {
+        log::error!("Blob {} is damaged: {}.", x.hash, x.reason);
+      }
+      progress.set_position(summary.blobs_checked);
+      match scan.last_rowid {
+        Some(x) => cursor = x,
+        // Past the last blob. A scrub continues from the first one.
+        None if scrub && !wrapped && start_cursor != 0 => {
+          cursor = 0;
+          wrapped = true;
+        }
+        None => break,
+      }
+      if wrapped && cursor >= start_cursor {
+        break;
+      }
+    }
+    progress.finish();
+    if scrub {
+      db.set_scrub_cursor(cursor);
+    }
+    say!(
+      "Checked {} of {} blobs ({}B).",
+      summary.blobs_checked,
+      total_blobs,
+      SizeFormatterBinary::new(summary.bytes_checked)
+    );
+    if summary.healed_blobs != 0 {
+      say!(
+        "Rebuilt {} damaged blobs from parity.",
+        summary.healed_blobs
+      );
+    }
+
+    if !scrub {
+      let missing = db.find_missing_blobs();
+      if !missing.is_empty() {
+        log::error!(
+          "{} blobs referenced by the redo log are missing.",
+          missing.len()
+        );
+      }
+
+      summary.damaged_versions = damaged_versions(&db)?;
+
+      summary.damaged_parity_shards = db.verify_parity_shards();
+      if summary.damaged_parity_shards != 0 {
+        db.update_parity()?;
+      }
+
+      summary.integrity_errors = db.integrity_check();
+      for x in &summary.integrity_errors {
+        log::error!("integrity_check: {}", x);
+      }
+    }
+
+    summary.damaged_blobs = db.list_damaged_blobs();
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    output::summary(&summary);
+
+    let mut problems = vec![];
+    if !summary.damaged_blobs.is_empty() {
+      problems.push(format!("{} damaged blobs", summary.damaged_blobs.len()));
+    }
+    if !summary.damaged_versions.is_empty() {
+      problems.push(format!(
+        "{} damaged versions",
+        summary.damaged_versions.len()
+      ));
+    }
+    if !summary.integrity_errors.is_empty() {
+      problems.push("SQLite integrity check failed".to_string());
+    }
+    if !problems.is_empty() {
+      return Err(RepositoryDamaged(problems.join(", ")).into());
+    }
+    say!("No problems found.");
+    Ok(())
+  }
+}
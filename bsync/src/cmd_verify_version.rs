@@ -0,0 +1,175 @@
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{
+  blob::ZERO_BLOCK_HASH,
+  cmd_verify::RepositoryDamaged,
+  db::{block_count, Database, NotConsistentPoint, LEASE_READ},
+  lease::Lease,
+  merkle::{self, MerkleBuilder, CHUNK_BLOCKS},
+  output::{self, say, Progress},
+  util::format_ranges,
+};
+
+/// Check that a version still matches the Merkle root recorded when it was pulled. Every block is
+/// read and hashed, so this detects changed blobs and redo entries. If the root is signed, the
+/// signature is checked too.
+#[derive(Debug, StructOpt)]
+pub struct VerifyVersioncmd {
+  /// Path to the database.
+  #[structopt(long)]
+  db: PathBuf,
+
+  /// The LSN of the version.
+  #[structopt(long)]
+  lsn: u64,
+
+  /// Require the root to be signed with this Ed25519 public key, in hex. Without it, a signature
+  /// is only checked against the public key stored next to it.
+  #[structopt(long)]
+  public_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyVersionSummary {
+  lsn: u64,
+  size: u64,
+  root: String,
+  computed_root: String,
+  public_key: Option<String>,
+  /// `None` if the root isn't signed.
+  signature_valid: Option<bool>,
+  /// Inclusive block ranges whose stored tree nodes don't match their content.
+  mismatched_blocks: Vec<(u64, u64)>,
+  duration_ms: u64,
+}
+
+impl VerifyVersioncmd {
+  pub fn run(&self) -> Result<()> {
+    #[derive(Error, Debug)]
+    enum E {
+      #[error("version {0} has no Merkle root - it was pulled by an older version of bsync")]
+      NoMerkleRoot(u64),
+
+      #[error("`--public-key` must be 32 bytes in hex")]
+      BadPublicKey,
+    }
+
+    let start = Instant::now();
+    let trusted_key = match &self.public_key {
+      Some(x) => Some(
+        hex::decode(x)
+          .ok()
+          .filter(|x| x.len() == 32)
+          .ok_or(E::BadPublicKey)?,
+      ),
+      None => None,
+    };
+    let db = Database::open_file(&self.db, false)?;
+    let cp = db
+      .list_consistent_point()
+      .into_iter()
+      .find(|x| x.lsn == self.lsn)
+      .ok_or(NotConsistentPoint("lsn"))?;
+    let _lease = Lease::acquire(&db, LEASE_READ, Some(cp.lsn), "verify-version")?;
+    let recorded = db.merkle_root(cp.lsn).ok_or(E::NoMerkleRoot(cp.lsn))?;
+    let stored_tree = db.merkle_tree(cp.lsn, cp.size);
+
+    let snapshot = db.snapshot(cp.lsn, Some(cp.size))?;
+    let blocks = block_count(cp.size);
+    let progress = Progress::new(
+      "verify-version",
+      blocks,
+      "{spinner:.green} Verify [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} blocks",
+    );
+    let mut builder = MerkleBuilder::new(cp.size);
+    for block_id in 0..blocks {
+      progress.set_position(block_id);
+      let hash = match snapshot.read_block(block_id)? {
+        Some(x) => *blake3::hash(&x).as_bytes(),
+        None => *ZERO_BLOCK_HASH,
+      };
+      builder.push(hash);
+    }
+    progress.finish();
+    let tree = builder.finish();
+    let computed_root = tree.root();
+
+    // The stored nodes locate the blocks that changed, as long as they weren't changed themselves.
+    let mut mismatched_blocks = vec![];
+    for (idx, hash) in tree.levels[0].iter().enumerate() {
+      let idx = idx as u64;
+      if stored_tree
+        .as_ref()
+        .and_then(|x| x.node(merkle::STORED_LEVEL, idx))
+        != Some(hash)
+      {
+        let first = idx * CHUNK_BLOCKS;
+        mismatched_blocks.push((first, (first + CHUNK_BLOCKS).min(blocks) - 1));
+      }
+    }
+
+    let signature_valid = recorded.signature.as_ref().map(|signature| {
+      merkle::verify_signature(
+        recorded.public_key.as_deref().unwrap_or_default(),
+        signature,
+        cp.lsn,
+        &recorded.root,
+      )
+    });
+
+    let mut problems = vec![];
+    if computed_root != recorded.root {
+      problems.push("the content doesn't match the Merkle root".to_string());
+    }
+    if !mismatched_blocks.is_empty() {
+      log::error!(
+        "Blocks that don't match the stored tree: {}",
+        format_ranges(&mismatched_blocks)
+      );
+      problems.push(format!(
+        "{} ranges of blocks don't match the stored tree",
+        mismatched_blocks.len()
+      ));
+    }
+    match signature_valid {
+      Some(false) => problems.push("the signature is invalid".to_string()),
+      None if trusted_key.is_some() => problems.push("the root isn't signed".to_string()),
+      _ => {}
+    }
+    if let Some(key) = &trusted_key {
+      if signature_valid == Some(true) && recorded.public_key.as_ref() != Some(key) {
+        problems.push("the root is signed with a different key".to_string());
+      }
+    }
+
+    output::summary(&VerifyVersionSummary {
+      lsn: cp.lsn,
+      size: cp.size,
+      root: hex::encode(recorded.root),
+      computed_root: hex::encode(computed_root),
+      public_key: recorded.public_key.as_ref().map(hex::encode),
+      signature_valid,
+      mismatched_blocks,
+      duration_ms: start.elapsed().as_millis() as u64,
+    });
+    if !problems.is_empty() {
+      return Err(RepositoryDamaged(format!("version {}: {}", cp.lsn, problems.join(", "))).into());
+    }
+    say!(
+      "Version {} matches its Merkle root {}{}.",
+      cp.lsn,
+      hex::encode(computed_root),
+      if signature_valid == Some(true) {
+        " and the signature is valid"
+      } else {
+        ""
+      }
+    );
+    Ok(())
+  }
+}
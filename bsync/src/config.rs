@@ -1,5 +1,6 @@
 use serde::Deserialize;
 use std::path::Path;
+use thiserror::Error;
 
 pub const LOG_BLOCK_SIZE: usize = 262144;
 
@@ -7,6 +8,28 @@ pub const LOG_BLOCK_SIZE: usize = 262144;
 pub struct BackupConfig {
   pub remote: BackupRemoteConfig,
   pub local: BackupLocalConfig,
+
+  /// Notifications sent after each pull.
+  #[serde(default)]
+  pub notify: Vec<NotifyConfig>,
+
+  /// When `bsync daemon` runs this pull.
+  pub schedule: Option<ScheduleConfig>,
+
+  /// Which versions `bsync prune` keeps.
+  pub retention: Option<RetentionConfig>,
+
+  /// Checks for ransomware-like change patterns during a pull.
+  pub guard: Option<GuardConfig>,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+  #[error("cannot open backup config at {0}: {1}")]
+  Open(String, std::io::Error),
+
+  #[error("cannot parse backup config at {0}: {1}")]
+  Parse(String, serde_yaml::Error),
 }
 
 #[derive(Deserialize)]
@@ -32,54 +55,382 @@ pub struct BackupRemoteConfig {
 
   /// Scripts.
   pub scripts: Option<BackupRemoteScripts>,
+
+  /// Interval between keepalive messages, in seconds. Keepalives are disabled if this is empty.
+  pub keepalive_interval: Option<u32>,
+
+  /// Timeout for connecting and for each blocking SSH operation, in seconds. Defaults to no timeout.
+  pub timeout: Option<u32>,
+
+  /// Retry policy for failed batches.
+  #[serde(default)]
+  pub retry: RetryConfig,
+
+  /// Compute a SHA-256 of the whole image on the remote while hashing its blocks. It is stored
+  /// with the version, and `replay` checks its output against it.
+  #[serde(default)]
+  pub checksum: bool,
+
+  /// How to detect and handle changes to the image while it is being pulled, e.g. when there is no
+  /// snapshot.
+  #[serde(default)]
+  pub source_change: SourceChangeConfig,
+
+  /// Identity fields of the image not to compare with the previous version, e.g. `dm_name` if the
+  /// `pre_pull` script creates a snapshot with a new name each time.
+  #[serde(default)]
+  pub ignore_identity: Vec<IdentityField>,
+
+  /// Command outputs and files captured from the remote after `pre_pull`, and stored with the
+  /// new version.
+  #[serde(default)]
+  pub attachments: Vec<AttachmentConfig>,
 }
 
+#[derive(Deserialize)]
+pub struct AttachmentConfig {
+  /// Name of the attachment, and of its file when extracted. Must be unique and can't contain `/`.
+  pub name: String,
+
+  #[serde(flatten)]
+  pub source: AttachmentSource,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AttachmentSource {
+  /// The stdout of a shell command. It gets the image path in `BSYNC_IMAGE`.
+  Command(String),
+  /// The content of a file.
+  File(String),
+}
+
+/// A field of the identity of the remote image. A pull fails if one of them changed since the
+/// previous version.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityField {
+  Wwid,
+  Serial,
+  Partition,
+  Uuid,
+  DmName,
+  /// Both the logical and the physical sector size.
+  SectorSize,
+}
+
+#[derive(Deserialize, Default)]
+pub struct SourceChangeConfig {
+  /// What to do with a fetched block that doesn't match the hash seen while diffing. Defaults to
+  /// `warn`.
+  #[serde(default)]
+  pub on_mismatch: SourceChangeAction,
+
+  /// Hash the remote image again after the pull, and mark the new version fuzzy if it no longer
+  /// matches.
+  #[serde(default)]
+  pub verify: bool,
+}
+
+#[derive(Deserialize, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceChangeAction {
+  /// Store the block as fetched and mark the new version fuzzy.
+  #[default]
+  Warn,
+  /// Fetch the block once more. If it still doesn't match, store it and mark the new version
+  /// fuzzy.
+  Refetch,
+  /// Fail the pull.
+  Fail,
+}
+
+#[derive(Deserialize)]
+pub struct RetryConfig {
+  /// Maximum number of retries for a single batch after a connection, I/O or timeout error.
+  /// Defaults to 3.
+  #[serde(default = "default_max_retries")]
+  pub max_retries: u32,
+
+  /// Delay before the first retry, in milliseconds. Doubled after each retry. Defaults to 1000.
+  #[serde(default = "default_initial_backoff_ms")]
+  pub initial_backoff_ms: u64,
+
+  /// Upper bound of the delay between retries, in milliseconds. Defaults to 60000.
+  #[serde(default = "default_max_backoff_ms")]
+  pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+  fn default() -> Self {
+    Self {
+      max_retries: default_max_retries(),
+      initial_backoff_ms: default_initial_backoff_ms(),
+      max_backoff_ms: default_max_backoff_ms(),
+    }
+  }
+}
+
+fn default_max_retries() -> u32 {
+  3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+  1000
+}
+
+fn default_max_backoff_ms() -> u64 {
+  60000
+}
+
+/// Scripts run on the backup source.
+///
+/// Scripts get the following environment variables: `BSYNC_STATUS` (`running`, `success` or
+/// `failure`), `BSYNC_IMAGE`, `BSYNC_LSN` (the new LSN, on success), `BSYNC_BYTES` (bytes
+/// downloaded so far) and `BSYNC_ERROR` (on failure).
 #[derive(Deserialize)]
 pub struct BackupRemoteScripts {
-  pub no_pull_lock: Option<bool>,
+  /// Run before the pull. If it prints a `BSYNC_IMAGE=<path>` line, `<path>` is pulled instead of
+  /// `remote.image`.
   pub pre_pull: Option<String>,
+
+  /// Run after the pull, whether it succeeded or not.
   pub post_pull: Option<String>,
+
+  /// Run before `post_pull` if the pull failed or was interrupted.
+  pub on_failure: Option<String>,
+
+  /// Timeout of each script, in seconds. Defaults to no timeout.
+  pub timeout: Option<u64>,
 }
 
+/// Scripts run on the backup host. Same semantics as `BackupRemoteScripts`.
 #[derive(Deserialize)]
+pub struct BackupLocalScripts {
+  pub pre_pull: Option<String>,
+  pub post_pull: Option<String>,
+  pub on_failure: Option<String>,
+  pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub enum HostVerification {
   Insecure,
+  #[default]
   Known,
   Dnssec,
 }
 
-impl Default for HostVerification {
-  fn default() -> Self {
-    Self::Known
-  }
-}
-
 #[derive(Deserialize)]
 pub struct BackupLocalConfig {
   /// Local database path.
   pub db: String,
 
-  /// Local pull lock path.
+  /// Optional lock file held during the pull. Pulls of the same database are serialized anyway;
+  /// this also serializes pulls of all configs sharing the file.
   pub pull_lock: Option<String>,
+
+  /// Scripts.
+  pub scripts: Option<BackupLocalScripts>,
+
+  /// Path of a Prometheus textfile to update after each pull, e.g. in the directory of the
+  /// node_exporter textfile collector.
+  pub metrics_textfile: Option<String>,
+
+  /// Path to an Ed25519 private key, stored as 32 bytes in hex, that signs the Merkle root of each
+  /// version. Create one with `openssl rand -hex 32`.
+  pub signing_key: Option<String>,
+
+  /// Approximate memory ceiling of a pull, in MiB. Defaults to 1024.
+  ///
+  /// Large images are diffed and fetched in windows sized to fit in this budget.
+  pub max_memory_mib: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleConfig {
+  /// Cron expression in local time, e.g. `30 2 * * *`. See `Schedule` for the syntax.
+  pub cron: String,
+
+  /// Maximum random delay added to each run, in seconds. Defaults to 0.
+  #[serde(default)]
+  pub jitter: u64,
+
+  /// Number of retries of a failed pull. Defaults to 3.
+  #[serde(default = "default_schedule_retries")]
+  pub retries: u32,
+
+  /// Delay before the first retry, in seconds. Doubled after each retry. Defaults to 300.
+  #[serde(default = "default_retry_delay")]
+  pub retry_delay: u64,
+}
+
+fn default_schedule_retries() -> u32 {
+  3
+}
+
+fn default_retry_delay() -> u64 {
+  300
+}
+
+/// A grandfather-father-son retention policy. The latest version is always kept.
+///
+/// For each `keep_<period>`, the newest version of each of the last `keep_<period>` periods that
+/// have a version is kept. Periods are in local time; weeks start on Monday.
+#[derive(Deserialize)]
+pub struct RetentionConfig {
+  /// Number of most recent versions to keep.
+  #[serde(default)]
+  pub keep_last: u32,
+
+  #[serde(default)]
+  pub keep_hourly: u32,
+
+  #[serde(default)]
+  pub keep_daily: u32,
+
+  #[serde(default)]
+  pub keep_weekly: u32,
+
+  #[serde(default)]
+  pub keep_monthly: u32,
+
+  #[serde(default)]
+  pub keep_yearly: u32,
+
+  /// Maximum stored size of the kept versions, in MiB. The oldest versions are removed until they
+  /// fit, even if the periods above would keep them.
+  pub max_db_size_mib: Option<u64>,
+}
+
+/// Flags a pull that changes far more blocks than the previous ones, or that turns blocks which
+/// compressed well into incompressible ones, as encryption does.
+#[derive(Deserialize)]
+pub struct GuardConfig {
+  /// What to do with a flagged pull. Defaults to `suspect`.
+  #[serde(default)]
+  pub action: GuardAction,
+
+  /// Number of previous versions whose fraction of changed blocks is averaged. Defaults to 10.
+  #[serde(default = "default_guard_history")]
+  pub history: usize,
+
+  /// Flag the pull if its fraction of changed blocks is more than this many times the average.
+  /// Defaults to 5.
+  #[serde(default = "default_change_factor")]
+  pub change_factor: f64,
+
+  /// Never flag the pull for changing at most this fraction of the blocks. Defaults to 0.2.
+  #[serde(default = "default_min_change_fraction")]
+  pub min_change_fraction: f64,
+
+  /// Flag the pull if more than this fraction of the fetched blocks that compressed well in the
+  /// previous version are now incompressible. Defaults to 0.5.
+  #[serde(default = "default_max_incompressible_fraction")]
+  pub max_incompressible_fraction: f64,
+
+  /// Minimum number of fetched blocks that compressed well in the previous version for the check
+  /// above to apply. Defaults to 64.
+  #[serde(default = "default_min_compressible_blocks")]
+  pub min_compressible_blocks: u64,
+}
+
+#[derive(Deserialize, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GuardAction {
+  /// Create the version, but mark it suspect. Suspect versions don't count towards the retention
+  /// policy.
+  #[default]
+  Suspect,
+  /// Fail the pull without creating a version.
+  Abort,
+}
+
+fn default_guard_history() -> usize {
+  10
+}
+
+fn default_change_factor() -> f64 {
+  5.0
+}
+
+fn default_min_change_fraction() -> f64 {
+  0.2
+}
+
+fn default_max_incompressible_fraction() -> f64 {
+  0.5
+}
+
+fn default_min_compressible_blocks() -> u64 {
+  64
+}
+
+#[derive(Deserialize)]
+pub struct NotifyConfig {
+  /// Outcomes to notify about. Defaults to `failure` and `warning`.
+  #[serde(default = "default_notify_on")]
+  pub on: Vec<NotifyEvent>,
+
+  /// Message template. `{{name}}` is replaced with the field `name` of the pull summary (the same
+  /// fields as `bsync pull --format json`), `status`, `error` or `warnings`.
+  pub template: Option<String>,
+
+  #[serde(flatten)]
+  pub backend: NotifyBackend,
+}
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifyEvent {
+  Success,
+  Failure,
+  /// The pull succeeded, but something needs attention, e.g. batches were retried, or the image
+  /// was resized or changed during the pull.
+  Warning,
+}
+
+fn default_notify_on() -> Vec<NotifyEvent> {
+  vec![NotifyEvent::Failure, NotifyEvent::Warning]
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum NotifyBackend {
+  /// POST a JSON object with the message and the pull summary to `url`.
+  Webhook { url: String },
+
+  /// Send an email through an SMTP relay. Neither TLS nor authentication is supported, so this is
+  /// meant for a local MTA.
+  Smtp {
+    server: String,
+
+    /// Defaults to 25.
+    port: Option<u16>,
+
+    from: String,
+    to: Vec<String>,
+
+    /// Subject template. Defaults to `bsync pull {{status}}: {{image}}`.
+    subject: Option<String>,
+  },
+
+  /// Run a command on the backup host, with the message on stdin and the fields of the summary in
+  /// `BSYNC_NOTIFY_*` environment variables.
+  Exec { command: String },
 }
 
 impl BackupConfig {
+  pub fn load_from_file(path: &Path) -> Result<Self, ConfigError> {
+    let path_str = path.to_string_lossy().into_owned();
+    let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Open(path_str.clone(), e))?;
+    serde_yaml::from_str(&text).map_err(|e| ConfigError::Parse(path_str, e))
+  }
+
   pub fn must_load_from_file(path: &Path) -> Self {
-    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| {
-      log::error!(
-        "cannot open backup config at {}: {}",
-        path.to_string_lossy(),
-        e
-      );
-      std::process::exit(1);
-    });
-    serde_yaml::from_str(&text).unwrap_or_else(|e| {
-      log::error!(
-        "cannot parse backup config at {}: {}",
-        path.to_string_lossy(),
-        e
-      );
+    Self::load_from_file(path).unwrap_or_else(|e| {
+      log::error!("{}", e);
       std::process::exit(1);
     })
   }
@@ -1,4 +1,5 @@
 use std::{
+  collections::HashMap,
   convert::TryInto,
   path::Path,
   sync::{
@@ -9,11 +10,20 @@ use std::{
 };
 
 use anyhow::Result;
+use chrono::NaiveDateTime;
 use parking_lot::Mutex;
 use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
 use thiserror::Error;
 
-use crate::{blob::ZERO_BLOCK_HASH, util::align_block};
+use crate::{
+  blob::ZERO_BLOCK_HASH,
+  config::LOG_BLOCK_SIZE,
+  merkle::{self, MerkleBuilder, MerkleTree},
+  parity,
+  source::SourceInfo,
+  util::{align_block, hostname, unix_secs},
+};
 
 macro_rules! migration {
   ($id:ident, $($version:expr,)*) => {
@@ -23,14 +33,75 @@ macro_rules! migration {
   };
 }
 
-migration!(VERSIONS, "000001", "000002", "000003",);
+migration!(
+  VERSIONS, "000001", "000002", "000003", "000004", "000005", "000006", "000007", "000008",
+  "000009", "000010", "000011", "000012", "000013", "000014", "000015",
+);
 
 static SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
 
+/// Number of LSNs `squash` processes per transaction.
+const SQUASH_BATCH_LSNS: u64 = 16384;
+
+/// Number of blobs `cas_gc` checks per transaction.
+const GC_BATCH_BLOBS: u64 = 4096;
+
+/// Number of pages `vacuum` frees per step.
+const VACUUM_STEP_PAGES: u64 = 2048;
+
+/// Number of rows `SalvageSource` reads per query, and probes one by one after a failed query.
+const SALVAGE_BATCH_ROWS: i64 = 1024;
+
+/// Number of consecutive unreadable batches after which `SalvageSource` gives up on a table whose
+/// last rowid is unknown.
+const SALVAGE_MAX_EMPTY_BATCHES: u32 = 64;
+
+/// How often `cas_gc` checks whether a running pull has finished.
+const GC_PULL_WAIT: Duration = Duration::from_secs(5);
+
+/// The exclusive lease held by `bsync pull`.
+pub const LEASE_PULL: &str = "pull";
+
+/// A lease on a consistent point that is being read.
+pub const LEASE_READ: &str = "read";
+
+#[derive(Error, Debug)]
+#[error("base lsn mismatch: expecting {0}, got {1}")]
+pub struct LsnMismatch(u64, u64);
+
+#[derive(Error, Debug)]
+#[error("block with hash {0} was assumed to exist in CAS but does not exist anymore - did you run `bsync squash` just now? please retry.")]
+pub struct MissingHash(String);
+
+#[derive(Error, Debug)]
+#[error("blob {0} is damaged: {1} - run `bsync verify` to find all damaged blobs")]
+pub struct DamagedBlob(pub String, pub String);
+
+#[derive(Error, Debug)]
+#[error("the provided `{0}` is not a consistent point")]
+pub struct NotConsistentPoint(pub &'static str);
+
+#[derive(Error, Debug)]
+#[error("version {0} is protected: {1}")]
+pub struct VersionProtected(pub u64, pub String);
+
+#[derive(Error, Debug)]
+#[error("version {0} is locked until {1} - a lock can be extended, but not shortened")]
+pub struct LockShortened(u64, NaiveDateTime);
+
+#[derive(Error, Debug, Clone)]
+#[error("the {0} lock is held by {1}")]
+pub struct LeaseHeld(pub &'static str, pub String);
+
+#[derive(Error, Debug)]
+#[error("version {0} is in use by {1}")]
+pub struct VersionInUse(pub u64, pub String);
+
 #[derive(Clone)]
 pub struct Database {
   db: Arc<Mutex<Connection>>,
   instance_id: Arc<str>,
+  path: Arc<Path>,
 }
 
 #[derive(Clone)]
@@ -38,6 +109,151 @@ pub struct ConsistentPoint {
   pub lsn: u64,
   pub size: u64,
   pub created_at: u64,
+  /// SHA-256 of the whole image, if the pull computed it.
+  pub sha256: Option<[u8; 32]>,
+  /// The image changed while it was being pulled, so this may not be a point-in-time copy of it.
+  pub fuzzy: bool,
+  /// Why the guard flagged the pull of this version, if it did.
+  pub suspect: Option<String>,
+}
+
+/// Merkle root of a consistent point, with its signature if the pull signed it.
+pub struct MerkleRoot {
+  pub root: [u8; 32],
+  pub public_key: Option<Vec<u8>>,
+  pub signature: Option<Vec<u8>>,
+}
+
+/// Why a consistent point must not be removed.
+#[derive(Clone, Serialize)]
+pub struct Protection {
+  pub lsn: u64,
+  pub pin_reason: Option<String>,
+  /// Seconds since the epoch.
+  pub locked_until: Option<u64>,
+  pub lock_reason: Option<String>,
+}
+
+impl Protection {
+  /// Whether the protection is in effect at `now`.
+  pub fn is_active(&self, now: u64) -> bool {
+    self.pin_reason.is_some() || matches!(self.locked_until, Some(x) if x > now)
+  }
+
+  /// Describes the protection in effect at `now`.
+  pub fn describe(&self, now: u64) -> Option<String> {
+    let mut out = vec![];
+    if let Some(x) = &self.pin_reason {
+      out.push(format!("pinned ({})", x));
+    }
+    if let Some(until) = self.locked_until.filter(|x| *x > now) {
+      let until = NaiveDateTime::from_timestamp(until as i64, 0);
+      out.push(
+        match self.lock_reason.as_deref().filter(|x| !x.is_empty()) {
+          Some(reason) => format!("locked until {} ({})", until, reason),
+          None => format!("locked until {}", until),
+        },
+      );
+    }
+    if out.is_empty() {
+      None
+    } else {
+      Some(out.join(", "))
+    }
+  }
+}
+
+#[derive(Clone, Serialize)]
+pub struct LeaseInfo {
+  pub id: i64,
+  pub kind: String,
+  pub lsn: Option<u64>,
+  pub holder: String,
+  pub hostname: String,
+  pub pid: u32,
+  /// Seconds since the epoch.
+  pub acquired_at: u64,
+  pub expires_at: u64,
+}
+
+impl LeaseInfo {
+  pub fn describe(&self) -> String {
+    format!("{} (pid {} on {})", self.holder, self.pid, self.hostname)
+  }
+
+  /// Whether the holder is known to be gone: it ran on this host, and its process doesn't exist.
+  fn is_stale(&self, hostname: &str) -> bool {
+    if self.hostname != hostname {
+      return false;
+    }
+    let ret = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+    ret != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+  }
+}
+
+/// A blob that `bsync verify` found to be missing or unreadable.
+#[derive(Clone, Serialize)]
+pub struct DamagedBlobInfo {
+  /// Hex-encoded.
+  pub hash: String,
+  pub reason: String,
+  /// Seconds since the epoch.
+  pub detected_at: u64,
+}
+
+/// Result of checking a range of CAS blobs.
+#[derive(Default)]
+pub struct BlobScan {
+  /// Rowid of the last blob checked, to continue from. `None` if there were no blobs left.
+  pub last_rowid: Option<i64>,
+  pub blobs: u64,
+  /// Stored size of the blobs checked.
+  pub bytes: u64,
+  pub damaged: Vec<DamagedBlobInfo>,
+  /// Damaged blobs that were rebuilt from parity.
+  pub healed: u64,
+}
+
+/// Parity coverage of the CAS.
+#[derive(Serialize, Default)]
+pub struct ParityStats {
+  pub data_shards: u32,
+  pub parity_shards: u32,
+  pub groups: u64,
+  pub protected_blobs: u64,
+  pub unprotected_blobs: u64,
+  pub parity_bytes: u64,
+}
+
+/// How a consistent point was produced by `bsync pull`.
+#[derive(Clone, Serialize)]
+pub struct PullMeta {
+  pub lsn: u64,
+  /// Start of the pull, in milliseconds since the epoch.
+  pub started_at: u64,
+  /// Time the consistent point was written, in milliseconds since the epoch.
+  pub finished_at: u64,
+  pub remote_host: String,
+  pub image: String,
+  pub downloaded_bytes: u64,
+  pub reused_bytes: u64,
+  pub changed_blocks: u64,
+  pub total_blocks: u64,
+  pub bsync_version: String,
+  pub pre_pull_output: Option<String>,
+  pub post_pull_output: Option<String>,
+  pub local_pre_pull_output: Option<String>,
+  pub local_post_pull_output: Option<String>,
+}
+
+/// A command output or file captured from the remote with a consistent point.
+#[derive(Serialize)]
+pub struct AttachmentInfo {
+  pub name: String,
+  /// Size of the content, in bytes.
+  pub size: u64,
+  /// Compressed size in the database, in bytes.
+  pub stored_size: u64,
 }
 
 pub enum RedoContentOrHash<'a> {
@@ -58,11 +274,15 @@ impl Database {
 
     let mut db = Connection::open_with_flags(path, flags)?;
 
-    db.execute_batch(
-      r#"
-      pragma journal_mode = wal;
-    "#,
-    )?;
+    // `auto_vacuum` can only be set before the first table is created. Existing databases are
+    // converted by `vacuum`.
+    let is_new: bool = db.query_row("select count(*) = 0 from sqlite_master", params![], |r| {
+      r.get(0)
+    })?;
+    if is_new {
+      db.execute_batch("pragma auto_vacuum = incremental;")?;
+    }
+    db.execute_batch("pragma journal_mode = wal;")?;
     db.busy_handler(Some(|i| {
       log::debug!("Waiting for lock on database (attempt {})", i);
       std::thread::sleep(Duration::from_millis(100));
@@ -86,16 +306,28 @@ impl Database {
     Ok(Self {
       db: Arc::new(Mutex::new(db)),
       instance_id: Arc::from(instance_id.as_str()),
+      path: Arc::from(path),
     })
   }
 
+  /// Opens another connection to the same database, for a thread that must not wait for this
+  /// connection to be unlocked.
+  pub fn reopen(&self) -> Result<Self> {
+    Self::open_file(&self.path, false)
+  }
+
   pub fn instance_id(&self) -> &str {
-    &*self.instance_id
+    &self.instance_id
   }
 
-  pub fn snapshot(&self, lsn: u64) -> Result<Snapshot> {
+  /// Materializes the block map at `lsn`.
+  ///
+  /// If `size` is given, blocks at or past the end of an image of that size are left out, so
+  /// that blocks left over from a larger version of the image never show up.
+  pub fn snapshot(&self, lsn: u64, size: Option<u64>) -> Result<Snapshot> {
     let id = SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
     let table_name = format!("snapshot_{}", id);
+    let block_limit = size.map(block_count).unwrap_or(i64::MAX as u64);
     let db = self.db.lock();
     let start = Instant::now();
     db.execute_batch(&format!(
@@ -110,9 +342,9 @@ impl Database {
         select max(lsn) from redo_v1
         where lsn <= {}
         group by block_id
-      );
+      ) and block_id < {};
     "#,
-      table_name, table_name, lsn
+      table_name, table_name, lsn, block_limit
     ))?;
     log::info!(
       "Materialized snapshot at LSN {} in {:?}.",
@@ -130,14 +362,6 @@ impl Database {
     base_lsn: u64,
     data: impl IntoIterator<Item = (u64, RedoContentOrHash<'a>)>,
   ) -> Result<u64> {
-    #[derive(Error, Debug)]
-    #[error("base lsn mismatch: expecting {0}, got {1}")]
-    struct LsnMismatch(u64, u64);
-
-    #[derive(Error, Debug)]
-    #[error("block with hash {0} was assumed to exist in CAS but does not exist anymore - did you run `bsync squash` just now? please retry.")]
-    struct MissingHash(String);
-
     let mut db = self.db.lock();
     let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
     let max_lsn: Option<u64>;
@@ -177,7 +401,7 @@ impl Database {
                 .execute(params![&hash[..], &content[..]])
                 .unwrap();
             }
-            RedoContentOrHash::Hash(_) => return Err(MissingHash(hex::encode(&hash)).into()),
+            RedoContentOrHash::Hash(_) => return Err(MissingHash(hex::encode(hash)).into()),
           }
         }
         insert_redo_stmt
@@ -205,6 +429,31 @@ impl Database {
     x.unwrap_or(0)
   }
 
+  /// Deletes the redo entries after the latest consistent point, which a failed pull left behind.
+  /// Returns how many there were.
+  pub fn discard_incomplete_pull(&self) -> usize {
+    self
+      .db
+      .lock()
+      .execute(
+        "delete from redo_v1 where lsn > coalesce((select max(lsn) from consistent_point_v1), -1)",
+        params![],
+      )
+      .unwrap()
+  }
+
+  /// Returns the stored (compressed) size of the blob `hash`, if it exists.
+  pub fn blob_stored_size(&self, hash: &[u8; 32]) -> Option<u64> {
+    self
+      .db
+      .lock()
+      .prepare_cached("select length(content) from cas_v1 where hash = ?")
+      .unwrap()
+      .query_row(params![&hash[..]], |r| r.get(0))
+      .optional()
+      .unwrap()
+  }
+
   pub fn exists_in_cas(&self, hash: &[u8; 32]) -> bool {
     let v: Option<u32> = self
       .db
@@ -219,10 +468,25 @@ impl Database {
     v.is_some()
   }
 
+  /// Returns the number of blobs in the CAS and their total stored size in bytes.
+  pub fn cas_stats(&self) -> (u64, u64) {
+    self
+      .db
+      .lock()
+      .query_row(
+        "select count(*), coalesce(sum(length(content)), 0) from cas_v1",
+        params![],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+      )
+      .unwrap()
+  }
+
   pub fn list_consistent_point(&self) -> Vec<ConsistentPoint> {
     let db = self.db.lock();
     let mut stmt = db
-      .prepare_cached("select lsn, size, created_at from consistent_point_v1 order by lsn asc")
+      .prepare_cached(
+        "select lsn, size, created_at, sha256, fuzzy, suspect from consistent_point_v1 order by lsn asc",
+      )
       .unwrap();
     stmt
       .query_map(params![], |r| {
@@ -230,6 +494,11 @@ impl Database {
           lsn: r.get(0)?,
           size: r.get(1)?,
           created_at: r.get(2)?,
+          sha256: r
+            .get::<_, Option<Vec<u8>>>(3)?
+            .and_then(|x| x.try_into().ok()),
+          fuzzy: r.get(4)?,
+          suspect: r.get(5)?,
         })
       })
       .unwrap()
@@ -237,117 +506,2291 @@ impl Database {
       .unwrap()
   }
 
-  pub fn add_consistent_point(&self, lsn: u64, size: u64) {
-    let db = self.db.lock();
+  /// Marks `lsn` as a consistent point. Fails if `lsn` is no longer the last LSN, e.g. because
+  /// the latest version was deleted while it was being pulled.
+  ///
+  /// The Merkle tree of the version and the public key and signature of its root are recorded in
+  /// the same transaction.
+  ///
+  /// A pull that didn't change anything ends at an existing consistent point. Its checksum is
+  /// filled in if it doesn't have one yet, and its original tree is kept.
+  pub fn add_consistent_point(
+    &self,
+    lsn: u64,
+    size: u64,
+    sha256: Option<[u8; 32]>,
+    tree: &MerkleTree,
+    signature: Option<(Vec<u8>, Vec<u8>)>,
+  ) -> Result<()> {
+    let mut db = self.db.lock();
     let now = SystemTime::now()
       .duration_since(UNIX_EPOCH)
       .unwrap()
       .as_secs();
-    // XXX: This doesn't update the size if the block device is extended with zeros.
-    let mut stmt = db
-      .prepare_cached(
-        "insert or ignore into consistent_point_v1 (lsn, size, created_at) values(?, ?, ?)",
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let max_lsn: u64 = txn
+      .query_row(
+        "select coalesce(max(lsn), 0) from redo_v1",
+        params![],
+        |r| r.get(0),
       )
       .unwrap();
-    stmt.execute(params![lsn, size, now]).unwrap();
-  }
-
-  pub fn squash(&self, start_lsn: u64, end_lsn: u64) -> Result<()> {
-    let mut db = self.db.lock();
-    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
-    txn.execute_batch(&format!(r#"
-      delete from consistent_point_v1 where lsn > {from} and lsn < {to};
-      create temp table squash (
-        `lsn` integer not null primary key
-      );
-      insert into temp.squash (lsn)
-        select max(lsn) from redo_v1
-          where lsn > {from} and lsn <= {to}
-          group by block_id;
-      delete from redo_v1 where lsn > {from} and lsn <= {to} and not exists (select * from temp.squash where lsn = redo_v1.lsn);
-      drop table temp.squash;
-    "#, from = start_lsn, to = end_lsn)).unwrap();
+    if max_lsn != lsn {
+      return Err(LsnMismatch(lsn, max_lsn).into());
+    }
+    txn
+      .execute(
+        r#"
+        insert into consistent_point_v1 (lsn, size, created_at, sha256) values(?, ?, ?, ?)
+          on conflict (lsn) do update set sha256 = coalesce(sha256, excluded.sha256)
+        "#,
+        params![lsn, size, now, sha256.as_ref().map(|x| &x[..])],
+      )
+      .unwrap();
+    insert_merkle_tree(&txn, lsn, tree, signature);
     txn.commit().unwrap();
     Ok(())
   }
 
-  pub fn cas_gc(&self) {
-    let db = self.db.lock();
-    db.execute_batch(
-      r#"
-      delete from cas_v1 where hash not in (select hash from redo_v1);
-    "#,
-    )
-    .unwrap();
+  /// Marks the consistent point at `lsn` as fuzzy.
+  pub fn mark_fuzzy(&self, lsn: u64) {
+    self
+      .db
+      .lock()
+      .execute(
+        "update consistent_point_v1 set fuzzy = 1 where lsn = ?",
+        params![lsn],
+      )
+      .unwrap();
   }
 
-  pub fn vacuum(&self) {
-    self.db.lock().execute_batch("vacuum;").unwrap();
+  /// Marks the consistent point at `lsn` as suspect, for `reason`.
+  pub fn mark_suspect(&self, lsn: u64, reason: &str) {
+    self
+      .db
+      .lock()
+      .execute(
+        "update consistent_point_v1 set suspect = ? where lsn = ?",
+        params![reason, lsn],
+      )
+      .unwrap();
   }
-}
 
-pub struct Snapshot {
-  db: Database,
-  table_name: String,
-}
+  pub fn merkle_root(&self, lsn: u64) -> Option<MerkleRoot> {
+    self
+      .db
+      .lock()
+      .query_row(
+        "select root, public_key, signature from merkle_root_v1 where lsn = ?",
+        params![lsn],
+        |r| {
+          Ok(MerkleRoot {
+            root: r.get::<_, Vec<u8>>(0)?.try_into().unwrap_or_default(),
+            public_key: r.get(1)?,
+            signature: r.get(2)?,
+          })
+        },
+      )
+      .optional()
+      .unwrap()
+  }
 
-impl Snapshot {
-  pub fn read_block(&self, block_id: u64) -> Option<Vec<u8>> {
-    let hash = self.read_block_hash(block_id)?;
-    if hash == *ZERO_BLOCK_HASH {
-      return None;
+  /// Loads the stored nodes of the tree of the consistent point at `lsn`, if it has one.
+  pub fn merkle_tree(&self, lsn: u64, size: u64) -> Option<MerkleTree> {
+    self.merkle_root(lsn)?;
+    let db = self.db.lock();
+    let mut stmt = db
+      .prepare_cached("select level, hash from merkle_node_v1 where lsn = ? order by level, idx")
+      .unwrap();
+    let mut levels: Vec<Vec<[u8; 32]>> = vec![vec![]];
+    for row in stmt
+      .query_map(params![lsn], |r| {
+        Ok((r.get::<_, u32>(0)?, r.get::<_, Vec<u8>>(1)?))
+      })
+      .unwrap()
+    {
+      let (level, hash) = row.unwrap();
+      let i = level.checked_sub(merkle::STORED_LEVEL)? as usize;
+      if levels.len() <= i {
+        levels.resize(i + 1, vec![]);
+      }
+      levels[i].push(hash.try_into().ok()?);
     }
+    Some(MerkleTree { size, levels })
+  }
 
-    let db = self.db.db.lock();
+  /// Returns the hashes of the blocks in `[first, end)` that were written up to `lsn`, ordered by
+  /// block.
+  pub fn block_hashes(&self, lsn: u64, first: u64, end: u64) -> Vec<(u64, [u8; 32])> {
+    self
+      .db
+      .lock()
+      .prepare_cached(
+        r#"
+        select block_id, hash from redo_v1
+          where lsn in (
+            select max(lsn) from redo_v1
+              where block_id >= ?2 and block_id < ?3 and lsn <= ?1
+              group by block_id
+          )
+          order by block_id asc
+        "#,
+      )
+      .unwrap()
+      .query_map(params![lsn, first, end], |r| {
+        Ok((r.get(0)?, r.get::<_, Vec<u8>>(1)?))
+      })
+      .unwrap()
+      .map(|x| {
+        let (block_id, hash) = x.unwrap();
+        (block_id, hash.try_into().unwrap())
+      })
+      .collect()
+  }
+
+  /// Records how the consistent point at `meta.lsn` was produced. A pull that didn't change
+  /// anything ends at an existing consistent point, whose original metadata is kept.
+  pub fn add_pull_meta(&self, meta: &PullMeta) {
+    let db = self.db.lock();
     let mut stmt = db
       .prepare_cached(
         r#"
-        select content, compressed from cas_v1 where hash = ?
+        insert or ignore into pull_meta_v1 (
+          lsn, started_at, finished_at, remote_host, image,
+          downloaded_bytes, reused_bytes, changed_blocks, total_blocks, bsync_version,
+          pre_pull_output, post_pull_output, local_pre_pull_output, local_post_pull_output
+        ) values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
       "#,
       )
       .unwrap();
-    let (content, compressed): (Vec<u8>, bool) = stmt
-      .query_row(params![&hash[..]], |r| Ok((r.get(0)?, r.get(1)?)))
-      .optional()
-      .unwrap()?;
-    if compressed {
-      let content = zstd::decode_all(&content[..]).expect("read_block: decompression failed");
-      Some(content)
-    } else {
-      Some(content)
-    }
+    stmt
+      .execute(params![
+        meta.lsn,
+        meta.started_at,
+        meta.finished_at,
+        meta.remote_host,
+        meta.image,
+        meta.downloaded_bytes,
+        meta.reused_bytes,
+        meta.changed_blocks,
+        meta.total_blocks,
+        meta.bsync_version,
+        meta.pre_pull_output,
+        meta.post_pull_output,
+        meta.local_pre_pull_output,
+        meta.local_post_pull_output,
+      ])
+      .unwrap();
   }
 
-  pub fn read_block_hash(&self, block_id: u64) -> Option<[u8; 32]> {
-    let db = self.db.db.lock();
+  pub fn list_pull_meta(&self) -> Vec<PullMeta> {
+    let db = self.db.lock();
     let mut stmt = db
-      .prepare_cached(&format!(
-        "select hash from temp.{} where block_id = ?",
-        self.table_name
-      ))
+      .prepare_cached(
+        r#"
+        select lsn, started_at, finished_at, remote_host, image,
+          downloaded_bytes, reused_bytes, changed_blocks, total_blocks, bsync_version,
+          pre_pull_output, post_pull_output, local_pre_pull_output, local_post_pull_output
+        from pull_meta_v1 order by lsn asc
+      "#,
+      )
       .unwrap();
-    let hash: Vec<u8> = stmt
-      .query_row(params![block_id], |r| r.get(0))
-      .optional()
-      .unwrap()?;
-    Some(hash.try_into().unwrap())
+    stmt
+      .query_map(params![], |r| {
+        Ok(PullMeta {
+          lsn: r.get(0)?,
+          started_at: r.get(1)?,
+          finished_at: r.get(2)?,
+          remote_host: r.get(3)?,
+          image: r.get(4)?,
+          downloaded_bytes: r.get(5)?,
+          reused_bytes: r.get(6)?,
+          changed_blocks: r.get(7)?,
+          total_blocks: r.get(8)?,
+          bsync_version: r.get(9)?,
+          pre_pull_output: r.get(10)?,
+          post_pull_output: r.get(11)?,
+          local_pre_pull_output: r.get(12)?,
+          local_post_pull_output: r.get(13)?,
+        })
+      })
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
   }
-}
 
-impl Drop for Snapshot {
-  fn drop(&mut self) {
-    self
-      .db
-      .db
-      .lock()
-      .execute_batch(&format!(
+  /// Records the remote image info of the consistent point at `lsn`. A pull that didn't change
+  /// anything ends at an existing consistent point, whose original info is kept.
+  pub fn add_source_info(&self, lsn: u64, info: &SourceInfo) {
+    let db = self.db.lock();
+    let mut stmt = db
+      .prepare_cached(
         r#"
-      drop table temp.{};
-    "#,
-        &self.table_name
-      ))
+        insert or ignore into source_info_v1 (
+          lsn, size, logical_sector_size, physical_sector_size, wwid, serial, partition, uuid,
+          dm_name
+        ) values(?, ?, ?, ?, ?, ?, ?, ?, ?)
+      "#,
+      )
+      .unwrap();
+    stmt
+      .execute(params![
+        lsn,
+        info.size,
+        info.logical_sector_size,
+        info.physical_sector_size,
+        info.wwid,
+        info.serial,
+        info.partition,
+        info.uuid,
+        info.dm_name,
+      ])
+      .unwrap();
+  }
+
+  /// Returns the remote image info of each consistent point that has one, by LSN.
+  pub fn list_source_info(&self) -> Vec<(u64, SourceInfo)> {
+    let db = self.db.lock();
+    let mut stmt = db
+      .prepare_cached(
+        r#"
+        select lsn, size, logical_sector_size, physical_sector_size, wwid, serial, partition, uuid,
+          dm_name
+        from source_info_v1 order by lsn asc
+      "#,
+      )
+      .unwrap();
+    stmt
+      .query_map(params![], |r| {
+        Ok((
+          r.get(0)?,
+          SourceInfo {
+            size: r.get(1)?,
+            logical_sector_size: r.get(2)?,
+            physical_sector_size: r.get(3)?,
+            wwid: r.get(4)?,
+            serial: r.get(5)?,
+            partition: r.get(6)?,
+            uuid: r.get(7)?,
+            dm_name: r.get(8)?,
+          },
+        ))
+      })
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
+  }
+
+  /// Stores `attachments` as `(name, content)` pairs with the consistent point at `lsn`. A pull
+  /// that didn't change anything ends at an existing consistent point, whose original attachments
+  /// are kept.
+  pub fn add_attachments(&self, lsn: u64, attachments: &[(String, Vec<u8>)]) -> Result<()> {
+    let mut db = self.db.lock();
+    let txn = db.transaction()?;
+    {
+      let mut stmt = txn
+        .prepare_cached(
+          "insert or ignore into attachment_v1 (lsn, name, size, content) values(?, ?, ?, ?)",
+        )
+        .unwrap();
+      for (name, content) in attachments {
+        let compressed = zstd::encode_all(&content[..], 3)?;
+        stmt
+          .execute(params![lsn, name, content.len() as u64, compressed])
+          .unwrap();
+      }
+    }
+    txn.commit().unwrap();
+    Ok(())
+  }
+
+  pub fn list_attachments(&self, lsn: u64) -> Vec<AttachmentInfo> {
+    let db = self.db.lock();
+    let mut stmt = db
+      .prepare_cached(
+        "select name, size, length(content) from attachment_v1 where lsn = ? order by name asc",
+      )
       .unwrap();
+    stmt
+      .query_map(params![lsn], |r| {
+        Ok(AttachmentInfo {
+          name: r.get(0)?,
+          size: r.get(1)?,
+          stored_size: r.get(2)?,
+        })
+      })
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
   }
+
+  /// Returns the content of the attachment `name` of the consistent point at `lsn`.
+  pub fn read_attachment(&self, lsn: u64, name: &str) -> Result<Option<Vec<u8>>> {
+    let content: Option<Vec<u8>> = self
+      .db
+      .lock()
+      .query_row(
+        "select content from attachment_v1 where lsn = ? and name = ?",
+        params![lsn, name],
+        |r| r.get(0),
+      )
+      .optional()
+      .unwrap();
+    Ok(content.map(|x| zstd::decode_all(&x[..])).transpose()?)
+  }
+
+  /// Removes the consistent points between `start_lsn` and `end_lsn` (exclusive), and the redo
+  /// entries in `(start_lsn, end_lsn]` that are overwritten before `end_lsn`. Fails without
+  /// removing anything if one of the consistent points is protected.
+  ///
+  /// The consistent points are removed first. After that, no snapshot can see the overwritten redo
+  /// entries, so they are deleted in small transactions that a concurrent pull can interleave
+  /// with. An interrupted squash leaves the database consistent and can be run again.
+  pub fn squash(&self, start_lsn: u64, end_lsn: u64, ignore_min_age: bool) -> Result<()> {
+    {
+      let mut db = self.db.lock();
+      let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+      check_removable(&txn, start_lsn, end_lsn, ignore_min_age)?;
+      txn
+        .execute(
+          "delete from protection_v1 where lsn > ? and lsn < ?",
+          params![start_lsn, end_lsn],
+        )
+        .unwrap();
+      txn
+        .execute(
+          "delete from consistent_point_v1 where lsn > ? and lsn < ?",
+          params![start_lsn, end_lsn],
+        )
+        .unwrap();
+      for table in [
+        "pull_meta_v1",
+        "source_info_v1",
+        "attachment_v1",
+        "merkle_root_v1",
+        "merkle_node_v1",
+      ] {
+        txn
+          .execute(
+            &format!("delete from {} where lsn > ? and lsn < ?", table),
+            params![start_lsn, end_lsn],
+          )
+          .unwrap();
+      }
+      txn.commit().unwrap();
+    }
+
+    let mut batch_start = start_lsn;
+    let mut deleted = 0;
+    while batch_start < end_lsn {
+      let batch_end = end_lsn.min(batch_start + SQUASH_BATCH_LSNS);
+      deleted += self
+        .db
+        .lock()
+        .prepare_cached(
+          r#"
+          delete from redo_v1 where lsn > ?1 and lsn <= ?2 and exists (
+            select * from redo_v1 next
+              where next.block_id = redo_v1.block_id and next.lsn > redo_v1.lsn and next.lsn <= ?3
+          )
+          "#,
+        )
+        .unwrap()
+        .execute(params![batch_start, batch_end, end_lsn])
+        .unwrap();
+      batch_start = batch_end;
+    }
+    log::info!(
+      "Squashed ({}, {}], deleting {} redo log entries.",
+      start_lsn,
+      end_lsn,
+      deleted
+    );
+    Ok(())
+  }
+
+  /// Removes the consistent point at `lsn`, so that all others replay the same as before.
+  ///
+  /// A version in the middle or at the start is squashed into the next one. The latest version is
+  /// dropped with all redo entries after the previous consistent point, so that the next pull
+  /// starts from there.
+  pub fn delete_consistent_point(&self, lsn: u64, ignore_min_age: bool) -> Result<()> {
+    let cp_list = self.list_consistent_point();
+    let i = cp_list
+      .iter()
+      .position(|x| x.lsn == lsn)
+      .ok_or(NotConsistentPoint("lsn"))?;
+    let prev_lsn = if i == 0 { 0 } else { cp_list[i - 1].lsn };
+    if let Some(next) = cp_list.get(i + 1) {
+      return self.squash(prev_lsn, next.lsn, ignore_min_age);
+    }
+
+    // This is one pull's worth of redo entries, so it's done in a single transaction. Lowering the
+    // last LSN makes a concurrent pull fail with `LsnMismatch` instead of producing a version on
+    // top of the deleted one.
+    let mut db = self.db.lock();
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    check_removable(&txn, prev_lsn, u64::MAX, ignore_min_age)?;
+    if let Some(pull) = list_leases(&txn, unix_secs())
+      .into_iter()
+      .find(|x| x.kind == LEASE_PULL)
+    {
+      return Err(LeaseHeld(LEASE_PULL, pull.describe()).into());
+    }
+    for table in [
+      "consistent_point_v1",
+      "pull_meta_v1",
+      "source_info_v1",
+      "attachment_v1",
+      "protection_v1",
+      "merkle_root_v1",
+      "merkle_node_v1",
+      "redo_v1",
+    ] {
+      txn
+        .execute(
+          &format!("delete from {} where lsn > ?", table),
+          params![prev_lsn],
+        )
+        .unwrap();
+    }
+    txn.commit().unwrap();
+    Ok(())
+  }
+
+  pub fn list_protection(&self) -> Vec<Protection> {
+    list_protection(&self.db.lock())
+  }
+
+  /// Returns the consistent points that can't be removed now, and why. With `ignore_min_age`,
+  /// versions that are only protected by `min_version_age_days` are left out.
+  pub fn protected_versions(&self, ignore_min_age: bool) -> HashMap<u64, String> {
+    protected_versions(&self.db.lock(), ignore_min_age)
+  }
+
+  /// Fails if a consistent point strictly between `start_lsn` and `end_lsn` can't be removed now.
+  pub fn check_removable(&self, start_lsn: u64, end_lsn: u64, ignore_min_age: bool) -> Result<()> {
+    check_removable(&self.db.lock(), start_lsn, end_lsn, ignore_min_age)
+  }
+
+  pub fn pin(&self, lsn: u64, reason: &str) -> Result<()> {
+    let mut db = self.db.lock();
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    ensure_consistent_point(&txn, lsn)?;
+    txn
+      .execute(
+        r#"
+        insert into protection_v1 (lsn, pin_reason) values(?1, ?2)
+          on conflict (lsn) do update set pin_reason = ?2
+        "#,
+        params![lsn, reason],
+      )
+      .unwrap();
+    txn.commit().unwrap();
+    Ok(())
+  }
+
+  pub fn unpin(&self, lsn: u64) -> Result<()> {
+    let db = self.db.lock();
+    ensure_consistent_point(&db, lsn)?;
+    db.execute(
+      "update protection_v1 set pin_reason = null where lsn = ?",
+      params![lsn],
+    )
+    .unwrap();
+    Ok(())
+  }
+
+  /// Locks the consistent point at `lsn` until `until` (seconds since the epoch). An existing lock
+  /// can only be extended.
+  pub fn lock_version(&self, lsn: u64, until: u64, reason: &str) -> Result<()> {
+    let mut db = self.db.lock();
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    ensure_consistent_point(&txn, lsn)?;
+    let current: Option<u64> = txn
+      .query_row(
+        "select locked_until from protection_v1 where lsn = ?",
+        params![lsn],
+        |r| r.get(0),
+      )
+      .optional()
+      .unwrap()
+      .flatten();
+    if let Some(current) = current {
+      if current > until && current > unix_secs() {
+        return Err(LockShortened(lsn, NaiveDateTime::from_timestamp(current as i64, 0)).into());
+      }
+    }
+    txn
+      .execute(
+        r#"
+        insert into protection_v1 (lsn, locked_until, lock_reason) values(?1, ?2, ?3)
+          on conflict (lsn) do update set locked_until = ?2, lock_reason = ?3
+        "#,
+        params![lsn, until, reason],
+      )
+      .unwrap();
+    txn.commit().unwrap();
+    Ok(())
+  }
+
+  /// Takes a lease of `kind`, valid for `ttl` seconds unless renewed. Only one `LEASE_PULL` can be
+  /// held at a time. A `LEASE_READ` is on the consistent point at `lsn`, which can't be removed
+  /// while the lease is held.
+  pub fn acquire_lease(
+    &self,
+    kind: &'static str,
+    lsn: Option<u64>,
+    holder: &str,
+    ttl: u64,
+  ) -> Result<i64> {
+    let mut db = self.db.lock();
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let now = unix_secs();
+    txn
+      .execute("delete from lease_v1 where expires_at <= ?", params![now])
+      .unwrap();
+    let hostname = hostname();
+    for lease in read_leases(&txn) {
+      if lease.is_stale(&hostname) {
+        log::warn!("Removing the stale lease of {}.", lease.describe());
+        txn
+          .execute("delete from lease_v1 where id = ?", params![lease.id])
+          .unwrap();
+      } else if kind == LEASE_PULL && lease.kind == LEASE_PULL {
+        return Err(LeaseHeld(LEASE_PULL, lease.describe()).into());
+      }
+    }
+    if let Some(lsn) = lsn {
+      ensure_consistent_point(&txn, lsn)?;
+    }
+    txn
+      .execute(
+        r#"
+        insert into lease_v1 (kind, lsn, holder, hostname, pid, acquired_at, expires_at)
+          values(?, ?, ?, ?, ?, ?, ?)
+        "#,
+        params![
+          kind,
+          lsn,
+          holder,
+          hostname,
+          std::process::id(),
+          now,
+          now + ttl
+        ],
+      )
+      .unwrap();
+    let id = txn.last_insert_rowid();
+    txn.commit().unwrap();
+    Ok(id)
+  }
+
+  /// Extends a lease by `ttl` seconds from now. Returns false if the lease no longer exists, or
+  /// has expired - others may have ignored it since.
+  pub fn renew_lease(&self, id: i64, ttl: u64) -> Result<bool> {
+    let now = unix_secs();
+    let n = self.db.lock().execute(
+      "update lease_v1 set expires_at = ? where id = ? and expires_at > ?",
+      params![now + ttl, id, now],
+    )?;
+    Ok(n != 0)
+  }
+
+  pub fn release_lease(&self, id: i64) {
+    self
+      .db
+      .lock()
+      .execute("delete from lease_v1 where id = ?", params![id])
+      .unwrap();
+  }
+
+  /// Returns the leases that haven't expired.
+  pub fn list_leases(&self) -> Vec<LeaseInfo> {
+    list_leases(&self.db.lock(), unix_secs())
+  }
+
+  /// Versions younger than this many days can only be removed with an explicit override.
+  pub fn min_version_age_days(&self) -> u64 {
+    min_version_age_days(&self.db.lock())
+  }
+
+  pub fn set_min_version_age_days(&self, days: u64) {
+    self
+      .db
+      .lock()
+      .execute(
+        "replace into bsync_config (k, v) values('min_version_age_days', ?)",
+        params![days.to_string()],
+      )
+      .unwrap();
+  }
+
+  /// Returns what `squash` followed by `cas_gc` would delete for each of `ranges`, without
+  /// changing anything. Blobs that are already unreferenced are included.
+  pub fn squash_preview(&self, ranges: &[(u64, u64)]) -> SquashPreview {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    txn
+      .execute_batch("create temp table squash_deleted (`lsn` integer not null primary key);")
+      .unwrap();
+    {
+      let mut stmt = txn
+        .prepare_cached(
+          r#"
+          insert or ignore into temp.squash_deleted (lsn)
+            select lsn from redo_v1 where lsn > ?1 and lsn <= ?2 and lsn not in (
+              select max(lsn) from redo_v1 where lsn > ?1 and lsn <= ?2 group by block_id
+            )
+          "#,
+        )
+        .unwrap();
+      for &(start_lsn, end_lsn) in ranges {
+        stmt.execute(params![start_lsn, end_lsn]).unwrap();
+      }
+    }
+    let redo_rows = txn
+      .query_row("select count(*) from temp.squash_deleted", params![], |r| {
+        r.get(0)
+      })
+      .unwrap();
+    let (cas_blobs, cas_bytes) = txn
+      .query_row(
+        r#"
+        select count(*), coalesce(sum(length(content)), 0) from cas_v1 where hash not in (
+          select hash from redo_v1 where lsn not in (select lsn from temp.squash_deleted)
+        )
+        "#,
+        params![],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+      )
+      .unwrap();
+    txn
+      .execute_batch("drop table temp.squash_deleted;")
+      .unwrap();
+    txn.commit().unwrap();
+    SquashPreview {
+      redo_rows,
+      cas_blobs,
+      cas_bytes,
+    }
+  }
+
+  /// Returns, for each consistent point, the number and stored size of the blobs that no other
+  /// consistent point references - what removing it alone would free. Consistent points without
+  /// such blobs are left out.
+  pub fn unique_blobs_per_version(&self) -> HashMap<u64, (u64, u64)> {
+    let db = self.db.lock();
+    // A redo entry is visible from the first consistent point at or after it, until the next write
+    // to its block. It's unique to that consistent point if the next consistent point comes after
+    // the next write, or if both don't exist. Entries not visible in exactly one consistent point
+    // get the owner -1.
+    let mut stmt = db
+      .prepare_cached(
+        r#"
+        with w as (
+          select hash, lsn, lead(lsn) over (partition by block_id order by lsn) as next_write
+            from redo_v1
+        ), f as (
+          select hash, next_write, (select min(lsn) from consistent_point_v1 where lsn >= w.lsn) as first_cp
+            from w
+        ), o as (
+          select hash, case
+              when first_cp is not null
+                and coalesce((select min(lsn) from consistent_point_v1 where lsn > f.first_cp), 9223372036854775807)
+                  >= coalesce(next_write, 9223372036854775807)
+              then first_cp
+              else -1
+            end as owner
+            from f
+        ), u as (
+          select hash, min(owner) as owner from o
+            group by hash
+            having min(owner) = max(owner) and min(owner) >= 0
+        )
+        select u.owner, count(*), coalesce(sum(length(cas_v1.content)), 0)
+          from u join cas_v1 on cas_v1.hash = u.hash
+          group by u.owner
+        "#,
+      )
+      .unwrap();
+    stmt
+      .query_map(params![], |r| Ok((r.get(0)?, (r.get(1)?, r.get(2)?))))
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
+  }
+
+  /// Decompresses and hashes up to `limit` CAS blobs with a rowid greater than `after_rowid`.
+  /// Damaged blobs are recorded in `damaged_blob_v1`, and blobs that verify are removed from it.
+  pub fn verify_blobs(&self, after_rowid: i64, limit: u64) -> BlobScan {
+    let rows: Vec<(i64, Vec<u8>, Vec<u8>, bool)> = self
+      .db
+      .lock()
+      .prepare_cached(
+        "select rowid, hash, content, compressed from cas_v1 where rowid > ? order by rowid asc limit ?",
+      )
+      .unwrap()
+      .query_map(params![after_rowid, limit], |r| {
+        Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+      })
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap();
+
+    // Decoding is the expensive part, so it's done without holding the connection.
+    let mut scan = BlobScan {
+      last_rowid: rows.last().map(|x| x.0),
+      blobs: rows.len() as u64,
+      ..Default::default()
+    };
+    let mut good = vec![];
+    for (_, hash, content, compressed) in rows {
+      scan.bytes += content.len() as u64;
+      match decode_blob(&hash, &content, compressed) {
+        Ok(_) => good.push(hash),
+        Err(_) if self.heal_blob(&hash).is_some() => {
+          scan.healed += 1;
+          good.push(hash);
+        }
+        Err(reason) => scan.damaged.push(DamagedBlobInfo {
+          hash: hex::encode(&hash),
+          reason,
+          detected_at: unix_secs(),
+        }),
+      }
+    }
+
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for hash in &good {
+      txn
+        .prepare_cached("delete from damaged_blob_v1 where hash = ?")
+        .unwrap()
+        .execute(params![hash])
+        .unwrap();
+    }
+    record_damaged_blobs(&txn, &scan.damaged);
+    txn.commit().unwrap();
+    scan
+  }
+
+  /// Returns the hashes that redo entries refer to but that aren't in the CAS, and records them
+  /// as damaged.
+  pub fn find_missing_blobs(&self) -> Vec<DamagedBlobInfo> {
+    let hashes: Vec<Vec<u8>> = self
+      .db
+      .lock()
+      .prepare_cached(
+        r#"
+        select distinct hash from redo_v1
+          where not exists (select * from cas_v1 where cas_v1.hash = redo_v1.hash)
+        "#,
+      )
+      .unwrap()
+      .query_map(params![], |r| r.get(0))
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap();
+    let missing: Vec<DamagedBlobInfo> = hashes
+      .into_iter()
+      .filter(|x| self.heal_blob(x).is_none())
+      .map(|x| DamagedBlobInfo {
+        hash: hex::encode(x),
+        reason: "missing from the CAS".into(),
+        detected_at: unix_secs(),
+      })
+      .collect();
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    record_damaged_blobs(&txn, &missing);
+    txn.commit().unwrap();
+    missing
+  }
+
+  /// Inserts blobs copied by `bsync salvage`.
+  pub fn import_blobs(&self, blobs: &[SalvagedBlob]) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for x in blobs {
+      txn
+        .prepare_cached("insert or ignore into cas_v1 (hash, content, compressed) values(?, ?, ?)")
+        .unwrap()
+        .execute(params![x.hash, x.content, x.compressed])
+        .unwrap();
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Inserts redo entries copied by `bsync salvage`, keeping their LSNs.
+  pub fn import_redo(&self, rows: &[(u64, u64, Vec<u8>)]) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for (lsn, block_id, hash) in rows {
+      txn
+        .prepare_cached("insert or ignore into redo_v1 (lsn, block_id, hash) values(?, ?, ?)")
+        .unwrap()
+        .execute(params![lsn, block_id, hash])
+        .unwrap();
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Inserts consistent points copied by `bsync salvage`.
+  pub fn import_consistent_points(&self, cps: &[ConsistentPoint]) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for cp in cps {
+      txn
+        .execute(
+          r#"
+          insert or ignore into consistent_point_v1 (
+            lsn, size, created_at, sha256, fuzzy, suspect
+          ) values(?, ?, ?, ?, ?, ?)
+          "#,
+          params![
+            cp.lsn,
+            cp.size,
+            cp.created_at,
+            cp.sha256.as_ref().map(|x| &x[..]),
+            cp.fuzzy,
+            cp.suspect
+          ],
+        )
+        .unwrap();
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Inserts attachments copied by `bsync salvage`.
+  pub fn import_attachments(&self, attachments: &[SalvagedAttachment]) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for x in attachments {
+      txn
+        .prepare_cached(
+          "insert or ignore into attachment_v1 (lsn, name, size, content) values(?, ?, ?, ?)",
+        )
+        .unwrap()
+        .execute(params![x.lsn, x.name, x.size, x.content])
+        .unwrap();
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Inserts pins and locks copied by `bsync salvage`.
+  pub fn import_protection(&self, rows: &[Protection]) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    for x in rows {
+      txn
+        .prepare_cached(
+          r#"
+          insert or ignore into protection_v1 (lsn, pin_reason, locked_until, lock_reason)
+            values(?, ?, ?, ?)
+          "#,
+        )
+        .unwrap()
+        .execute(params![x.lsn, x.pin_reason, x.locked_until, x.lock_reason])
+        .unwrap();
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Records a Merkle tree rebuilt by `bsync salvage`.
+  pub fn import_merkle_tree(
+    &self,
+    lsn: u64,
+    tree: &MerkleTree,
+    signature: Option<(Vec<u8>, Vec<u8>)>,
+  ) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    insert_merkle_tree(&txn, lsn, tree, signature);
+    txn.commit().unwrap();
+  }
+
+  /// Replaces a damaged or missing blob with `content`, if it matches `hash`. Returns whether it
+  /// did.
+  pub fn repair_blob(&self, hash: &[u8; 32], content: &[u8]) -> Result<bool> {
+    if blake3::hash(content).as_bytes() != hash {
+      return Ok(false);
+    }
+    let content = zstd::encode_all(&*align_block(content), 3)?;
+    let mut db = self.db.lock();
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    txn
+      .execute(
+        r#"
+        insert into cas_v1 (hash, content, compressed) values(?, ?, 1)
+          on conflict (hash) do update set content = excluded.content, compressed = 1
+        "#,
+        params![&hash[..], &content[..]],
+      )
+      .unwrap();
+    txn
+      .execute(
+        "delete from damaged_blob_v1 where hash = ?",
+        params![&hash[..]],
+      )
+      .unwrap();
+    // The new content isn't what the parity of its group was computed from. If the rest of the
+    // group is intact, it's regrouped by the next `update_parity`.
+    let group_id: Option<i64> = txn
+      .query_row(
+        "select group_id from parity_member_v1 where hash = ?",
+        params![&hash[..]],
+        |r| r.get(0),
+      )
+      .optional()
+      .unwrap();
+    if let Some(group_id) = group_id {
+      dissolve_intact_parity_group(&txn, group_id, Some(&hash[..]));
+    }
+    txn.commit().unwrap();
+    Ok(true)
+  }
+
+  /// Parity settings of the repository as `(data_shards, parity_shards)`, if parity is enabled.
+  pub fn parity_settings(&self) -> Option<(u32, u32)> {
+    parity_settings(&self.db.lock())
+  }
+
+  /// Enables parity with `Some((data_shards, parity_shards))`, or disables it and deletes all
+  /// parity with `None`. Existing groups keep the settings they were made with.
+  pub fn set_parity_settings(&self, settings: Option<(u32, u32)>) {
+    let mut db = self.db.lock();
+    let txn = db.transaction().unwrap();
+    match settings {
+      Some((data_shards, parity_shards)) => {
+        txn
+          .execute(
+            "replace into bsync_config (k, v) values('parity', ?)",
+            params![format!("{},{}", data_shards, parity_shards)],
+          )
+          .unwrap();
+      }
+      None => {
+        txn
+          .execute_batch(
+            r#"
+            delete from bsync_config where k = 'parity';
+            delete from parity_shard_v1;
+            delete from parity_member_v1;
+            delete from parity_group_v1;
+            "#,
+          )
+          .unwrap();
+      }
+    }
+    txn.commit().unwrap();
+  }
+
+  /// Puts the blobs that aren't covered by parity yet into new groups, one transaction per group.
+  /// Groups that aren't full are dissolved first, so that their blobs are grouped with the new
+  /// ones. Returns the number of blobs put into groups.
+  pub fn update_parity(&self) -> Result<u64> {
+    let (data_shards, parity_shards) = match self.parity_settings() {
+      Some(x) => x,
+      None => return Ok(0),
+    };
+
+    let partial: Vec<i64> = self
+      .db
+      .lock()
+      .prepare_cached(
+        r#"
+        select id from parity_group_v1 g
+          where (select count(*) from parity_member_v1 m where m.group_id = g.id) < g.data_shards
+        "#,
+      )
+      .unwrap()
+      .query_map(params![], |r| r.get(0))
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap();
+    for group_id in partial {
+      dissolve_intact_parity_group(&self.db.lock(), group_id, None);
+    }
+
+    let mut cursor = 0i64;
+    let mut grouped = 0;
+    loop {
+      let mut db = self.db.lock();
+      let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+      let rows: Vec<(i64, Vec<u8>, Vec<u8>, bool)> = txn
+        .prepare_cached(
+          r#"
+          select rowid, hash, content, compressed from cas_v1
+            where rowid > ?
+            and not exists (select * from parity_member_v1 m where m.hash = cas_v1.hash)
+            order by rowid asc limit ?
+          "#,
+        )
+        .unwrap()
+        .query_map(params![cursor, data_shards], |r| {
+          Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })
+        .unwrap()
+        .collect::<Result<_, rusqlite::Error>>()
+        .unwrap();
+      cursor = match rows.last() {
+        Some(x) => x.0,
+        None => break,
+      };
+      // Parity computed from a damaged blob can't rebuild it.
+      let rows: Vec<_> = rows
+        .into_iter()
+        .filter(|(_, hash, content, compressed)| decode_blob(hash, content, *compressed).is_ok())
+        .collect();
+      if rows.is_empty() {
+        continue;
+      }
+
+      let data: Vec<&[u8]> = rows.iter().map(|x| &x.2[..]).collect();
+      let parity = parity::encode(data_shards as usize, parity_shards as usize, &data)?;
+      txn
+        .execute(
+          "insert into parity_group_v1 (data_shards, parity_shards, shard_size) values(?, ?, ?)",
+          params![data_shards, parity_shards, parity[0].len() as u64],
+        )
+        .unwrap();
+      let group_id = txn.last_insert_rowid();
+      for (i, (_, hash, content, compressed)) in rows.iter().enumerate() {
+        txn
+          .prepare_cached(
+            r#"
+            insert into parity_member_v1 (hash, group_id, idx, length, compressed, content_hash)
+              values(?, ?, ?, ?, ?, ?)
+            "#,
+          )
+          .unwrap()
+          .execute(params![
+            hash,
+            group_id,
+            i as u32,
+            content.len() as u64,
+            compressed,
+            &blake3::hash(content).as_bytes()[..]
+          ])
+          .unwrap();
+      }
+      for (i, content) in parity.iter().enumerate() {
+        txn
+          .prepare_cached(
+            "insert into parity_shard_v1 (group_id, idx, hash, content) values(?, ?, ?, ?)",
+          )
+          .unwrap()
+          .execute(params![
+            group_id,
+            data_shards + i as u32,
+            &blake3::hash(content).as_bytes()[..],
+            content
+          ])
+          .unwrap();
+      }
+      txn.commit().unwrap();
+      grouped += rows.len() as u64;
+    }
+    Ok(grouped)
+  }
+
+  /// Rebuilds a missing or damaged blob from its parity group, stores it and returns its
+  /// decompressed content. Returns `None` if it isn't covered by parity, or too much of its group
+  /// is damaged.
+  pub fn heal_blob(&self, hash: &[u8]) -> Option<Vec<u8>> {
+    let db = self.db.lock();
+    let (group_id, data_shards, parity_shards, shard_size): (i64, usize, usize, usize) = db
+      .query_row(
+        r#"
+        select g.id, g.data_shards, g.parity_shards, g.shard_size from parity_member_v1 m
+          join parity_group_v1 g on g.id = m.group_id
+          where m.hash = ?
+        "#,
+        params![hash],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+      )
+      .optional()
+      .unwrap()?;
+
+    // Data shards past the blobs of the group are zeros.
+    let mut shards: Vec<Option<Vec<u8>>> = (0..data_shards + parity_shards)
+      .map(|i| {
+        if i < data_shards {
+          Some(vec![0u8; shard_size])
+        } else {
+          None
+        }
+      })
+      .collect();
+    let members = parity_group_blobs(&db, group_id);
+    let mut target = None;
+    for member in &members {
+      if member.hash == hash {
+        target = Some(member);
+        shards[member.idx] = None;
+      } else {
+        shards[member.idx] = member.intact_content().map(|x| {
+          let mut x = x.to_vec();
+          x.resize(shard_size, 0);
+          x
+        });
+      }
+    }
+    let target = target?;
+    let mut stmt = db
+      .prepare_cached("select idx, hash, content from parity_shard_v1 where group_id = ?")
+      .unwrap();
+    let parity = stmt
+      .query_map(params![group_id], |r| {
+        Ok((
+          r.get::<_, usize>(0)?,
+          r.get::<_, Vec<u8>>(1)?,
+          r.get::<_, Vec<u8>>(2)?,
+        ))
+      })
+      .unwrap()
+      .collect::<Result<Vec<_>, rusqlite::Error>>()
+      .unwrap();
+    drop(stmt);
+    for (i, shard_hash, content) in parity {
+      if i < shards.len() && blake3::hash(&content).as_bytes()[..] == shard_hash[..] {
+        shards[i] = Some(content);
+      }
+    }
+
+    if let Err(e) = parity::reconstruct(data_shards, parity_shards, &mut shards) {
+      log::error!(
+        "Cannot rebuild blob {} from parity: {}",
+        hex::encode(hash),
+        e
+      );
+      return None;
+    }
+    let mut content = shards[target.idx].take()?;
+    content.truncate(target.length);
+    if blake3::hash(&content).as_bytes()[..] != target.content_hash[..] {
+      log::error!(
+        "Blob {} rebuilt from parity doesn't match its hash.",
+        hex::encode(hash)
+      );
+      return None;
+    }
+    let block = decode_blob(hash, &content, target.compressed).ok()?;
+    db.execute(
+      r#"
+      insert into cas_v1 (hash, content, compressed) values(?, ?, ?)
+        on conflict (hash) do update set content = excluded.content, compressed = excluded.compressed
+      "#,
+      params![hash, content, target.compressed],
+    )
+    .unwrap();
+    db.execute("delete from damaged_blob_v1 where hash = ?", params![hash])
+      .unwrap();
+    log::warn!("Rebuilt blob {} from parity.", hex::encode(hash));
+    Some(block)
+  }
+
+  /// Checks all parity shards. Groups with damaged parity are dissolved if their blobs are intact,
+  /// and regrouped by the next `update_parity`. Returns the number of damaged shards.
+  pub fn verify_parity_shards(&self) -> u64 {
+    let mut cursor = 0i64;
+    let mut damaged = 0;
+    loop {
+      let rows: Vec<(i64, i64, Vec<u8>, Vec<u8>)> = self
+        .db
+        .lock()
+        .prepare_cached(
+          r#"
+          select rowid, group_id, hash, content from parity_shard_v1
+            where rowid > ? order by rowid asc limit ?
+          "#,
+        )
+        .unwrap()
+        .query_map(params![cursor, GC_BATCH_BLOBS], |r| {
+          Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })
+        .unwrap()
+        .collect::<Result<_, rusqlite::Error>>()
+        .unwrap();
+      cursor = match rows.last() {
+        Some(x) => x.0,
+        None => break,
+      };
+      for (_, group_id, hash, content) in rows {
+        if blake3::hash(&content).as_bytes()[..] != hash[..] {
+          log::error!("A parity shard of group {} is damaged.", group_id);
+          damaged += 1;
+          dissolve_intact_parity_group(&self.db.lock(), group_id, None);
+        }
+      }
+    }
+    damaged
+  }
+
+  pub fn parity_stats(&self) -> ParityStats {
+    let db = self.db.lock();
+    let (data_shards, parity_shards) = parity_settings(&db).unwrap_or_default();
+    let (groups, protected_blobs, unprotected_blobs, parity_bytes) = db
+      .query_row(
+        r#"
+        select
+          (select count(*) from parity_group_v1),
+          (select count(*) from parity_member_v1),
+          (select count(*) from cas_v1
+            where not exists (select * from parity_member_v1 m where m.hash = cas_v1.hash)),
+          (select coalesce(sum(length(content)), 0) from parity_shard_v1)
+        "#,
+        params![],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+      )
+      .unwrap();
+    ParityStats {
+      data_shards,
+      parity_shards,
+      groups,
+      protected_blobs,
+      unprotected_blobs,
+      parity_bytes,
+    }
+  }
+
+  pub fn list_damaged_blobs(&self) -> Vec<DamagedBlobInfo> {
+    self
+      .db
+      .lock()
+      .prepare_cached(
+        "select hash, reason, detected_at from damaged_blob_v1 order by detected_at asc",
+      )
+      .unwrap()
+      .query_map(params![], |r| {
+        Ok(DamagedBlobInfo {
+          hash: hex::encode(r.get::<_, Vec<u8>>(0)?),
+          reason: r.get(1)?,
+          detected_at: r.get(2)?,
+        })
+      })
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
+  }
+
+  /// Runs SQLite's `integrity_check`. Returns the problems found, if any.
+  pub fn integrity_check(&self) -> Vec<String> {
+    let out: Vec<String> = self
+      .db
+      .lock()
+      .prepare("pragma integrity_check")
+      .unwrap()
+      .query_map(params![], |r| r.get(0))
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap();
+    if out.len() == 1 && out[0] == "ok" {
+      vec![]
+    } else {
+      out
+    }
+  }
+
+  /// Rowid of the last blob checked by the previous `bsync verify --scrub-fraction` run.
+  pub fn scrub_cursor(&self) -> i64 {
+    let v: Option<String> = self
+      .db
+      .lock()
+      .query_row(
+        "select v from bsync_config where k = 'scrub_cursor'",
+        params![],
+        |r| r.get(0),
+      )
+      .optional()
+      .unwrap();
+    v.and_then(|x| x.parse().ok()).unwrap_or(0)
+  }
+
+  pub fn set_scrub_cursor(&self, rowid: i64) {
+    self
+      .db
+      .lock()
+      .execute(
+        "replace into bsync_config (k, v) values('scrub_cursor', ?)",
+        params![rowid.to_string()],
+      )
+      .unwrap();
+  }
+
+  /// Deletes the blobs that no redo entry references, a batch at a time.
+  pub fn cas_gc(&self) {
+    let mut cursor = 0i64;
+    let mut deleted = 0;
+    let mut waiting = false;
+    loop {
+      let mut db = self.db.lock();
+      let txn = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap();
+      // A running pull may reuse any blob it has seen in the CAS, so none can be deleted until it
+      // finishes. A pull that starts later sees the blobs deleted so far.
+      let pull = list_leases(&txn, unix_secs())
+        .into_iter()
+        .find(|x| x.kind == LEASE_PULL);
+      if let Some(pull) = pull {
+        drop(txn);
+        drop(db);
+        if !waiting {
+          log::info!(
+            "Waiting for the pull of {} to finish before deleting blobs.",
+            pull.describe()
+          );
+          waiting = true;
+        }
+        std::thread::sleep(GC_PULL_WAIT);
+        continue;
+      }
+      waiting = false;
+      let batch_end: Option<i64> = txn
+        .prepare_cached(
+          "select max(rowid) from (select rowid from cas_v1 where rowid > ? order by rowid limit ?)",
+        )
+        .unwrap()
+        .query_row(params![cursor, GC_BATCH_BLOBS], |r| r.get(0))
+        .unwrap();
+      let batch_end = match batch_end {
+        Some(x) => x,
+        None => break,
+      };
+      // The groups of deleted blobs are dissolved, so that the rest is regrouped. A group that
+      // can still rebuild a damaged blob is kept, with the member rows of its deleted blobs, until
+      // the damaged blob is repaired.
+      let groups: Vec<i64> = txn
+        .prepare_cached(
+          r#"
+          select distinct m.group_id from cas_v1 c join parity_member_v1 m on m.hash = c.hash
+            where c.rowid > ? and c.rowid <= ?
+            and not exists (select * from redo_v1 where redo_v1.hash = c.hash)
+          "#,
+        )
+        .unwrap()
+        .query_map(params![cursor, batch_end], |r| r.get(0))
+        .unwrap()
+        .collect::<Result<_, rusqlite::Error>>()
+        .unwrap();
+      for group_id in groups {
+        dissolve_intact_parity_group(&txn, group_id, None);
+      }
+      deleted += txn
+        .prepare_cached(
+          r#"
+          delete from cas_v1 where rowid > ? and rowid <= ?
+            and not exists (select * from redo_v1 where redo_v1.hash = cas_v1.hash)
+          "#,
+        )
+        .unwrap()
+        .execute(params![cursor, batch_end])
+        .unwrap();
+      txn.commit().unwrap();
+      cursor = batch_end;
+    }
+    // Damaged blobs that no version needs anymore don't need a repair either.
+    self
+      .db
+      .lock()
+      .execute(
+        r#"
+        delete from damaged_blob_v1
+          where not exists (select * from redo_v1 where redo_v1.hash = damaged_blob_v1.hash)
+        "#,
+        params![],
+      )
+      .unwrap();
+    log::info!("Deleted {} unreferenced blobs.", deleted);
+    if let Err(e) = self.update_parity() {
+      log::error!("Cannot update parity: {:?}", e);
+    }
+  }
+
+  /// Returns free pages to the file system.
+  ///
+  /// Databases created before incremental auto-vacuum are converted with a full `vacuum` first,
+  /// which rewrites the whole file once.
+  pub fn vacuum(&self) {
+    let auto_vacuum: u32 = self
+      .db
+      .lock()
+      .query_row("pragma auto_vacuum", params![], |r| r.get(0))
+      .unwrap();
+    // 2 is `incremental`.
+    if auto_vacuum != 2 {
+      log::warn!(
+        "Converting the database to incremental auto-vacuum. This rewrites the whole file."
+      );
+      self
+        .db
+        .lock()
+        .execute_batch("pragma auto_vacuum = incremental; vacuum;")
+        .unwrap();
+      return;
+    }
+    // Pages freed by concurrent squashes while this runs are left for the next vacuum, so that it
+    // ends.
+    let mut remaining: u64 = self
+      .db
+      .lock()
+      .query_row("pragma freelist_count", params![], |r| r.get(0))
+      .unwrap();
+    let mut freed = 0;
+    while remaining != 0 {
+      let db = self.db.lock();
+      let mut stmt = db
+        .prepare(&format!(
+          "pragma incremental_vacuum({})",
+          VACUUM_STEP_PAGES.min(remaining)
+        ))
+        .unwrap();
+      // Each step of the statement frees one page.
+      let mut rows = stmt.query(params![]).unwrap();
+      let mut step_freed = 0;
+      while rows.next().unwrap().is_some() {
+        step_freed += 1;
+      }
+      if step_freed == 0 {
+        break;
+      }
+      freed += step_freed;
+      remaining = remaining.saturating_sub(step_freed);
+    }
+    log::info!("Freed {} pages.", freed);
+  }
+}
+
+/// What a squash would delete.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct SquashPreview {
+  pub redo_rows: u64,
+  pub cas_blobs: u64,
+  /// Stored (compressed) size of the deleted blobs.
+  pub cas_bytes: u64,
+}
+
+/// A database file that may be damaged, opened read-only to copy out the rows that can still be
+/// read.
+pub struct SalvageSource {
+  db: Connection,
+}
+
+pub struct SalvagedBlob {
+  pub hash: Vec<u8>,
+  pub content: Vec<u8>,
+  pub compressed: bool,
+}
+
+impl SalvagedBlob {
+  /// Checks that the blob decompresses and matches its hash.
+  pub fn verify(&self) -> Result<(), String> {
+    decode_blob(&self.hash, &self.content, self.compressed).map(|_| ())
+  }
+}
+
+pub struct SalvagedAttachment {
+  pub lsn: u64,
+  pub name: String,
+  pub size: u64,
+  pub content: Vec<u8>,
+}
+
+impl SalvagedAttachment {
+  /// Decompresses the content and checks its size.
+  pub fn decode(&self) -> Result<Vec<u8>, String> {
+    let content = zstd::decode_all(&self.content[..]).map_err(|e| e.to_string())?;
+    if content.len() as u64 != self.size {
+      return Err(format!(
+        "expected {} bytes, got {}",
+        self.size,
+        content.len()
+      ));
+    }
+    Ok(content)
+  }
+}
+
+impl SalvageSource {
+  pub fn open(path: &Path) -> Result<Self> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(Self { db })
+  }
+
+  /// Reads `cas_v1`. Returns the rowid ranges that couldn't be read.
+  pub fn blobs(&self, sink: impl FnMut(Vec<SalvagedBlob>)) -> Vec<(i64, i64)> {
+    self.scan(
+      "cas_v1",
+      "hash, content, compressed",
+      |r| {
+        Ok(SalvagedBlob {
+          hash: r.get(1)?,
+          content: r.get(2)?,
+          compressed: r.get(3)?,
+        })
+      },
+      sink,
+    )
+  }
+
+  /// Reads `redo_v1` as `(lsn, block_id, hash)`. Returns the LSN ranges that couldn't be read.
+  pub fn redo(&self, sink: impl FnMut(Vec<(u64, u64, Vec<u8>)>)) -> Vec<(i64, i64)> {
+    self.scan(
+      "redo_v1",
+      "block_id, hash",
+      |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+      sink,
+    )
+  }
+
+  /// Reads `consistent_point_v1`. Returns the LSN ranges that couldn't be read. Image checksums,
+  /// fuzzy flags and suspect marks are only copied if the database has the columns; those created
+  /// by older versions of bsync don't.
+  pub fn consistent_points(&self) -> (Vec<ConsistentPoint>, Vec<(i64, i64)>) {
+    let optional = |column: &str, default: &str| {
+      if self.has_column("consistent_point_v1", column) {
+        column.to_string()
+      } else {
+        default.to_string()
+      }
+    };
+    let columns = format!(
+      "size, created_at, {}, {}, {}",
+      optional("sha256", "null"),
+      optional("fuzzy", "0"),
+      optional("suspect", "null")
+    );
+    let mut out = vec![];
+    let unreadable = self.scan(
+      "consistent_point_v1",
+      &columns,
+      |r| {
+        Ok(ConsistentPoint {
+          lsn: r.get(0)?,
+          size: r.get(1)?,
+          created_at: r.get(2)?,
+          sha256: r
+            .get::<_, Option<Vec<u8>>>(3)?
+            .and_then(|x| x.try_into().ok()),
+          fuzzy: r.get(4)?,
+          suspect: r.get(5)?,
+        })
+      },
+      |x| out.extend(x),
+    );
+    (out, unreadable)
+  }
+
+  /// Reads `merkle_root_v1` as `(lsn, root)`. Returns the LSN ranges that couldn't be read. The
+  /// nodes aren't read, since they can be rebuilt from the redo log.
+  pub fn merkle_roots(&self, sink: impl FnMut(Vec<(u64, MerkleRoot)>)) -> Vec<(i64, i64)> {
+    if !self.has_table("merkle_root_v1") {
+      return vec![];
+    }
+    self.scan(
+      "merkle_root_v1",
+      "root, public_key, signature",
+      |r| {
+        Ok((
+          r.get(0)?,
+          MerkleRoot {
+            root: r.get::<_, Vec<u8>>(1)?.try_into().unwrap_or_default(),
+            public_key: r.get(2)?,
+            signature: r.get(3)?,
+          },
+        ))
+      },
+      sink,
+    )
+  }
+
+  /// Reads `source_info_v1` as `(lsn, info)`. Returns the LSN ranges that couldn't be read.
+  pub fn source_info(&self, sink: impl FnMut(Vec<(u64, SourceInfo)>)) -> Vec<(i64, i64)> {
+    if !self.has_table("source_info_v1") {
+      return vec![];
+    }
+    self.scan(
+      "source_info_v1",
+      r#"
+      size, logical_sector_size, physical_sector_size, wwid, serial, partition, uuid, dm_name
+      "#,
+      |r| {
+        Ok((
+          r.get(0)?,
+          SourceInfo {
+            size: r.get(1)?,
+            logical_sector_size: r.get(2)?,
+            physical_sector_size: r.get(3)?,
+            wwid: r.get(4)?,
+            serial: r.get(5)?,
+            partition: r.get(6)?,
+            uuid: r.get(7)?,
+            dm_name: r.get(8)?,
+          },
+        ))
+      },
+      sink,
+    )
+  }
+
+  /// Reads `attachment_v1`. Returns the rowid ranges that couldn't be read.
+  pub fn attachments(&self, sink: impl FnMut(Vec<SalvagedAttachment>)) -> Vec<(i64, i64)> {
+    if !self.has_table("attachment_v1") {
+      return vec![];
+    }
+    self.scan(
+      "attachment_v1",
+      "lsn, name, size, content",
+      |r| {
+        Ok(SalvagedAttachment {
+          lsn: r.get(1)?,
+          name: r.get(2)?,
+          size: r.get(3)?,
+          content: r.get(4)?,
+        })
+      },
+      sink,
+    )
+  }
+
+  /// Reads `pull_meta_v1`. Returns the LSN ranges that couldn't be read.
+  pub fn pull_meta(&self, sink: impl FnMut(Vec<PullMeta>)) -> Vec<(i64, i64)> {
+    if !self.has_table("pull_meta_v1") {
+      return vec![];
+    }
+    self.scan(
+      "pull_meta_v1",
+      r#"
+      started_at, finished_at, remote_host, image,
+      downloaded_bytes, reused_bytes, changed_blocks, total_blocks, bsync_version,
+      pre_pull_output, post_pull_output, local_pre_pull_output, local_post_pull_output
+      "#,
+      |r| {
+        Ok(PullMeta {
+          lsn: r.get(0)?,
+          started_at: r.get(1)?,
+          finished_at: r.get(2)?,
+          remote_host: r.get(3)?,
+          image: r.get(4)?,
+          downloaded_bytes: r.get(5)?,
+          reused_bytes: r.get(6)?,
+          changed_blocks: r.get(7)?,
+          total_blocks: r.get(8)?,
+          bsync_version: r.get(9)?,
+          pre_pull_output: r.get(10)?,
+          post_pull_output: r.get(11)?,
+          local_pre_pull_output: r.get(12)?,
+          local_post_pull_output: r.get(13)?,
+        })
+      },
+      sink,
+    )
+  }
+
+  /// Reads `protection_v1`. Returns the LSN ranges that couldn't be read.
+  pub fn protection(&self, sink: impl FnMut(Vec<Protection>)) -> Vec<(i64, i64)> {
+    if !self.has_table("protection_v1") {
+      return vec![];
+    }
+    self.scan(
+      "protection_v1",
+      "pin_reason, locked_until, lock_reason",
+      |r| {
+        Ok(Protection {
+          lsn: r.get(0)?,
+          pin_reason: r.get(1)?,
+          locked_until: r.get(2)?,
+          lock_reason: r.get(3)?,
+        })
+      },
+      sink,
+    )
+  }
+
+  /// Reads the `min_version_age_days` setting, or `None` if it isn't set.
+  pub fn min_version_age_days(&self) -> rusqlite::Result<Option<u64>> {
+    let v: Option<String> = self
+      .db
+      .query_row(
+        "select v from bsync_config where k = 'min_version_age_days'",
+        params![],
+        |r| r.get(0),
+      )
+      .optional()?;
+    Ok(v.and_then(|x| x.parse().ok()))
+  }
+
+  /// Whether the database has `table`. Databases created by older versions of bsync lack the newer
+  /// ones.
+  fn has_table(&self, table: &str) -> bool {
+    match self.db.query_row(
+      "select count(*) from sqlite_master where type = 'table' and name = ?",
+      params![table],
+      |r| r.get::<_, i64>(0),
+    ) {
+      Ok(x) => x != 0,
+      Err(e) => {
+        log::warn!("Cannot look up table {}: {}", table, e);
+        false
+      }
+    }
+  }
+
+  /// Whether `table` has `column`. Databases created by older versions of bsync lack the newer
+  /// ones.
+  fn has_column(&self, table: &str, column: &str) -> bool {
+    let columns = self
+      .db
+      .prepare(&format!("pragma table_info({})", table))
+      .and_then(|mut stmt| {
+        stmt
+          .query_map(params![], |r| r.get::<_, String>(1))?
+          .collect::<rusqlite::Result<Vec<_>>>()
+      });
+    match columns {
+      Ok(x) => x.iter().any(|x| x == column),
+      Err(e) => {
+        log::warn!("Cannot read the columns of {}: {}", table, e);
+        false
+      }
+    }
+  }
+
+  /// Reads the rows of `table` in rowid order and passes them to `sink` a batch at a time. `map`
+  /// gets the rowid as column 0, followed by `columns`.
+  ///
+  /// When a batch can't be read, each of its rowids is tried on its own, so that only the rows on
+  /// damaged pages are lost. Returns the rowid ranges that couldn't be read.
+  fn scan<T>(
+    &self,
+    table: &str,
+    columns: &str,
+    map: impl Fn(&rusqlite::Row) -> rusqlite::Result<T>,
+    mut sink: impl FnMut(Vec<T>),
+  ) -> Vec<(i64, i64)> {
+    let mut unreadable: Vec<(i64, i64)> = vec![];
+    let mut mark_unreadable = |rowid: i64| match unreadable.last_mut() {
+      Some(x) if x.1 + 1 == rowid => x.1 = rowid,
+      _ => unreadable.push((rowid, rowid)),
+    };
+    let max_rowid: Option<i64> = match self.db.query_row(
+      &format!("select max(rowid) from {}", table),
+      params![],
+      |r| r.get::<_, Option<i64>>(0),
+    ) {
+      Ok(None) => return vec![],
+      Ok(x) => x,
+      Err(e) => {
+        log::warn!("Cannot find the last row of {}: {}", table, e);
+        None
+      }
+    };
+    let batch_sql = format!(
+      "select rowid, {} from {} where rowid > ? order by rowid asc limit ?",
+      columns, table
+    );
+    let row_sql = format!("select rowid, {} from {} where rowid = ?", columns, table);
+
+    // Consistent points start at LSN 0.
+    let mut cursor = -1i64;
+    let mut empty_batches = 0;
+    loop {
+      let batch = self.db.prepare(&batch_sql).and_then(|mut stmt| {
+        stmt
+          .query_map(params![cursor, SALVAGE_BATCH_ROWS], |r| {
+            Ok((r.get::<_, i64>(0)?, map(r)?))
+          })?
+          .collect::<rusqlite::Result<Vec<_>>>()
+      });
+      match batch {
+        Ok(rows) if rows.is_empty() => break,
+        Ok(rows) => {
+          cursor = rows.last().unwrap().0;
+          sink(rows.into_iter().map(|x| x.1).collect());
+          empty_batches = 0;
+          continue;
+        }
+        Err(e) => log::warn!(
+          "Cannot read {} after rowid {}: {}. Reading row by row.",
+          table,
+          cursor,
+          e
+        ),
+      }
+
+      let end = cursor + SALVAGE_BATCH_ROWS;
+      let mut rows = vec![];
+      for rowid in cursor + 1..=end {
+        let row = self
+          .db
+          .query_row(&row_sql, params![rowid], |r| map(r))
+          .optional();
+        match row {
+          Ok(Some(x)) => rows.push(x),
+          Ok(None) => {}
+          Err(_) => mark_unreadable(rowid),
+        }
+      }
+      if rows.is_empty() {
+        empty_batches += 1;
+      } else {
+        empty_batches = 0;
+      }
+      sink(rows);
+      cursor = end;
+      match max_rowid {
+        Some(x) if cursor >= x => break,
+        None if empty_batches >= SALVAGE_MAX_EMPTY_BATCHES => {
+          // Anything after this might have existed.
+          log::warn!("Giving up on {} after rowid {}.", table, cursor);
+          mark_unreadable(cursor + 1);
+          unreadable.last_mut().unwrap().1 = i64::MAX;
+          break;
+        }
+        _ => {}
+      }
+    }
+    unreadable
+  }
+}
+
+pub struct Snapshot {
+  db: Database,
+  table_name: String,
+}
+
+impl Snapshot {
+  /// Reads a block, or `None` if it's all zeros. Fails if its blob is missing or doesn't match its
+  /// hash.
+  pub fn read_block(&self, block_id: u64) -> Result<Option<Vec<u8>>> {
+    let hash = match self.read_block_hash(block_id) {
+      Some(x) if x != *ZERO_BLOCK_HASH => x,
+      _ => return Ok(None),
+    };
+
+    let db = self.db.db.lock();
+    let mut stmt = db
+      .prepare_cached(
+        r#"
+        select content, compressed from cas_v1 where hash = ?
+      "#,
+      )
+      .unwrap();
+    let row: Option<(Vec<u8>, bool)> = stmt
+      .query_row(params![&hash[..]], |r| Ok((r.get(0)?, r.get(1)?)))
+      .optional()
+      .unwrap();
+    drop(stmt);
+    drop(db);
+    let err = match row {
+      Some((content, compressed)) => match decode_blob(&hash, &content, compressed) {
+        Ok(x) => return Ok(Some(x)),
+        Err(e) => e,
+      },
+      None => "missing from the CAS".to_string(),
+    };
+    match self.db.heal_blob(&hash) {
+      Some(x) => Ok(Some(x)),
+      None => Err(DamagedBlob(hex::encode(hash), err).into()),
+    }
+  }
+
+  /// Builds the Merkle tree of the snapshot from its block hashes. `size` is the image size.
+  pub fn merkle_tree(&self, size: u64) -> MerkleTree {
+    let db = self.db.db.lock();
+    let mut stmt = db
+      .prepare_cached(&format!(
+        "select block_id, hash from temp.{} order by block_id asc",
+        self.table_name
+      ))
+      .unwrap();
+    let mut builder = MerkleBuilder::new(size);
+    for row in stmt
+      .query_map(params![], |r| Ok((r.get(0)?, r.get::<_, Vec<u8>>(1)?)))
+      .unwrap()
+    {
+      let (block_id, hash) = row.unwrap();
+      builder.push_at(block_id, hash.try_into().unwrap());
+    }
+    builder.finish()
+  }
+
+  /// Returns the blocks whose blob is missing or was found damaged by `bsync verify`.
+  pub fn unreadable_blocks(&self) -> Vec<u64> {
+    let db = self.db.db.lock();
+    let mut stmt = db
+      .prepare_cached(&format!(
+        r#"
+        select block_id from temp.{} s where hash != ? and (
+          not exists (select * from cas_v1 where cas_v1.hash = s.hash)
+          or exists (select * from damaged_blob_v1 where damaged_blob_v1.hash = s.hash)
+        ) order by block_id asc
+        "#,
+        self.table_name
+      ))
+      .unwrap();
+    stmt
+      .query_map(params![&ZERO_BLOCK_HASH[..]], |r| r.get(0))
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
+  }
+
+  /// Lists up to `limit` blocks with non-zero content, starting from `start_block`.
+  pub fn list_nonzero_blocks(&self, start_block: u64, limit: usize) -> Vec<(u64, [u8; 32])> {
+    let db = self.db.db.lock();
+    let mut stmt = db
+      .prepare_cached(&format!(
+        "select block_id, hash from temp.{} where block_id >= ? and hash != ? order by block_id asc limit ?",
+        self.table_name
+      ))
+      .unwrap();
+    stmt
+      .query_map(
+        params![start_block, &ZERO_BLOCK_HASH[..], limit as i64],
+        |r| {
+          let hash: Vec<u8> = r.get(1)?;
+          Ok((r.get(0)?, hash.try_into().unwrap()))
+        },
+      )
+      .unwrap()
+      .collect::<Result<_, rusqlite::Error>>()
+      .unwrap()
+  }
+
+  pub fn read_block_hash(&self, block_id: u64) -> Option<[u8; 32]> {
+    let db = self.db.db.lock();
+    let mut stmt = db
+      .prepare_cached(&format!(
+        "select hash from temp.{} where block_id = ?",
+        self.table_name
+      ))
+      .unwrap();
+    let hash: Vec<u8> = stmt
+      .query_row(params![block_id], |r| r.get(0))
+      .optional()
+      .unwrap()?;
+    Some(hash.try_into().unwrap())
+  }
+}
+
+impl Drop for Snapshot {
+  fn drop(&mut self) {
+    self
+      .db
+      .db
+      .lock()
+      .execute_batch(&format!(
+        r#"
+      drop table temp.{};
+    "#,
+        &self.table_name
+      ))
+      .unwrap();
+  }
+}
+
+fn list_protection(db: &Connection) -> Vec<Protection> {
+  let mut stmt = db
+    .prepare_cached(
+      "select lsn, pin_reason, locked_until, lock_reason from protection_v1 order by lsn asc",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![], |r| {
+      Ok(Protection {
+        lsn: r.get(0)?,
+        pin_reason: r.get(1)?,
+        locked_until: r.get(2)?,
+        lock_reason: r.get(3)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<_, rusqlite::Error>>()
+    .unwrap()
+}
+
+fn min_version_age_days(db: &Connection) -> u64 {
+  let v: Option<String> = db
+    .query_row(
+      "select v from bsync_config where k = 'min_version_age_days'",
+      params![],
+      |r| r.get(0),
+    )
+    .optional()
+    .unwrap();
+  v.and_then(|x| x.parse().ok()).unwrap_or(0)
+}
+
+fn protected_versions(db: &Connection, ignore_min_age: bool) -> HashMap<u64, String> {
+  let now = unix_secs();
+  let mut out: HashMap<u64, String> = list_protection(db)
+    .into_iter()
+    .filter_map(|x| Some((x.lsn, x.describe(now)?)))
+    .collect();
+  let min_age_days = min_version_age_days(db);
+  if min_age_days != 0 && !ignore_min_age {
+    let mut stmt = db
+      .prepare_cached("select lsn from consistent_point_v1 where created_at > ?")
+      .unwrap();
+    let young = stmt
+      .query_map(params![now.saturating_sub(min_age_days * 86400)], |r| {
+        r.get::<_, u64>(0)
+      })
+      .unwrap();
+    for lsn in young {
+      out
+        .entry(lsn.unwrap())
+        .or_insert_with(|| format!("younger than {} days", min_age_days));
+    }
+  }
+  for lease in list_leases(db, now) {
+    if let (LEASE_READ, Some(lsn)) = (lease.kind.as_str(), lease.lsn) {
+      out
+        .entry(lsn)
+        .or_insert_with(|| format!("in use by {}", lease.describe()));
+    }
+  }
+  out
+}
+
+/// Leases that haven't expired and whose holder isn't known to be gone, by ID.
+fn list_leases(db: &Connection, now: u64) -> Vec<LeaseInfo> {
+  let hostname = hostname();
+  read_leases(db)
+    .into_iter()
+    .filter(|x| x.expires_at > now && !x.is_stale(&hostname))
+    .collect()
+}
+
+fn read_leases(db: &Connection) -> Vec<LeaseInfo> {
+  let mut stmt = db
+    .prepare_cached(
+      "select id, kind, lsn, holder, hostname, pid, acquired_at, expires_at from lease_v1 order by id asc",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![], |r| {
+      Ok(LeaseInfo {
+        id: r.get(0)?,
+        kind: r.get(1)?,
+        lsn: r.get(2)?,
+        holder: r.get(3)?,
+        hostname: r.get(4)?,
+        pid: r.get(5)?,
+        acquired_at: r.get(6)?,
+        expires_at: r.get(7)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<_, _>>()
+    .unwrap()
+}
+
+/// Fails if a consistent point strictly between `start_lsn` and `end_lsn` is protected or held by
+/// a read lease.
+fn check_removable(
+  db: &Connection,
+  start_lsn: u64,
+  end_lsn: u64,
+  ignore_min_age: bool,
+) -> Result<()> {
+  for lease in list_leases(db, unix_secs()) {
+    if let (LEASE_READ, Some(lsn)) = (lease.kind.as_str(), lease.lsn) {
+      if lsn > start_lsn && lsn < end_lsn {
+        return Err(VersionInUse(lsn, lease.describe()).into());
+      }
+    }
+  }
+  let protected = protected_versions(db, ignore_min_age);
+  let mut blocking: Vec<(&u64, &String)> = protected
+    .iter()
+    .filter(|(lsn, _)| **lsn > start_lsn && **lsn < end_lsn)
+    .collect();
+  blocking.sort();
+  match blocking.first() {
+    Some((lsn, why)) => Err(VersionProtected(**lsn, why.to_string()).into()),
+    None => Ok(()),
+  }
+}
+
+fn ensure_consistent_point(db: &Connection, lsn: u64) -> Result<()> {
+  let n: u32 = db
+    .query_row(
+      "select count(*) from consistent_point_v1 where lsn = ?",
+      params![lsn],
+      |r| r.get(0),
+    )
+    .unwrap();
+  if n == 0 {
+    return Err(NotConsistentPoint("lsn").into());
+  }
+  Ok(())
+}
+
+/// Decompresses a CAS blob and checks it against its key.
+fn decode_blob(hash: &[u8], content: &[u8], compressed: bool) -> Result<Vec<u8>, String> {
+  let content = if compressed {
+    zstd::decode_all(content).map_err(|e| format!("decompression failed: {}", e))?
+  } else {
+    content.to_vec()
+  };
+  if blake3::hash(&content).as_bytes()[..] != *hash {
+    return Err("hash mismatch".into());
+  }
+  Ok(content)
+}
+
+fn parity_settings(db: &Connection) -> Option<(u32, u32)> {
+  let v: String = db
+    .query_row(
+      "select v from bsync_config where k = 'parity'",
+      params![],
+      |r| r.get(0),
+    )
+    .optional()
+    .unwrap()?;
+  let (data_shards, parity_shards) = v.split_once(',')?;
+  Some((data_shards.parse().ok()?, parity_shards.parse().ok()?))
+}
+
+struct ParityMember {
+  hash: Vec<u8>,
+  idx: usize,
+  length: usize,
+  compressed: bool,
+  content_hash: Vec<u8>,
+  /// `None` if the blob is missing from the CAS.
+  content: Option<Vec<u8>>,
+}
+
+impl ParityMember {
+  /// The stored content, if it is still what the parity was computed from.
+  fn intact_content(&self) -> Option<&[u8]> {
+    self
+      .content
+      .as_deref()
+      .filter(|x| blake3::hash(x).as_bytes()[..] == self.content_hash[..])
+  }
+}
+
+fn parity_group_blobs(db: &Connection, group_id: i64) -> Vec<ParityMember> {
+  db.prepare_cached(
+    r#"
+    select m.hash, m.idx, m.length, m.compressed, m.content_hash, c.content from parity_member_v1 m
+      left join cas_v1 c on c.hash = m.hash
+      where m.group_id = ?
+    "#,
+  )
+  .unwrap()
+  .query_map(params![group_id], |r| {
+    Ok(ParityMember {
+      hash: r.get(0)?,
+      idx: r.get(1)?,
+      length: r.get(2)?,
+      compressed: r.get(3)?,
+      content_hash: r.get(4)?,
+      content: r.get(5)?,
+    })
+  })
+  .unwrap()
+  .collect::<Result<_, rusqlite::Error>>()
+  .unwrap()
+}
+
+/// Records the Merkle tree of the consistent point at `lsn`, unless it already has one.
+fn insert_merkle_tree(
+  db: &Connection,
+  lsn: u64,
+  tree: &MerkleTree,
+  signature: Option<(Vec<u8>, Vec<u8>)>,
+) {
+  let (public_key, signature) = signature.unzip();
+  let inserted = db
+    .execute(
+      "insert or ignore into merkle_root_v1 (lsn, root, public_key, signature) values(?, ?, ?, ?)",
+      params![lsn, &tree.root()[..], public_key, signature],
+    )
+    .unwrap();
+  if inserted == 0 {
+    return;
+  }
+  let mut stmt = db
+    .prepare_cached("insert into merkle_node_v1 (lsn, level, idx, hash) values(?, ?, ?, ?)")
+    .unwrap();
+  for (i, nodes) in tree.levels.iter().enumerate() {
+    for (idx, hash) in nodes.iter().enumerate() {
+      stmt
+        .execute(params![
+          lsn,
+          merkle::STORED_LEVEL + i as u32,
+          idx as u64,
+          &hash[..]
+        ])
+        .unwrap();
+    }
+  }
+}
+
+fn dissolve_parity_group(db: &Connection, group_id: i64) {
+  for table in ["parity_shard_v1", "parity_member_v1"] {
+    db.execute(
+      &format!("delete from {} where group_id = ?", table),
+      params![group_id],
+    )
+    .unwrap();
+  }
+  db.execute(
+    "delete from parity_group_v1 where id = ?",
+    params![group_id],
+  )
+  .unwrap();
+}
+
+/// Dissolves a group unless one of its blobs, other than `except`, is damaged or missing and still
+/// needs it. Blobs that no version references don't need it.
+fn dissolve_intact_parity_group(db: &Connection, group_id: i64, except: Option<&[u8]>) {
+  let mut referenced = db
+    .prepare_cached("select exists (select * from redo_v1 where hash = ?)")
+    .unwrap();
+  let needed = parity_group_blobs(db, group_id)
+    .iter()
+    .filter(|x| Some(&x.hash[..]) != except && x.intact_content().is_none())
+    .any(|x| {
+      referenced
+        .query_row(params![&x.hash[..]], |r| r.get::<_, bool>(0))
+        .unwrap()
+    });
+  if !needed {
+    dissolve_parity_group(db, group_id);
+  }
+}
+
+fn record_damaged_blobs(db: &Connection, damaged: &[DamagedBlobInfo]) {
+  let mut stmt = db
+    .prepare_cached(
+      r#"
+      insert into damaged_blob_v1 (hash, reason, detected_at) values(?, ?, ?)
+        on conflict (hash) do update set reason = excluded.reason
+      "#,
+    )
+    .unwrap();
+  for x in damaged {
+    stmt
+      .execute(params![
+        hex::decode(&x.hash).unwrap(),
+        x.reason,
+        x.detected_at
+      ])
+      .unwrap();
+  }
+}
+
+/// Number of blocks in an image of `size` bytes.
+pub fn block_count(size: u64) -> u64 {
+  size.div_ceil(LOG_BLOCK_SIZE as u64)
 }
 
 fn run_migration(db: &mut Connection) -> Result<()> {
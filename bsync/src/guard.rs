@@ -0,0 +1,134 @@
+use thiserror::Error;
+
+use crate::{
+  blob::ZERO_BLOCK_HASH,
+  config::{GuardAction, GuardConfig, LOG_BLOCK_SIZE},
+  db::{Database, Snapshot},
+};
+
+/// Minimum number of previous versions for the change fraction check.
+const MIN_HISTORY: usize = 3;
+
+/// A block compressed well if its blob is at most this fraction of the block size.
+const COMPRESSIBLE_RATIO: f64 = 0.5;
+
+/// A block is incompressible if its blob is at least this fraction of the block size.
+const INCOMPRESSIBLE_RATIO: f64 = 0.95;
+
+#[derive(Error, Debug)]
+#[error("pull aborted by the guard: {0}")]
+pub struct GuardTriggered(pub String);
+
+/// Watches a pull for ransomware-like change patterns. See `GuardConfig`.
+pub struct Guard<'a> {
+  config: &'a GuardConfig,
+  total_blocks: u64,
+  /// Average fraction of changed blocks of the previous versions, if there are enough of them.
+  average_change: Option<f64>,
+  /// Fetched blocks whose previous version compressed well.
+  compressible_before: u64,
+  /// Of those, the blocks that are now incompressible.
+  incompressible_now: u64,
+}
+
+impl<'a> Guard<'a> {
+  pub fn new(config: &'a GuardConfig, db: &Database, total_blocks: u64) -> Self {
+    // Suspect versions would raise the average.
+    let suspect: Vec<u64> = db
+      .list_consistent_point()
+      .into_iter()
+      .filter(|x| x.suspect.is_some())
+      .map(|x| x.lsn)
+      .collect();
+    let history: Vec<f64> = db
+      .list_pull_meta()
+      .into_iter()
+      .rev()
+      .filter(|x| x.total_blocks != 0 && !suspect.contains(&x.lsn))
+      .take(config.history)
+      .map(|x| x.changed_blocks as f64 / x.total_blocks as f64)
+      .collect();
+    let average_change = if history.len() >= MIN_HISTORY {
+      Some(history.iter().sum::<f64>() / history.len() as f64)
+    } else {
+      log::info!(
+        "Guard: {} previous versions are not enough to check the fraction of changed blocks.",
+        history.len()
+      );
+      None
+    };
+    Self {
+      config,
+      total_blocks,
+      average_change,
+      compressible_before: 0,
+      incompressible_now: 0,
+    }
+  }
+
+  /// Whether a flagged pull fails instead of creating a suspect version.
+  pub fn aborts(&self) -> bool {
+    self.config.action == GuardAction::Abort
+  }
+
+  /// Checks the number of blocks changed so far.
+  pub fn check_changes(&self, changed_blocks: u64) -> Option<String> {
+    let average = self.average_change?;
+    if self.total_blocks == 0 {
+      return None;
+    }
+    let fraction = changed_blocks as f64 / self.total_blocks as f64;
+    let limit = (average * self.config.change_factor).max(self.config.min_change_fraction);
+    if fraction > limit {
+      Some(format!(
+        "{:.1}% of the blocks changed, against an average of {:.1}%",
+        fraction * 100.0,
+        average * 100.0
+      ))
+    } else {
+      None
+    }
+  }
+
+  /// Records fetched blocks as `(offset, hash)` pairs, once their blobs are written. `base` is the
+  /// previous version.
+  pub fn record_fetched(&mut self, db: &Database, base: &Snapshot, blocks: &[(usize, [u8; 32])]) {
+    let size_ratio = |hash: &[u8; 32]| {
+      db.blob_stored_size(hash)
+        .map(|x| x as f64 / LOG_BLOCK_SIZE as f64)
+    };
+    for (offset, hash) in blocks {
+      let prev = match base.read_block_hash((offset / LOG_BLOCK_SIZE) as u64) {
+        Some(x) if x != *ZERO_BLOCK_HASH => x,
+        // New data in empty space is often compressed media - only rewrites count.
+        _ => continue,
+      };
+      if !matches!(size_ratio(&prev), Some(x) if x <= COMPRESSIBLE_RATIO) {
+        continue;
+      }
+      self.compressible_before += 1;
+      if matches!(size_ratio(hash), Some(x) if x >= INCOMPRESSIBLE_RATIO) {
+        self.incompressible_now += 1;
+      }
+    }
+  }
+
+  /// Returns why the pull should be flagged, if it should.
+  pub fn verdict(&self, changed_blocks: u64) -> Option<String> {
+    let mut reasons: Vec<String> = self.check_changes(changed_blocks).into_iter().collect();
+    if self.compressible_before >= self.config.min_compressible_blocks
+      && self.incompressible_now as f64
+        > self.compressible_before as f64 * self.config.max_incompressible_fraction
+    {
+      reasons.push(format!(
+        "{} of {} rewritten blocks that compressed well are now incompressible",
+        self.incompressible_now, self.compressible_before
+      ));
+    }
+    if reasons.is_empty() {
+      None
+    } else {
+      Some(reasons.join("; "))
+    }
+  }
+}
@@ -0,0 +1,145 @@
+use std::{
+  borrow::Cow,
+  io::Read,
+  process::{Command, ExitStatus, Stdio},
+  time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use shell_escape::unix::escape;
+use thiserror::Error;
+
+use crate::{output::say, remote::Remote};
+
+/// Prefix of the line a `pre_pull` script prints to override the image path.
+const IMAGE_OVERRIDE_PREFIX: &str = "BSYNC_IMAGE=";
+
+#[derive(Error, Debug)]
+#[error("local {0} script failed: {1}")]
+pub struct LocalHookFailed(String, ExitStatus);
+
+#[derive(Error, Debug)]
+#[error("local {0} script timed out after {1:?}")]
+pub struct LocalHookTimeout(String, Duration);
+
+#[derive(Error, Debug)]
+#[error("remote {0} script failed")]
+pub struct RemoteHookFailed(String);
+
+#[derive(Copy, Clone)]
+pub enum HookStatus {
+  Running,
+  Success,
+  Failure,
+}
+
+impl HookStatus {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::Running => "running",
+      Self::Success => "success",
+      Self::Failure => "failure",
+    }
+  }
+}
+
+/// Environment variables passed to hook scripts.
+pub struct HookEnv<'a> {
+  pub status: HookStatus,
+  pub image: &'a str,
+  pub lsn: Option<u64>,
+  pub bytes: u64,
+  pub error: Option<String>,
+}
+
+impl<'a> HookEnv<'a> {
+  fn vars(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("BSYNC_STATUS", self.status.as_str().to_string()),
+      ("BSYNC_IMAGE", self.image.to_string()),
+      (
+        "BSYNC_LSN",
+        self.lsn.map(|x| x.to_string()).unwrap_or_default(),
+      ),
+      ("BSYNC_BYTES", self.bytes.to_string()),
+      ("BSYNC_ERROR", self.error.clone().unwrap_or_default()),
+    ]
+  }
+}
+
+/// Runs a script on the backup host and returns its stdout.
+pub fn run_local(
+  name: &str,
+  script: &str,
+  env: &HookEnv,
+  timeout: Option<Duration>,
+) -> Result<String> {
+  log::info!("Running local {} script.", name);
+  let mut child = Command::new("sh")
+    .arg("-c")
+    .arg(script)
+    .envs(env.vars())
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .spawn()?;
+
+  // Drain stdout in the background so that a chatty script can't block on a full pipe.
+  let mut stdout = child.stdout.take().unwrap();
+  let reader = std::thread::spawn(move || {
+    let mut out = Vec::new();
+    let _ = stdout.read_to_end(&mut out);
+    out
+  });
+
+  let start = Instant::now();
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if let Some(timeout) = timeout {
+      if start.elapsed() >= timeout {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(LocalHookTimeout(name.to_string(), timeout).into());
+      }
+    }
+    std::thread::sleep(Duration::from_millis(100));
+  };
+  let out = String::from_utf8_lossy(&reader.join().unwrap()).into_owned();
+  log::info!("local {} output: {}", name, out);
+  if !status.success() {
+    return Err(LocalHookFailed(name.to_string(), status).into());
+  }
+  say!("Finished running local {} script.", name);
+  Ok(out)
+}
+
+/// Runs a script on the backup source and returns its stdout.
+pub fn run_remote(
+  remote: &mut Remote,
+  name: &str,
+  script: &str,
+  env: &HookEnv,
+  timeout: Option<Duration>,
+) -> Result<String> {
+  log::info!("Running {} script.", name);
+  let mut cmd = String::new();
+  for (k, v) in env.vars() {
+    cmd.push_str(&format!("export {}={}\n", k, escape(Cow::Owned(v))));
+  }
+  cmd.push_str(script);
+  let out = remote
+    .exec_script(&cmd, timeout)
+    .context(RemoteHookFailed(name.to_string()))?;
+  log::info!("{} output: {}", name, out);
+  say!("Finished running {} script.", name);
+  Ok(out)
+}
+
+/// Returns the image path printed by a `pre_pull` script as a `BSYNC_IMAGE=<path>` line, if any.
+pub fn image_override(output: &str) -> Option<&str> {
+  output
+    .lines()
+    .filter_map(|x| x.trim().strip_prefix(IMAGE_OVERRIDE_PREFIX))
+    .rfind(|x| !x.is_empty())
+}
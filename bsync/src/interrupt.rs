@@ -0,0 +1,41 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use signal_hook::{
+  consts::{SIGINT, SIGTERM},
+  flag,
+};
+use thiserror::Error;
+
+use crate::lease;
+
+lazy_static! {
+  static ref INTERRUPTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+#[derive(Error, Debug)]
+#[error("interrupted by signal")]
+pub struct Interrupted;
+
+/// Turns SIGINT and SIGTERM into a graceful stop request, so that long-running operations can
+/// clean up before exiting. A second signal terminates the process immediately.
+pub fn install() -> Result<()> {
+  for sig in [SIGINT, SIGTERM] {
+    flag::register_conditional_shutdown(sig, 130, INTERRUPTED.clone())?;
+    flag::register(sig, INTERRUPTED.clone())?;
+  }
+  Ok(())
+}
+
+/// Returns an error if a stop was requested, or if a lease held by this process was lost.
+pub fn check() -> Result<()> {
+  if INTERRUPTED.load(Ordering::Relaxed) {
+    Err(Interrupted.into())
+  } else {
+    lease::check()
+  }
+}
@@ -0,0 +1,99 @@
+use std::{
+  sync::mpsc::{self, RecvTimeoutError, Sender},
+  thread::JoinHandle,
+  time::Duration,
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::db::{Database, LeaseHeld};
+
+/// Lifetime of a lease row. A holder that dies without releasing it blocks others for at most this
+/// long, or not at all if it ran on the same host.
+const LEASE_TTL_SECS: u64 = 60;
+
+/// How often a held lease is renewed.
+const RENEW_INTERVAL: Duration = Duration::from_secs(15);
+
+lazy_static! {
+  /// Set when a held lease is found to be gone. Others may have acted as if the holder wasn't
+  /// running, so it must stop.
+  static ref LOST: Mutex<Option<LeaseHeld>> = Mutex::new(None);
+}
+
+/// A lease in the database, renewed in the background and released on drop.
+pub struct Lease {
+  db: Database,
+  id: i64,
+  stop: Option<Sender<()>>,
+  renewer: Option<JoinHandle<()>>,
+}
+
+impl Lease {
+  /// Takes a lease of `kind` (`LEASE_PULL` or `LEASE_READ`) on `lsn`. `holder` names the command
+  /// in `bsync locks`.
+  pub fn acquire(
+    db: &Database,
+    kind: &'static str,
+    lsn: Option<u64>,
+    holder: &str,
+  ) -> Result<Self> {
+    // The renewer has its own connection, so that it isn't held up by long operations on `db`.
+    let renewer_db = db.reopen()?;
+    let id = db.acquire_lease(kind, lsn, holder, LEASE_TTL_SECS)?;
+    log::debug!("Acquired {} lease {}.", kind, id);
+    let (stop, stop_rx) = mpsc::channel::<()>();
+    let renewer = std::thread::spawn(move || loop {
+      match stop_rx.recv_timeout(RENEW_INTERVAL) {
+        Err(RecvTimeoutError::Timeout) => match renewer_db.renew_lease(id, LEASE_TTL_SECS) {
+          Ok(true) => {}
+          Ok(false) => {
+            log::error!("Lease {} expired or was removed by someone else.", id);
+            let holder = renewer_db
+              .list_leases()
+              .into_iter()
+              .find(|x| x.kind == kind && x.id != id)
+              .map(|x| x.describe())
+              .unwrap_or_else(|| {
+                format!(
+                  "someone else (lease {} of this command expired or was removed)",
+                  id
+                )
+              });
+            *LOST.lock() = Some(LeaseHeld(kind, holder));
+            return;
+          }
+          // Retried at the next interval. If the lease expires meanwhile, that fails too.
+          Err(e) => log::warn!("Cannot renew lease {}: {:?}", id, e),
+        },
+        _ => return,
+      }
+    });
+    Ok(Self {
+      db: db.clone(),
+      id,
+      stop: Some(stop),
+      renewer: Some(renewer),
+    })
+  }
+}
+
+/// Returns an error if a lease held by this process was lost.
+pub fn check() -> Result<()> {
+  match &*LOST.lock() {
+    Some(e) => Err(e.clone().into()),
+    None => Ok(()),
+  }
+}
+
+impl Drop for Lease {
+  fn drop(&mut self) {
+    drop(self.stop.take());
+    if let Some(x) = self.renewer.take() {
+      let _ = x.join();
+    }
+    self.db.release_lease(self.id);
+  }
+}
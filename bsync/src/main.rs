@@ -1,23 +1,75 @@
+mod attachment;
 mod blob;
+mod cmd_attachments;
+mod cmd_daemon;
+mod cmd_delete;
+mod cmd_diff;
 mod cmd_list;
+mod cmd_locks;
+mod cmd_metrics;
+mod cmd_parity;
+mod cmd_protect;
+mod cmd_prune;
 mod cmd_pull;
+mod cmd_repair;
 mod cmd_replay;
+mod cmd_salvage;
 mod cmd_serve;
 mod cmd_squash;
+mod cmd_stats;
+mod cmd_verify;
+mod cmd_verify_version;
 mod config;
 mod db;
+mod guard;
+mod hooks;
+mod interrupt;
+mod lease;
+mod merkle;
+mod metrics;
+mod notify;
+mod output;
+mod parity;
+mod remote;
+mod retention;
+mod schedule;
+mod source;
 mod util;
 
 use anyhow::Result;
+use cmd_attachments::Attachmentscmd;
+use cmd_daemon::Daemoncmd;
+use cmd_delete::Deletecmd;
+use cmd_diff::Diffcmd;
 use cmd_list::Listcmd;
+use cmd_locks::Lockscmd;
+use cmd_metrics::Metricscmd;
+use cmd_parity::Paritycmd;
+use cmd_protect::Protectcmd;
+use cmd_prune::Prunecmd;
 use cmd_pull::Pullcmd;
+use cmd_repair::Repaircmd;
 use cmd_replay::Replaycmd;
+use cmd_salvage::Salvagecmd;
 use cmd_serve::Servecmd;
 use cmd_squash::SquashCmd;
+use cmd_stats::Statscmd;
+use cmd_verify::Verifycmd;
+use cmd_verify_version::VerifyVersioncmd;
+use output::OutputFormat;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+  /// Output format: `text` or `json`. With `json`, a summary object is printed on stdout when the
+  /// command exits, and the exit code tells the kind of error.
+  #[structopt(long, global = true, default_value = "text")]
+  format: OutputFormat,
+
+  /// Write progress as newline-delimited JSON on stderr.
+  #[structopt(long, global = true)]
+  json_progress: bool,
+
   #[structopt(subcommand)]
   subcommand: Subcmd,
 }
@@ -27,29 +79,75 @@ enum Subcmd {
   Pull(Pullcmd),
   Replay(Replaycmd),
   List(Listcmd),
+  Diff(Diffcmd),
+  Attachments(Attachmentscmd),
   Squash(SquashCmd),
+  Prune(Prunecmd),
+  Delete(Deletecmd),
+  Protect(Protectcmd),
+  Stats(Statscmd),
+  Locks(Lockscmd),
+  Verify(Verifycmd),
+  VerifyVersion(VerifyVersioncmd),
+  Repair(Repaircmd),
+  Salvage(Salvagecmd),
+  Parity(Paritycmd),
   Serve(Servecmd),
+  Metrics(Metricscmd),
+  Daemon(Daemoncmd),
 }
 
-fn main() -> Result<()> {
+fn main() {
   pretty_env_logger::init_timed();
   let opt = Opt::from_args();
-  match &opt.subcommand {
-    Subcmd::Pull(cmd) => {
-      cmd.run()?;
-    }
-    Subcmd::Replay(cmd) => {
-      cmd.run()?;
-    }
-    Subcmd::List(cmd) => {
-      cmd.run()?;
+  output::init(opt.format, opt.json_progress);
+  let (command, res) = run(&opt.subcommand);
+  let code = match &res {
+    Ok(()) => {
+      // `list` prints its own JSON, `metrics` prints the Prometheus text format, and `attachments`
+      // may print an attachment.
+      let own_output = match &opt.subcommand {
+        Subcmd::List(_) | Subcmd::Metrics(_) => true,
+        Subcmd::Attachments(x) => x.writes_to_stdout(),
+        _ => false,
+      };
+      if !own_output {
+        output::print_summary(command, "success", None);
+      }
+      0
     }
-    Subcmd::Squash(cmd) => {
-      cmd.run()?;
-    }
-    Subcmd::Serve(cmd) => {
-      cmd.run()?;
+    Err(e) => {
+      if output::is_json() {
+        output::print_summary(command, "failure", Some(e));
+      } else {
+        eprintln!("Error: {:?}", e);
+      }
+      output::exit_code(e)
     }
+  };
+  std::process::exit(code);
+}
+
+fn run(subcommand: &Subcmd) -> (&'static str, Result<()>) {
+  match subcommand {
+    Subcmd::Pull(cmd) => ("pull", cmd.run()),
+    Subcmd::Replay(cmd) => ("replay", cmd.run()),
+    Subcmd::List(cmd) => ("list", cmd.run()),
+    Subcmd::Diff(cmd) => ("diff", cmd.run()),
+    Subcmd::Attachments(cmd) => ("attachments", cmd.run()),
+    Subcmd::Squash(cmd) => ("squash", cmd.run()),
+    Subcmd::Prune(cmd) => ("prune", cmd.run()),
+    Subcmd::Delete(cmd) => ("delete", cmd.run()),
+    Subcmd::Protect(cmd) => ("protect", cmd.run()),
+    Subcmd::Stats(cmd) => ("stats", cmd.run()),
+    Subcmd::Locks(cmd) => ("locks", cmd.run()),
+    Subcmd::Verify(cmd) => ("verify", cmd.run()),
+    Subcmd::VerifyVersion(cmd) => ("verify-version", cmd.run()),
+    Subcmd::Repair(cmd) => ("repair", cmd.run()),
+    Subcmd::Salvage(cmd) => ("salvage", cmd.run()),
+    Subcmd::Parity(cmd) => ("parity", cmd.run()),
+    Subcmd::Serve(cmd) => ("serve", cmd.run()),
+    Subcmd::Metrics(cmd) => ("metrics", cmd.run()),
+    Subcmd::Daemon(cmd) => ("daemon", cmd.run()),
   }
-  Ok(())
 }
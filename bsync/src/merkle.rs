@@ -0,0 +1,373 @@
+use std::{convert::TryInto, fs::read_to_string};
+
+use anyhow::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use thiserror::Error;
+
+use crate::{blob::ZERO_BLOCK_HASH, db::block_count};
+
+/// Nodes at this level and above are stored with each version. Each covers `CHUNK_BLOCKS` blocks.
+pub const STORED_LEVEL: u32 = 8;
+
+pub const CHUNK_BLOCKS: u64 = 1 << STORED_LEVEL;
+
+#[derive(Error, Debug)]
+#[error("cannot read the signing key at {0}: expected 32 bytes in hex")]
+pub struct BadSigningKey(String);
+
+/// A binary hash tree over the block hashes of a version. Leaves are the BLAKE3 hashes of the
+/// blocks, and each node hashes its level and its one or two children, so that a node covers
+/// blocks `[idx << level, (idx + 1) << level)` in every version. Only the nodes from
+/// `STORED_LEVEL` up are kept.
+pub struct MerkleTree {
+  pub size: u64,
+  /// `levels[i]` holds the nodes of level `STORED_LEVEL + i`. The last level has at most one node.
+  pub levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+  pub fn from_chunks(size: u64, chunks: Vec<[u8; 32]>) -> Self {
+    let mut levels = vec![chunks];
+    while levels.last().unwrap().len() > 1 {
+      let level = STORED_LEVEL + levels.len() as u32;
+      let next = levels
+        .last()
+        .unwrap()
+        .chunks(2)
+        .map(|x| node_hash(level, &x[0], x.get(1)))
+        .collect();
+      levels.push(next);
+    }
+    Self { size, levels }
+  }
+
+  /// Commits to the image size and all block hashes.
+  pub fn root(&self) -> [u8; 32] {
+    let top = self
+      .levels
+      .last()
+      .and_then(|x| x.first())
+      .copied()
+      .unwrap_or([0u8; 32]);
+    let mut h = blake3::Hasher::new();
+    h.update(b"bsync merkle root v1");
+    h.update(&self.size.to_le_bytes());
+    h.update(&top);
+    *h.finalize().as_bytes()
+  }
+
+  pub fn node(&self, level: u32, idx: u64) -> Option<&[u8; 32]> {
+    self
+      .levels
+      .get(level.checked_sub(STORED_LEVEL)? as usize)?
+      .get(idx as usize)
+  }
+
+  /// Returns the chunks that may differ between two versions, walking down from the top and
+  /// skipping subtrees whose hashes are equal.
+  pub fn changed_chunks(&self, other: &MerkleTree) -> Vec<u64> {
+    let top = STORED_LEVEL + self.levels.len().max(other.levels.len()) as u32 - 1;
+    let mut out = vec![];
+    let mut stack = vec![(top, 0u64)];
+    while let Some((level, idx)) = stack.pop() {
+      match (self.node(level, idx), other.node(level, idx)) {
+        (None, None) => continue,
+        (Some(a), Some(b)) if a == b => continue,
+        _ => {}
+      }
+      if level == STORED_LEVEL {
+        out.push(idx);
+      } else {
+        stack.push((level - 1, idx * 2 + 1));
+        stack.push((level - 1, idx * 2));
+      }
+    }
+    out
+  }
+}
+
+/// Builds a `MerkleTree` from the block hashes of a version, in block order.
+pub struct MerkleBuilder {
+  size: u64,
+  blocks: u64,
+  leaves: Vec<[u8; 32]>,
+  chunks: Vec<[u8; 32]>,
+}
+
+impl MerkleBuilder {
+  pub fn new(size: u64) -> Self {
+    Self {
+      size,
+      blocks: block_count(size),
+      leaves: Vec::with_capacity(CHUNK_BLOCKS as usize),
+      chunks: vec![],
+    }
+  }
+
+  /// Adds the hash of the next block.
+  pub fn push(&mut self, hash: [u8; 32]) {
+    self.leaves.push(hash);
+    if self.leaves.len() as u64 == CHUNK_BLOCKS {
+      self.chunks.push(chunk_hash(&self.leaves));
+      self.leaves.clear();
+    }
+  }
+
+  /// Adds the block at `block_id`, with zero blocks for the ones skipped since the last call.
+  pub fn push_at(&mut self, block_id: u64, hash: [u8; 32]) {
+    while self.position() < block_id {
+      self.push(*ZERO_BLOCK_HASH);
+    }
+    self.push(hash);
+  }
+
+  pub fn finish(mut self) -> MerkleTree {
+    while self.position() < self.blocks {
+      self.push(*ZERO_BLOCK_HASH);
+    }
+    if !self.leaves.is_empty() {
+      self.chunks.push(chunk_hash(&self.leaves));
+    }
+    MerkleTree::from_chunks(self.size, self.chunks)
+  }
+
+  fn position(&self) -> u64 {
+    self.chunks.len() as u64 * CHUNK_BLOCKS + self.leaves.len() as u64
+  }
+}
+
+fn node_hash(level: u32, left: &[u8; 32], right: Option<&[u8; 32]>) -> [u8; 32] {
+  let mut h = blake3::Hasher::new();
+  h.update(&level.to_le_bytes());
+  h.update(left);
+  if let Some(right) = right {
+    h.update(right);
+  }
+  *h.finalize().as_bytes()
+}
+
+/// Hashes up to `CHUNK_BLOCKS` leaves into their node at `STORED_LEVEL`.
+fn chunk_hash(leaves: &[[u8; 32]]) -> [u8; 32] {
+  let mut nodes = leaves.to_vec();
+  for level in 1..=STORED_LEVEL {
+    nodes = nodes
+      .chunks(2)
+      .map(|x| node_hash(level, &x[0], x.get(1)))
+      .collect();
+  }
+  nodes[0]
+}
+
+/// Reads an Ed25519 private key stored as 32 bytes in hex, e.g. from `openssl rand -hex 32`.
+pub fn load_signing_key(path: &str) -> Result<SigningKey> {
+  let key: [u8; 32] = read_to_string(path)
+    .ok()
+    .and_then(|x| hex::decode(x.trim()).ok())
+    .and_then(|x| x.try_into().ok())
+    .ok_or_else(|| BadSigningKey(path.to_string()))?;
+  Ok(SigningKey::from_bytes(&key))
+}
+
+fn signed_message(lsn: u64, root: &[u8; 32]) -> Vec<u8> {
+  let mut msg = b"bsync version v1".to_vec();
+  msg.extend_from_slice(&lsn.to_le_bytes());
+  msg.extend_from_slice(root);
+  msg
+}
+
+/// Signs the root of the version at `lsn`. Returns the public key and the signature.
+pub fn sign(key: &SigningKey, lsn: u64, root: &[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+  let signature = key.sign(&signed_message(lsn, root));
+  (
+    key.verifying_key().to_bytes().to_vec(),
+    signature.to_bytes().to_vec(),
+  )
+}
+
+pub fn verify_signature(public_key: &[u8], signature: &[u8], lsn: u64, root: &[u8; 32]) -> bool {
+  let public_key = match public_key
+    .try_into()
+    .ok()
+    .and_then(|x| VerifyingKey::from_bytes(x).ok())
+  {
+    Some(x) => x,
+    None => return false,
+  };
+  let signature = match Signature::from_slice(signature) {
+    Ok(x) => x,
+    Err(_) => return false,
+  };
+  public_key
+    .verify(&signed_message(lsn, root), &signature)
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::LOG_BLOCK_SIZE;
+
+  /// Distinct block hashes. `version` changes all of them.
+  fn blocks(count: u64, version: u8) -> Vec<[u8; 32]> {
+    (0..count)
+      .map(|i| {
+        let mut h = blake3::Hasher::new();
+        h.update(&i.to_le_bytes());
+        h.update(&[version]);
+        *h.finalize().as_bytes()
+      })
+      .collect()
+  }
+
+  fn tree(blocks: &[[u8; 32]]) -> MerkleTree {
+    let mut b = MerkleBuilder::new(blocks.len() as u64 * LOG_BLOCK_SIZE as u64);
+    for x in blocks {
+      b.push(*x);
+    }
+    b.finish()
+  }
+
+  /// The chunks whose blocks differ, including the ones only one version has.
+  fn brute_force(a: &[[u8; 32]], b: &[[u8; 32]]) -> Vec<u64> {
+    let chunk =
+      |x: &[[u8; 32]], i: usize| x.chunks(CHUNK_BLOCKS as usize).nth(i).map(|x| x.to_vec());
+    let chunks = a.len().max(b.len()).div_ceil(CHUNK_BLOCKS as usize);
+    (0..chunks)
+      .filter(|&i| chunk(a, i) != chunk(b, i))
+      .map(|i| i as u64)
+      .collect()
+  }
+
+  fn check(a: &[[u8; 32]], b: &[[u8; 32]]) -> Vec<u64> {
+    let expected = brute_force(a, b);
+    assert_eq!(tree(a).changed_chunks(&tree(b)), expected);
+    assert_eq!(tree(b).changed_chunks(&tree(a)), expected);
+    expected
+  }
+
+  #[test]
+  fn identical() {
+    for n in [0, 1, 255, 256, 257, 1000, 4096] {
+      let x = blocks(n, 0);
+      assert_eq!(check(&x, &x), Vec::<u64>::new());
+      assert_eq!(tree(&x).root(), tree(&x).root());
+    }
+  }
+
+  #[test]
+  fn single_block_changes() {
+    let n = 5 * CHUNK_BLOCKS + 17;
+    let base = blocks(n, 0);
+    for i in [0, 1, 255, 256, 511, 700, 4 * CHUNK_BLOCKS, n - 1] {
+      let mut changed = base.clone();
+      changed[i as usize] = blocks(n, 1)[i as usize];
+      assert_eq!(check(&base, &changed), [i / CHUNK_BLOCKS]);
+      assert_ne!(tree(&base).root(), tree(&changed).root());
+    }
+  }
+
+  #[test]
+  fn scattered_changes() {
+    let n = 37 * CHUNK_BLOCKS + 100;
+    let base = blocks(n, 0);
+    let other = blocks(n, 1);
+    let mut changed = base.clone();
+    for i in (0..n as usize).step_by(1777) {
+      changed[i] = other[i];
+    }
+    let expected = check(&base, &changed);
+    assert_eq!(expected, [0, 6, 13, 20, 27, 34]);
+    assert_eq!(check(&base, &other).len(), 38);
+  }
+
+  #[test]
+  fn different_sizes() {
+    let long = blocks(9 * CHUNK_BLOCKS + 3, 0);
+    for n in [
+      0,
+      1,
+      CHUNK_BLOCKS - 1,
+      CHUNK_BLOCKS,
+      CHUNK_BLOCKS + 1,
+      2 * CHUNK_BLOCKS,
+      4 * CHUNK_BLOCKS + 1,
+      8 * CHUNK_BLOCKS,
+      9 * CHUNK_BLOCKS,
+    ] {
+      let short = &long[..n as usize];
+      let changed = check(short, &long);
+      // Only the partial chunk at the end of the shorter image and the chunks after it.
+      assert_eq!(changed[0], n / CHUNK_BLOCKS);
+      assert_eq!(*changed.last().unwrap(), 9);
+      assert_ne!(tree(short).root(), tree(&long).root());
+    }
+  }
+
+  #[test]
+  fn partial_last_chunks() {
+    let base = blocks(3 * CHUNK_BLOCKS + 10, 0);
+    // Growing within the last chunk changes only that chunk.
+    assert_eq!(check(&base[..3 * 256 + 5], &base), [3]);
+    // A changed block in a partial last chunk.
+    let mut changed = base.clone();
+    *changed.last_mut().unwrap() = [7; 32];
+    assert_eq!(check(&base, &changed), [3]);
+    // Both sizes end mid-chunk, in different chunks.
+    assert_eq!(check(&base[..CHUNK_BLOCKS as usize + 1], &base), [1, 2, 3]);
+  }
+
+  #[test]
+  fn size_is_part_of_the_root() {
+    // The same blocks, but the image ends 1 byte earlier.
+    let x = blocks(10, 0);
+    let a = tree(&x);
+    let mut b = MerkleBuilder::new(10 * LOG_BLOCK_SIZE as u64 - 1);
+    for h in &x {
+      b.push(*h);
+    }
+    let b = b.finish();
+    assert_eq!(a.levels, b.levels);
+    assert_ne!(a.root(), b.root());
+  }
+
+  #[test]
+  fn builder_fills_zero_blocks() {
+    let n = 2 * CHUNK_BLOCKS + 50;
+    let mut explicit = vec![*ZERO_BLOCK_HASH; n as usize];
+    explicit[3] = [1; 32];
+    explicit[300] = [2; 32];
+
+    let mut b = MerkleBuilder::new(n * LOG_BLOCK_SIZE as u64);
+    b.push_at(3, [1; 32]);
+    b.push_at(300, [2; 32]);
+    let sparse = b.finish();
+    assert_eq!(sparse.levels, tree(&explicit).levels);
+    assert_eq!(sparse.root(), tree(&explicit).root());
+  }
+
+  #[test]
+  fn levels() {
+    let t = tree(&blocks(5 * CHUNK_BLOCKS, 0));
+    let lens: Vec<usize> = t.levels.iter().map(|x| x.len()).collect();
+    assert_eq!(lens, [5, 3, 2, 1]);
+    assert!(t.node(STORED_LEVEL - 1, 0).is_none());
+    assert!(t.node(STORED_LEVEL, 4).is_some());
+    assert!(t.node(STORED_LEVEL, 5).is_none());
+    assert_eq!(
+      t.node(STORED_LEVEL + 3, 0),
+      t.levels.last().unwrap().first()
+    );
+  }
+
+  #[test]
+  fn signatures() {
+    let key = SigningKey::from_bytes(&[9; 32]);
+    let root = tree(&blocks(3, 0)).root();
+    let (public_key, signature) = sign(&key, 5, &root);
+    assert!(verify_signature(&public_key, &signature, 5, &root));
+    assert!(!verify_signature(&public_key, &signature, 6, &root));
+    assert!(!verify_signature(&public_key, &signature, 5, &[0; 32]));
+    assert!(!verify_signature(&public_key[1..], &signature, 5, &root));
+    assert!(!verify_signature(&public_key, &signature[1..], 5, &root));
+  }
+}
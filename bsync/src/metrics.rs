@@ -0,0 +1,213 @@
+use std::{
+  fmt::Write as _,
+  io::{BufRead, BufReader, Write},
+  net::TcpListener,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::db::Database;
+
+/// Collects samples grouped by metric, since each metric may only be described once.
+#[derive(Default)]
+struct Writer {
+  metrics: Vec<(&'static str, &'static str, Vec<String>)>,
+  labels: String,
+}
+
+impl Writer {
+  fn gauge(&mut self, name: &'static str, help: &'static str, value: impl std::fmt::Display) {
+    let sample = format!("{}{{{}}} {}", name, self.labels, value);
+    match self.metrics.iter_mut().find(|x| x.0 == name) {
+      Some(x) => x.2.push(sample),
+      None => self.metrics.push((name, help, vec![sample])),
+    }
+  }
+
+  fn finish(self) -> String {
+    let mut out = String::new();
+    for (name, help, samples) in self.metrics {
+      writeln!(out, "# HELP {} {}", name, help).unwrap();
+      writeln!(out, "# TYPE {} gauge", name).unwrap();
+      for sample in samples {
+        writeln!(out, "{}", sample).unwrap();
+      }
+    }
+    out
+  }
+}
+
+/// Renders the metrics of the repository at `db_path`.
+///
+/// `last_pull_success` is the outcome of the pull that just finished, if any. It is not stored in
+/// the database, so only `bsync pull` and `bsync daemon` can report it.
+pub fn render(db: &Database, db_path: &Path, last_pull_success: Option<bool>) -> Result<String> {
+  let mut w = Writer::default();
+  render_repo(&mut w, db, db_path, last_pull_success)?;
+  Ok(w.finish())
+}
+
+/// Renders the metrics of several repositories, skipping those that can't be read.
+pub fn render_all(repos: &[(PathBuf, Option<bool>)]) -> String {
+  let mut w = Writer::default();
+  for (db_path, last_pull_success) in repos {
+    let res = Database::open_file(db_path, false)
+      .and_then(|db| render_repo(&mut w, &db, db_path, *last_pull_success));
+    if let Err(e) = res {
+      log::error!("cannot read metrics of {:?}: {:?}", db_path, e);
+    }
+  }
+  w.finish()
+}
+
+fn render_repo(
+  w: &mut Writer,
+  db: &Database,
+  db_path: &Path,
+  last_pull_success: Option<bool>,
+) -> Result<()> {
+  w.labels = format!("db=\"{}\"", escape_label(&db_path.to_string_lossy()));
+
+  if let Some(x) = last_pull_success {
+    w.gauge(
+      "bsync_last_pull_success",
+      "Whether the last pull succeeded.",
+      x as u8,
+    );
+  }
+
+  let cp_list = db.list_consistent_point();
+  w.gauge(
+    "bsync_consistent_points",
+    "Number of versions in the repository.",
+    cp_list.len(),
+  );
+  if let Some(cp) = cp_list.last() {
+    w.gauge("bsync_latest_lsn", "LSN of the latest version.", cp.lsn);
+    w.gauge(
+      "bsync_latest_size_bytes",
+      "Image size of the latest version.",
+      cp.size,
+    );
+    w.gauge(
+      "bsync_last_success_timestamp_seconds",
+      "Time the latest version was created.",
+      cp.created_at,
+    );
+  }
+
+  // Versions pulled by older versions of bsync have no metadata.
+  if let Some(meta) = db
+    .list_pull_meta()
+    .into_iter()
+    .find(|x| Some(x.lsn) == cp_list.last().map(|x| x.lsn))
+  {
+    w.gauge(
+      "bsync_last_pull_duration_seconds",
+      "Duration of the pull that created the latest version.",
+      meta.finished_at.saturating_sub(meta.started_at) as f64 / 1000.0,
+    );
+    w.gauge(
+      "bsync_last_pull_downloaded_bytes",
+      "Bytes downloaded by the pull that created the latest version.",
+      meta.downloaded_bytes,
+    );
+    w.gauge(
+      "bsync_last_pull_reused_bytes",
+      "Bytes reused from the repository by the pull that created the latest version.",
+      meta.reused_bytes,
+    );
+    w.gauge(
+      "bsync_last_pull_changed_blocks",
+      "Blocks changed in the latest version.",
+      meta.changed_blocks,
+    );
+    w.gauge(
+      "bsync_last_pull_total_blocks",
+      "Blocks in the image of the latest version.",
+      meta.total_blocks,
+    );
+  }
+
+  let (blobs, blob_bytes) = db.cas_stats();
+  w.gauge("bsync_cas_blobs", "Number of blobs in the CAS.", blobs);
+  w.gauge(
+    "bsync_cas_bytes",
+    "Stored (compressed) size of the blobs in the CAS.",
+    blob_bytes,
+  );
+
+  let mut db_bytes = std::fs::metadata(db_path)?.len();
+  let mut wal_path = db_path.as_os_str().to_owned();
+  wal_path.push("-wal");
+  if let Ok(x) = std::fs::metadata(&wal_path) {
+    db_bytes += x.len();
+  }
+  w.gauge(
+    "bsync_database_bytes",
+    "Size of the database file, including the WAL.",
+    db_bytes,
+  );
+  Ok(())
+}
+
+/// Atomically replaces `path` with `content`, so that the collector never reads a partial file.
+pub fn write_textfile(path: &Path, content: &str) -> Result<()> {
+  let mut tmp_path = path.as_os_str().to_owned();
+  tmp_path.push(".tmp");
+  std::fs::write(&tmp_path, content)?;
+  std::fs::rename(&tmp_path, path)?;
+  Ok(())
+}
+
+/// Serves the output of `render` at `/metrics`. Requests are handled one at a time.
+pub fn serve(listen: &str, render: impl Fn() -> Result<String>) -> Result<()> {
+  let listener = TcpListener::bind(listen)?;
+  for conn in listener.incoming() {
+    let mut conn = conn?;
+    conn.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request_line = String::new();
+    {
+      // Read the whole request head - closing the connection with unread data would reset it.
+      let mut reader = BufReader::new(&mut conn);
+      if reader.read_line(&mut request_line).is_err() {
+        continue;
+      }
+      let mut line = String::new();
+      while matches!(reader.read_line(&mut line), Ok(n) if n > 0 && line.trim_end() != "") {
+        line.clear();
+      }
+    }
+    let path = request_line.split(' ').nth(1).unwrap_or("");
+    let (status, body) = if path != "/metrics" {
+      ("404 Not Found", "not found\n".to_string())
+    } else {
+      match render() {
+        Ok(x) => ("200 OK", x),
+        Err(e) => {
+          log::error!("cannot render metrics: {:?}", e);
+          ("500 Internal Server Error", format!("{:#}\n", e))
+        }
+      }
+    };
+    let res = write!(
+      conn,
+      "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      status,
+      body.len(),
+      body
+    );
+    if let Err(e) = res {
+      log::warn!("cannot write metrics response: {}", e);
+    }
+  }
+  Ok(())
+}
+
+fn escape_label(x: &str) -> String {
+  x.replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
@@ -0,0 +1,17 @@
+-- How each consistent point was produced. Keyed by the LSN of the consistent point.
+create table `pull_meta_v1` (
+  `lsn` integer not null primary key,
+  `started_at` integer not null,
+  `finished_at` integer not null,
+  `remote_host` text not null,
+  `image` text not null,
+  `downloaded_bytes` integer not null,
+  `reused_bytes` integer not null,
+  `changed_blocks` integer not null,
+  `total_blocks` integer not null,
+  `bsync_version` text not null,
+  `pre_pull_output` text,
+  `post_pull_output` text,
+  `local_pre_pull_output` text,
+  `local_post_pull_output` text
+);
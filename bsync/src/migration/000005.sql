@@ -0,0 +1,5 @@
+-- For garbage collection: finding the redo entries that reference a blob.
+create index `redo_v1_hash` on `redo_v1` (`hash`);
+
+-- For squash: finding the next write to a block.
+create index `redo_v1_block_id_lsn` on `redo_v1` (`block_id`, `lsn`);
@@ -0,0 +1,11 @@
+-- Versions that `squash`, `prune` and `delete` must not remove. Keyed by the LSN of the
+-- consistent point.
+create table `protection_v1` (
+  `lsn` integer not null primary key,
+  -- Set while the version is pinned.
+  `pin_reason` text,
+  -- Seconds since the epoch. The version can't be removed, and the lock can't be shortened, before
+  -- this time.
+  `locked_until` integer,
+  `lock_reason` text
+);
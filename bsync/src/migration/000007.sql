@@ -0,0 +1,13 @@
+-- Locks and leases held by running commands. A lease is renewed by its holder, and is ignored
+-- once `expires_at` (seconds since the epoch) passes, e.g. because the holder crashed.
+create table `lease_v1` (
+  `id` integer not null primary key autoincrement,
+  -- `pull`: the exclusive pull lock. `read`: a reader of the consistent point at `lsn`.
+  `kind` text not null,
+  `lsn` integer,
+  `holder` text not null,
+  `hostname` text not null,
+  `pid` integer not null,
+  `acquired_at` integer not null,
+  `expires_at` integer not null
+);
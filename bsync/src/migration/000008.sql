@@ -0,0 +1,8 @@
+-- CAS blobs that `bsync verify` found to be missing or unreadable. Keyed by the hash that redo
+-- entries refer to. A row is removed once the blob verifies again.
+create table `damaged_blob_v1` (
+  `hash` blob not null primary key,
+  `reason` text not null,
+  -- Seconds since the epoch.
+  `detected_at` integer not null
+);
@@ -0,0 +1,31 @@
+-- Reed-Solomon parity over CAS blobs, so that damaged blobs can be rebuilt. A group covers up to
+-- `data_shards` blobs, each zero-padded to `shard_size`. Missing data shards of a group with
+-- fewer blobs are all zeros.
+create table `parity_group_v1` (
+  `id` integer not null primary key autoincrement,
+  `data_shards` integer not null,
+  `parity_shards` integer not null,
+  `shard_size` integer not null
+);
+
+-- The blobs of each group. `idx` is the data shard, `length`, `compressed` and `content_hash` (the
+-- BLAKE3 of the stored content) describe the `cas_v1` row the shard was made from.
+create table `parity_member_v1` (
+  `hash` blob not null primary key,
+  `group_id` integer not null,
+  `idx` integer not null,
+  `length` integer not null,
+  `compressed` integer not null,
+  `content_hash` blob not null
+);
+create index `parity_member_v1_group_id` on `parity_member_v1` (`group_id`);
+
+-- Parity shards, keyed by group and shard index, starting at `data_shards`. `hash` is the BLAKE3
+-- of `content`, so that damaged parity isn't used.
+create table `parity_shard_v1` (
+  `group_id` integer not null,
+  `idx` integer not null,
+  `hash` blob not null,
+  `content` blob not null,
+  primary key (`group_id`, `idx`)
+);
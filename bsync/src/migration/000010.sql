@@ -0,0 +1,18 @@
+-- Merkle root of each consistent point over its image size and block hashes, and its Ed25519
+-- signature if the pull had a signing key. Keyed by the LSN of the consistent point.
+create table `merkle_root_v1` (
+  `lsn` integer not null primary key,
+  `root` blob not null,
+  `public_key` blob,
+  `signature` blob
+);
+
+-- The upper nodes of each version's tree, from the level where a node covers 256 blocks. Used to
+-- find changed blocks without comparing all of them.
+create table `merkle_node_v1` (
+  `lsn` integer not null,
+  `level` integer not null,
+  `idx` integer not null,
+  `hash` blob not null,
+  primary key (`lsn`, `level`, `idx`)
+);
@@ -0,0 +1,3 @@
+-- SHA-256 of the whole image, computed on the remote during the pull if `remote.checksum` is set.
+-- `replay` checks its output against it.
+alter table `consistent_point_v1` add column `sha256` blob;
@@ -0,0 +1,3 @@
+-- Set if the image changed while it was being pulled, so the version may not be a point-in-time
+-- copy of it.
+alter table `consistent_point_v1` add column `fuzzy` integer not null default 0;
@@ -0,0 +1,13 @@
+-- Size, geometry and identity of the remote image at each consistent point, as reported by
+-- transmit. Pulls refuse to continue if the identity changed.
+create table `source_info_v1` (
+  `lsn` integer not null primary key,
+  `size` integer not null,
+  `logical_sector_size` integer,
+  `physical_sector_size` integer,
+  `wwid` text,
+  `serial` text,
+  `partition` integer,
+  `uuid` text,
+  `dm_name` text
+);
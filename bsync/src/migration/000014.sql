@@ -0,0 +1,9 @@
+-- Command outputs and files captured from the remote by a pull, e.g. the partition table, stored
+-- compressed with zstd. Keyed by the LSN of the consistent point.
+create table `attachment_v1` (
+  `lsn` integer not null,
+  `name` text not null,
+  `size` integer not null,
+  `content` blob not null,
+  primary key (`lsn`, `name`)
+);
@@ -0,0 +1,3 @@
+-- Why the `guard` of the config flagged the pull of a consistent point, e.g. because most of its
+-- blocks became incompressible.
+alter table `consistent_point_v1` add column `suspect` text;
@@ -0,0 +1,582 @@
+use std::{
+  io::{BufRead, BufReader, Write},
+  net::TcpStream,
+  process::{Command, ExitStatus, Stdio},
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::config::{NotifyBackend, NotifyConfig, NotifyEvent};
+
+const DEFAULT_TEMPLATE: &str = "bsync pull {{status}}: {{remote_host}}:{{image}}
+LSN: {{lsn}}
+Downloaded {{downloaded_bytes}} bytes and reused {{reused_bytes}} bytes. {{changed_blocks}} of {{total_blocks}} blocks changed. {{retries}} retries.
+Warnings: {{warnings}}
+Error: {{error}}
+";
+
+const DEFAULT_SUBJECT: &str = "bsync pull {{status}}: {{image}}";
+
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+#[error("unexpected SMTP response: {0}")]
+struct SmtpError(String);
+
+#[derive(Error, Debug)]
+#[error("notification command failed: {0}")]
+struct ExecFailed(ExitStatus);
+
+#[derive(Error, Debug)]
+#[error("notification command timed out after {0:?}")]
+struct ExecTimeout(Duration);
+
+/// The outcome of a pull, as reported to the notification backends.
+pub struct Notification {
+  event: NotifyEvent,
+  fields: Map<String, Value>,
+  error_chain: Vec<String>,
+  warnings: Vec<String>,
+}
+
+impl Notification {
+  pub fn new(summary: &impl Serialize, error: Option<&anyhow::Error>, warnings: &[String]) -> Self {
+    let event = if error.is_some() {
+      NotifyEvent::Failure
+    } else if !warnings.is_empty() {
+      NotifyEvent::Warning
+    } else {
+      NotifyEvent::Success
+    };
+    let mut fields = match serde_json::to_value(summary).unwrap() {
+      Value::Object(x) => x,
+      _ => Map::new(),
+    };
+    // Kept separately, and rendered as one line in templates.
+    fields.remove("warnings");
+    Self {
+      event,
+      fields,
+      error_chain: error
+        .map(|e| e.chain().map(|x| x.to_string()).collect())
+        .unwrap_or_default(),
+      warnings: warnings.to_vec(),
+    }
+  }
+
+  fn status(&self) -> &'static str {
+    match self.event {
+      NotifyEvent::Success => "success",
+      NotifyEvent::Failure => "failure",
+      NotifyEvent::Warning => "warning",
+    }
+  }
+
+  /// Template variables, as strings.
+  fn vars(&self) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = self
+      .fields
+      .iter()
+      .map(|(k, v)| {
+        let v = match v {
+          Value::Null => String::new(),
+          Value::String(x) => x.clone(),
+          x => x.to_string(),
+        };
+        (k.clone(), v)
+      })
+      .collect();
+    vars.push(("status".into(), self.status().into()));
+    vars.push(("error".into(), self.error_chain.join(": ")));
+    vars.push(("warnings".into(), self.warnings.join("; ")));
+    vars
+  }
+
+  fn render(&self, template: &str) -> String {
+    let vars = self.vars();
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+      out.push_str(&rest[..start]);
+      let after = &rest[start + 2..];
+      match after.find("}}") {
+        Some(end) => {
+          let name = after[..end].trim();
+          if let Some((_, v)) = vars.iter().find(|(k, _)| k == name) {
+            out.push_str(v);
+          }
+          rest = &after[end + 2..];
+        }
+        None => {
+          out.push_str(&rest[start..]);
+          rest = "";
+        }
+      }
+    }
+    out.push_str(rest);
+    out
+  }
+}
+
+/// Sends `n` to every backend that is configured for its outcome. Failures are logged. `timeout`
+/// bounds the `exec` backend, like the local scripts.
+pub fn send_all(configs: &[NotifyConfig], n: &Notification, timeout: Option<Duration>) {
+  for config in configs.iter().filter(|x| x.on.contains(&n.event)) {
+    let message = n.render(config.template.as_deref().unwrap_or(DEFAULT_TEMPLATE));
+    let (kind, res) = match &config.backend {
+      NotifyBackend::Webhook { url } => ("webhook", send_webhook(url, n, &message)),
+      NotifyBackend::Smtp {
+        server,
+        port,
+        from,
+        to,
+        subject,
+      } => (
+        "smtp",
+        send_smtp(
+          server,
+          port.unwrap_or(25),
+          from,
+          to,
+          &n.render(subject.as_deref().unwrap_or(DEFAULT_SUBJECT)),
+          &message,
+        ),
+      ),
+      NotifyBackend::Exec { command } => ("exec", send_exec(command, n, &message, timeout)),
+    };
+    match res {
+      Ok(()) => log::info!("Sent {} notification via {}.", n.status(), kind),
+      Err(e) => log::error!("{} notification failed: {:?}", kind, e),
+    }
+  }
+}
+
+fn send_webhook(url: &str, n: &Notification, message: &str) -> Result<()> {
+  let mut body = n.fields.clone();
+  body.insert("status".into(), json!(n.status()));
+  body.insert("message".into(), json!(message));
+  body.insert("error_chain".into(), json!(n.error_chain));
+  body.insert("warnings".into(), json!(n.warnings));
+  ureq::post(url)
+    .timeout(NETWORK_TIMEOUT)
+    .set("Content-Type", "application/json")
+    .send_string(&Value::Object(body).to_string())?;
+  Ok(())
+}
+
+fn send_smtp(
+  server: &str,
+  port: u16,
+  from: &str,
+  to: &[String],
+  subject: &str,
+  message: &str,
+) -> Result<()> {
+  let mut stream = TcpStream::connect((server, port))?;
+  stream.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+  stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+
+  smtp_expect(&mut reader, 220)?;
+  smtp_command(&mut stream, &mut reader, "EHLO bsync", 250)?;
+  smtp_command(
+    &mut stream,
+    &mut reader,
+    &format!("MAIL FROM:<{}>", from),
+    250,
+  )?;
+  for rcpt in to {
+    smtp_command(
+      &mut stream,
+      &mut reader,
+      &format!("RCPT TO:<{}>", rcpt),
+      250,
+    )?;
+  }
+  smtp_command(&mut stream, &mut reader, "DATA", 354)?;
+
+  let mut data = format!(
+    "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+    from,
+    to.iter().map(|x| format!("<{}>", x)).collect::<Vec<_>>().join(", "),
+    subject.lines().next().unwrap_or(""),
+    chrono::Local::now().to_rfc2822(),
+  );
+  for line in message.lines() {
+    // Dot-stuffing, so that a line with a single dot doesn't end the message.
+    if line.starts_with('.') {
+      data.push('.');
+    }
+    data.push_str(line);
+    data.push_str("\r\n");
+  }
+  data.push_str(".\r\n");
+  stream.write_all(data.as_bytes())?;
+  smtp_expect(&mut reader, 250)?;
+
+  smtp_command(&mut stream, &mut reader, "QUIT", 221)?;
+  Ok(())
+}
+
+fn smtp_command(
+  stream: &mut TcpStream,
+  reader: &mut impl BufRead,
+  command: &str,
+  expected: u16,
+) -> Result<()> {
+  stream.write_all(format!("{}\r\n", command).as_bytes())?;
+  smtp_expect(reader, expected)
+}
+
+/// Reads a (possibly multi-line) reply and checks that its code is in the class of `expected`,
+/// e.g. `251 will forward` for 250.
+fn smtp_expect(reader: &mut impl BufRead, expected: u16) -> Result<()> {
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+      return Err(SmtpError("connection closed".into()).into());
+    }
+    let line = line.trim_end();
+    let code: Option<u16> = line.get(..3).and_then(|x| x.parse().ok());
+    if code.map(|x| x / 100) != Some(expected / 100) {
+      return Err(SmtpError(line.to_string()).into());
+    }
+    // `250-...` is followed by more lines, `250 ...` is the last one.
+    if line.as_bytes().get(3) != Some(&b'-') {
+      return Ok(());
+    }
+  }
+}
+
+fn send_exec(
+  command: &str,
+  n: &Notification,
+  message: &str,
+  timeout: Option<Duration>,
+) -> Result<()> {
+  let mut child = Command::new("sh")
+    .arg("-c")
+    .arg(command)
+    .envs(
+      n.vars()
+        .into_iter()
+        .map(|(k, v)| (format!("BSYNC_NOTIFY_{}", k.to_uppercase()), v)),
+    )
+    .stdin(Stdio::piped())
+    .spawn()?;
+  // The command may not read its input.
+  let _ = child.stdin.take().unwrap().write_all(message.as_bytes());
+  let start = Instant::now();
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if let Some(timeout) = timeout {
+      if start.elapsed() >= timeout {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(ExecTimeout(timeout).into());
+      }
+    }
+    std::thread::sleep(Duration::from_millis(100));
+  };
+  if !status.success() {
+    return Err(ExecFailed(status).into());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::Read,
+    net::TcpListener,
+    thread::{self, JoinHandle},
+  };
+
+  use anyhow::anyhow;
+
+  use super::*;
+
+  fn notification(error: Option<&anyhow::Error>, warnings: &[String]) -> Notification {
+    Notification::new(
+      &json!({
+        "lsn": 42,
+        "image": "/dev/vg0/data",
+        "remote_host": "vm",
+        "downloaded_bytes": 4096,
+        "duration_ms": null,
+        "warnings": ["ignored"],
+      }),
+      error,
+      warnings,
+    )
+  }
+
+  /// Accepts one connection on a local port and passes it to `f`. Returns the port and what `f`
+  /// returns.
+  fn serve_once<T: Send + 'static>(
+    f: impl FnOnce(TcpStream) -> T + Send + 'static,
+  ) -> (u16, JoinHandle<T>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || f(listener.accept().unwrap().0));
+    (port, handle)
+  }
+
+  #[test]
+  fn event_from_outcome() {
+    assert!(notification(None, &[]).event == NotifyEvent::Success);
+    assert!(notification(None, &["resized".into()]).event == NotifyEvent::Warning);
+    let e = anyhow!("boom");
+    assert!(notification(Some(&e), &["resized".into()]).event == NotifyEvent::Failure);
+  }
+
+  #[test]
+  fn render_fields() {
+    let n = notification(None, &["a".into(), "b".into()]);
+    assert_eq!(
+      n.render("{{status}} {{ lsn }} {{remote_host}}:{{image}} {{downloaded_bytes}}"),
+      "warning 42 vm:/dev/vg0/data 4096"
+    );
+    // The warnings of the summary are replaced with the ones passed in.
+    assert_eq!(n.render("[{{warnings}}]"), "[a; b]");
+    assert_eq!(n.render("[{{duration_ms}}] [{{error}}]"), "[] []");
+  }
+
+  #[test]
+  fn render_unknown_and_unterminated_placeholders() {
+    let n = notification(None, &[]);
+    assert_eq!(n.render("a{{nope}}b"), "ab");
+    assert_eq!(n.render("{{lsn}} {{lsn"), "42 {{lsn");
+    assert_eq!(n.render("}} {{}} {"), "}}  {");
+    assert_eq!(n.render(""), "");
+  }
+
+  #[test]
+  fn render_error_chain() {
+    let e = anyhow!("connection refused")
+      .context("cannot connect to vm")
+      .context("pull failed");
+    let n = notification(Some(&e), &[]);
+    assert_eq!(
+      n.render("{{status}}: {{error}}"),
+      "failure: pull failed: cannot connect to vm: connection refused"
+    );
+    assert_eq!(
+      n.error_chain,
+      ["pull failed", "cannot connect to vm", "connection refused"]
+    );
+  }
+
+  /// Plays the server side of an SMTP session and returns the lines received. `reject` is the
+  /// command to reply to with an error.
+  fn smtp_server(stream: TcpStream, reject: Option<&'static str>) -> Vec<String> {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut received = vec![];
+    writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+    let mut in_data = false;
+    loop {
+      let mut line = String::new();
+      if reader.read_line(&mut line).unwrap() == 0 {
+        break;
+      }
+      received.push(line.clone());
+      let reply: &[u8] = if in_data {
+        if line != ".\r\n" {
+          continue;
+        }
+        in_data = false;
+        b"250 queued\r\n"
+      } else if matches!(reject, Some(x) if line.starts_with(x)) {
+        b"550 no such user\r\n"
+      } else if line.starts_with("RCPT TO:<fwd@") {
+        b"251 will forward\r\n"
+      } else if line.starts_with("EHLO ") {
+        b"250-localhost\r\n250-SIZE 1000000\r\n250 8BITMIME\r\n"
+      } else if line.starts_with("DATA") {
+        in_data = true;
+        b"354 go ahead\r\n"
+      } else if line.starts_with("QUIT") {
+        writer.write_all(b"221 bye\r\n").unwrap();
+        break;
+      } else {
+        b"250 ok\r\n"
+      };
+      writer.write_all(reply).unwrap();
+    }
+    received
+  }
+
+  #[test]
+  fn smtp_dialogue() {
+    let (port, server) = serve_once(|x| smtp_server(x, None));
+    send_smtp(
+      "127.0.0.1",
+      port,
+      "bsync@example.com",
+      &["a@example.com".into(), "b@example.com".into()],
+      "bsync pull failure: /dev/vg0/data\nsecond line",
+      "first\n.\n..dots\nlast",
+    )
+    .unwrap();
+    let received = server.join().unwrap();
+
+    assert_eq!(
+      received[..5],
+      [
+        "EHLO bsync\r\n",
+        "MAIL FROM:<bsync@example.com>\r\n",
+        "RCPT TO:<a@example.com>\r\n",
+        "RCPT TO:<b@example.com>\r\n",
+        "DATA\r\n",
+      ]
+    );
+    assert_eq!(received.last().unwrap(), "QUIT\r\n");
+
+    let data = received[5..received.len() - 1].concat();
+    assert!(
+      data.starts_with("From: <bsync@example.com>\r\nTo: <a@example.com>, <b@example.com>\r\n")
+    );
+    // Only the first line of the subject is used.
+    assert!(data.contains("\r\nSubject: bsync pull failure: /dev/vg0/data\r\n"));
+    assert!(data.contains("\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n"));
+    assert!(data.ends_with("\r\n\r\nfirst\r\n..\r\n...dots\r\nlast\r\n.\r\n"));
+  }
+
+  #[test]
+  fn smtp_rejected_recipient() {
+    let (port, server) = serve_once(|x| smtp_server(x, Some("RCPT TO:<b@")));
+    let e = send_smtp(
+      "127.0.0.1",
+      port,
+      "bsync@example.com",
+      &["a@example.com".into(), "b@example.com".into()],
+      "subject",
+      "message",
+    )
+    .unwrap_err();
+    assert_eq!(e.to_string(), "unexpected SMTP response: 550 no such user");
+    // The client gives up without sending the message.
+    assert!(!server.join().unwrap().iter().any(|x| x == "DATA\r\n"));
+  }
+
+  #[test]
+  fn smtp_forwarded_recipient() {
+    let (port, server) = serve_once(|x| smtp_server(x, None));
+    send_smtp(
+      "127.0.0.1",
+      port,
+      "bsync@example.com",
+      &["fwd@example.com".into()],
+      "subject",
+      "message",
+    )
+    .unwrap();
+    assert_eq!(server.join().unwrap().last().unwrap(), "QUIT\r\n");
+  }
+
+  #[test]
+  fn smtp_connection_closed() {
+    let (port, server) = serve_once(drop);
+    let e = send_smtp("127.0.0.1", port, "a@b", &["c@d".into()], "s", "m").unwrap_err();
+    server.join().unwrap();
+    assert_eq!(e.to_string(), "unexpected SMTP response: connection closed");
+  }
+
+  /// Reads one HTTP request, replies with `status` and returns the request head and body.
+  fn http_server(stream: TcpStream, status: &'static str) -> (String, Vec<u8>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    loop {
+      let n = head.len();
+      reader.read_line(&mut head).unwrap();
+      if &head[n..] == "\r\n" {
+        break;
+      }
+    }
+    let len: usize = head
+      .lines()
+      .find_map(|x| {
+        let (k, v) = x.split_once(':')?;
+        k.eq_ignore_ascii_case("content-length")
+          .then(|| v.trim().parse().unwrap())
+      })
+      .unwrap();
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).unwrap();
+    writer
+      .write_all(
+        format!(
+          "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+          status
+        )
+        .as_bytes(),
+      )
+      .unwrap();
+    (head, body)
+  }
+
+  #[test]
+  fn webhook_post() {
+    let (port, server) = serve_once(|x| http_server(x, "200 OK"));
+    let e = anyhow!("inner").context("outer");
+    let n = notification(Some(&e), &["w".into()]);
+    send_webhook(
+      &format!("http://127.0.0.1:{}/hook", port),
+      &n,
+      "the message",
+    )
+    .unwrap();
+    let (head, body) = server.join().unwrap();
+
+    assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+    assert!(head
+      .to_ascii_lowercase()
+      .contains("\r\ncontent-type: application/json\r\n"));
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["status"], "failure");
+    assert_eq!(body["message"], "the message");
+    assert_eq!(body["error_chain"], json!(["outer", "inner"]));
+    assert_eq!(body["warnings"], json!(["w"]));
+    assert_eq!(body["lsn"], 42);
+    assert_eq!(body["image"], "/dev/vg0/data");
+  }
+
+  #[test]
+  fn webhook_error_status() {
+    let (port, server) = serve_once(|x| http_server(x, "500 Internal Server Error"));
+    let n = notification(None, &[]);
+    assert!(send_webhook(&format!("http://127.0.0.1:{}/", port), &n, "m").is_err());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn exec_input_and_env() {
+    let n = notification(None, &[]);
+    send_exec(
+      r#"test "$BSYNC_NOTIFY_STATUS:$BSYNC_NOTIFY_LSN" = success:42 && test "$(cat)" = msg"#,
+      &n,
+      "msg",
+      None,
+    )
+    .unwrap();
+    let e = send_exec("exit 3", &n, "msg", None).unwrap_err();
+    assert!(e.is::<ExecFailed>());
+  }
+
+  #[test]
+  fn exec_timeout() {
+    let n = notification(None, &[]);
+    let start = Instant::now();
+    let e = send_exec("sleep 10", &n, "msg", Some(Duration::from_millis(200))).unwrap_err();
+    assert!(e.is::<ExecTimeout>());
+    assert!(start.elapsed() < Duration::from_secs(5));
+  }
+}
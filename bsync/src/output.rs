@@ -0,0 +1,287 @@
+use std::{
+  io::{IsTerminal, Write},
+  str::FromStr,
+  sync::atomic::{AtomicBool, AtomicU64, Ordering},
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use size_format::SizeFormatterBinary;
+use thiserror::Error;
+
+use crate::{
+  cmd_pull::{LockAcquire, SourceChanged},
+  cmd_replay::ImageChecksumMismatch,
+  cmd_verify::RepositoryDamaged,
+  db::{
+    DamagedBlob, LeaseHeld, LockShortened, LsnMismatch, MissingHash, NotConsistentPoint,
+    VersionInUse, VersionProtected,
+  },
+  guard::GuardTriggered,
+  hooks::{LocalHookFailed, LocalHookTimeout, RemoteHookFailed},
+  interrupt::Interrupted,
+  remote::{HostKeyVerifyError, NoHostKey, RemoteError, ScriptTimeout},
+  source::SourceIdentityChanged,
+};
+
+/// Generic failure.
+pub const EXIT_FAILURE: i32 = 1;
+/// Another command holds a conflicting lease.
+pub const EXIT_LOCKED: i32 = 2;
+/// The host key of the remote is unknown or doesn't match.
+pub const EXIT_HOST_KEY: i32 = 3;
+/// An SSH or remote command failure.
+pub const EXIT_REMOTE: i32 = 4;
+/// The database was modified concurrently. Retrying may succeed.
+pub const EXIT_LSN_MISMATCH: i32 = 5;
+/// The requested LSN is not a consistent point.
+pub const EXIT_BAD_LSN: i32 = 6;
+/// A user script failed or timed out.
+pub const EXIT_SCRIPT: i32 = 7;
+/// The command would remove a protected version.
+pub const EXIT_PROTECTED: i32 = 8;
+/// Blobs or versions in the repository are damaged.
+pub const EXIT_DAMAGED: i32 = 9;
+/// The remote image changed during a pull set to fail on it.
+pub const EXIT_SOURCE_CHANGED: i32 = 10;
+/// The remote image is not the device of the previous version.
+pub const EXIT_IDENTITY_CHANGED: i32 = 11;
+/// The guard aborted a pull.
+pub const EXIT_GUARD: i32 = 12;
+/// Stopped by SIGINT or SIGTERM.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Minimum interval between two progress events.
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static PROGRESS_EVENTS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+  static ref SUMMARY: Mutex<Map<String, Value>> = Mutex::new(Map::new());
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+  Text,
+  Json,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown output format `{0}` - expecting `text` or `json`")]
+pub struct UnknownOutputFormat(String);
+
+impl FromStr for OutputFormat {
+  type Err = UnknownOutputFormat;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Self::Text),
+      "json" => Ok(Self::Json),
+      _ => Err(UnknownOutputFormat(s.to_string())),
+    }
+  }
+}
+
+pub fn init(format: OutputFormat, progress_events: bool) {
+  JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+  PROGRESS_EVENTS.store(progress_events, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+  JSON.load(Ordering::Relaxed)
+}
+
+/// Prints a human-readable message to stdout. In JSON mode stdout is reserved for the summary, so
+/// the message is logged instead.
+macro_rules! say {
+  ($($arg:tt)*) => {
+    if $crate::output::is_json() {
+      log::info!($($arg)*);
+    } else {
+      println!($($arg)*);
+    }
+  };
+}
+pub(crate) use say;
+
+/// Adds the fields of `x` to the summary printed when the command exits.
+pub fn summary(x: &impl Serialize) {
+  if let Value::Object(x) = serde_json::to_value(x).unwrap() {
+    SUMMARY.lock().extend(x);
+  }
+}
+
+/// Prints the summary collected so far as a single line of JSON on stdout.
+pub fn print_summary(command: &str, status: &str, error: Option<&anyhow::Error>) {
+  if !is_json() {
+    return;
+  }
+  let mut out = Map::new();
+  out.insert("command".into(), json!(command));
+  out.insert("status".into(), json!(status));
+  out.extend(SUMMARY.lock().clone());
+  if let Some(e) = error {
+    out.insert(
+      "error".into(),
+      json!({
+        "code": exit_code(e),
+        "message": format!("{:#}", e),
+      }),
+    );
+  }
+  let mut stdout = std::io::stdout();
+  let _ = writeln!(stdout, "{}", Value::Object(out));
+  let _ = stdout.flush();
+}
+
+/// Prints `x` as a single line of JSON on stdout right away, in JSON mode. Commands that run until
+/// stopped use this to tell that they are ready; their summary is printed when they exit.
+pub fn print_started(command: &str, x: &impl Serialize) {
+  if !is_json() {
+    return;
+  }
+  let mut out = Map::new();
+  out.insert("command".into(), json!(command));
+  out.insert("status".into(), json!("started"));
+  if let Value::Object(x) = serde_json::to_value(x).unwrap() {
+    out.extend(x);
+  }
+  let mut stdout = std::io::stdout();
+  let _ = writeln!(stdout, "{}", Value::Object(out));
+  let _ = stdout.flush();
+}
+
+/// Writes an event as a single line of JSON on stderr, if `--json-progress` is set.
+pub fn event(name: &str, x: &impl Serialize) {
+  if !PROGRESS_EVENTS.load(Ordering::Relaxed) {
+    return;
+  }
+  let mut out = Map::new();
+  out.insert("event".into(), json!(name));
+  if let Value::Object(x) = serde_json::to_value(x).unwrap() {
+    out.extend(x);
+  }
+  let _ = writeln!(std::io::stderr(), "{}", Value::Object(out));
+}
+
+/// Maps an error to the documented exit code of its kind.
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+  fn has<T: std::error::Error + Send + Sync + 'static>(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<T>().is_some() || e.chain().any(|x| x.is::<T>())
+  }
+
+  if has::<Interrupted>(e) {
+    EXIT_INTERRUPTED
+  } else if has::<LockAcquire>(e) || has::<LeaseHeld>(e) || has::<VersionInUse>(e) {
+    EXIT_LOCKED
+  } else if has::<HostKeyVerifyError>(e) || has::<NoHostKey>(e) {
+    EXIT_HOST_KEY
+  } else if has::<LocalHookFailed>(e)
+    || has::<LocalHookTimeout>(e)
+    || has::<RemoteHookFailed>(e)
+    || has::<ScriptTimeout>(e)
+  {
+    EXIT_SCRIPT
+  } else if has::<RemoteError>(e) || has::<ssh2::Error>(e) {
+    EXIT_REMOTE
+  } else if has::<LsnMismatch>(e) || has::<MissingHash>(e) {
+    EXIT_LSN_MISMATCH
+  } else if has::<NotConsistentPoint>(e) {
+    EXIT_BAD_LSN
+  } else if has::<VersionProtected>(e) || has::<LockShortened>(e) {
+    EXIT_PROTECTED
+  } else if has::<RepositoryDamaged>(e) || has::<DamagedBlob>(e) || has::<ImageChecksumMismatch>(e)
+  {
+    EXIT_DAMAGED
+  } else if has::<SourceChanged>(e) {
+    EXIT_SOURCE_CHANGED
+  } else if has::<SourceIdentityChanged>(e) {
+    EXIT_IDENTITY_CHANGED
+  } else if has::<GuardTriggered>(e) {
+    EXIT_GUARD
+  } else {
+    EXIT_FAILURE
+  }
+}
+
+/// A progress bar on an interactive terminal, and optionally a stream of JSON progress events on
+/// stderr.
+pub struct Progress {
+  bar: ProgressBar,
+  phase: &'static str,
+  total: u64,
+  position: AtomicU64,
+  transferred: AtomicU64,
+  last_event: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+  pub fn new(phase: &'static str, total: u64, template: &str) -> Self {
+    let bar = if !is_json() && std::io::stderr().is_terminal() {
+      ProgressBar::new(total)
+    } else {
+      ProgressBar::hidden()
+    };
+    bar.set_style(
+      ProgressStyle::default_bar()
+        .template(template)
+        .progress_chars("#>-"),
+    );
+    Self {
+      bar,
+      phase,
+      total,
+      position: AtomicU64::new(0),
+      transferred: AtomicU64::new(0),
+      last_event: Mutex::new(None),
+    }
+  }
+
+  pub fn set_position(&self, position: u64) {
+    self.bar.set_position(position);
+    self.position.store(position, Ordering::Relaxed);
+    self.event(false);
+  }
+
+  /// Sets the number of bytes received from the remote.
+  pub fn set_transferred(&self, bytes: u64) {
+    self.transferred.store(bytes, Ordering::Relaxed);
+    self
+      .bar
+      .set_message(format!("fetched {}B", SizeFormatterBinary::new(bytes)));
+    self.event(false);
+  }
+
+  pub fn finish(&self) {
+    self.bar.finish();
+    self.position.store(self.total, Ordering::Relaxed);
+    self.event(true);
+  }
+
+  fn event(&self, force: bool) {
+    if !PROGRESS_EVENTS.load(Ordering::Relaxed) {
+      return;
+    }
+    let mut last_event = self.last_event.lock();
+    let now = Instant::now();
+    if !force && matches!(*last_event, Some(x) if now - x < PROGRESS_EVENT_INTERVAL) {
+      return;
+    }
+    *last_event = Some(now);
+    event(
+      "progress",
+      &json!({
+        "phase": self.phase,
+        "position": self.position.load(Ordering::Relaxed),
+        "total": self.total,
+        "transferred": self.transferred.load(Ordering::Relaxed),
+      }),
+    );
+  }
+}
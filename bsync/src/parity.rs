@@ -0,0 +1,28 @@
+use anyhow::Result;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Computes the parity shards of a group. Data shards are zero-padded to the longest one, and
+/// shards past `data.len()` are all zeros.
+pub fn encode(data_shards: usize, parity_shards: usize, data: &[&[u8]]) -> Result<Vec<Vec<u8>>> {
+  let shard_size = data.iter().map(|x| x.len()).max().unwrap_or(0);
+  let mut shards: Vec<Vec<u8>> = (0..data_shards + parity_shards)
+    .map(|i| {
+      let mut x = data.get(i).map(|x| x.to_vec()).unwrap_or_default();
+      x.resize(shard_size, 0);
+      x
+    })
+    .collect();
+  ReedSolomon::new(data_shards, parity_shards)?.encode(&mut shards)?;
+  Ok(shards.split_off(data_shards))
+}
+
+/// Rebuilds the missing data shards of a group from the others. `shards` holds the data shards,
+/// padded to the shard size, followed by the parity shards.
+pub fn reconstruct(
+  data_shards: usize,
+  parity_shards: usize,
+  shards: &mut [Option<Vec<u8>>],
+) -> Result<()> {
+  ReedSolomon::new(data_shards, parity_shards)?.reconstruct_data(shards)?;
+  Ok(())
+}
@@ -0,0 +1,297 @@
+use std::{
+  io::{BufRead, BufReader, Read},
+  net::{IpAddr, SocketAddr, TcpStream},
+  path::Path,
+  str::FromStr,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use socket2::{SockRef, TcpKeepalive};
+use ssh2::{Channel, CheckResult, KnownHostFileKind, Session};
+use thiserror::Error;
+
+use crate::{
+  config::{BackupRemoteConfig, HostVerification, RetryConfig},
+  interrupt,
+};
+
+#[derive(Error, Debug)]
+#[error("no host key")]
+pub struct NoHostKey;
+
+#[derive(Error, Debug)]
+#[error("host key verification error: {0}")]
+pub struct HostKeyVerifyError(&'static str);
+
+#[derive(Debug, Error)]
+#[error("remote returned error {0}")]
+pub struct RemoteError(pub i32);
+
+#[derive(Debug, Error)]
+#[error("remote script timed out after {0:?}")]
+pub struct ScriptTimeout(Duration);
+
+#[derive(Debug, Error)]
+#[error("deadline exceeded")]
+struct DeadlineExceeded;
+
+/// An SSH session to the backup source that can be re-established after a failure.
+pub struct Remote<'a> {
+  config: &'a BackupRemoteConfig,
+  sess: Session,
+}
+
+impl<'a> Remote<'a> {
+  pub fn connect(config: &'a BackupRemoteConfig) -> Result<Self> {
+    let sess = establish(config)?;
+    Ok(Self { config, sess })
+  }
+
+  /// Drops the current session and establishes a new one.
+  pub fn reconnect(&mut self) -> Result<()> {
+    log::info!("Reconnecting to {}.", self.config.server);
+    let old = std::mem::replace(&mut self.sess, establish(self.config)?);
+    // Don't wait for a possibly dead peer while tearing down the old session.
+    old.set_blocking(false);
+    Ok(())
+  }
+
+  pub fn session(&mut self) -> &mut Session {
+    &mut self.sess
+  }
+
+  /// Runs `f`, reconnecting and retrying with exponential backoff on transport, I/O and timeout
+  /// errors. Other errors, e.g. from transmit, and host key errors while reconnecting would happen
+  /// again on every attempt, so they are returned right away.
+  ///
+  /// `retries` is incremented once for each retried attempt.
+  pub fn with_retry<T>(
+    &mut self,
+    what: &str,
+    retries: &mut u64,
+    mut f: impl FnMut(&mut Self) -> Result<T>,
+  ) -> Result<T> {
+    let config: &'a BackupRemoteConfig = self.config;
+    let policy: &RetryConfig = &config.retry;
+    let mut backoff = Duration::from_millis(policy.initial_backoff_ms);
+    let max_backoff = Duration::from_millis(policy.max_backoff_ms);
+    let mut attempt: u32 = 0;
+    loop {
+      let e = match f(self) {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+      };
+      if attempt >= policy.max_retries || !is_transient(&e) {
+        return Err(e);
+      }
+      interrupt::check()?;
+      attempt += 1;
+      *retries += 1;
+      log::warn!(
+        "{} failed (attempt {}/{}): {:?} - retrying in {:?}",
+        what,
+        attempt,
+        policy.max_retries + 1,
+        e,
+        backoff
+      );
+      std::thread::sleep(backoff);
+      backoff = (backoff * 2).min(max_backoff);
+
+      // A failed reconnect is retried as part of the next attempt.
+      if let Err(e) = self.reconnect() {
+        if !is_transient(&e) {
+          return Err(e);
+        }
+        log::warn!("reconnect failed: {:?}", e);
+      }
+    }
+  }
+
+  /// Runs a user script. Scripts may legitimately stay silent for a long time, so instead of
+  /// `remote.timeout` they are bounded by `timeout` as a whole.
+  pub fn exec_script(&mut self, cmd: &str, timeout: Option<Duration>) -> Result<String> {
+    Ok(String::from_utf8(self.exec_script_bin(cmd, timeout)?)?)
+  }
+
+  /// Like `exec_script`, for scripts whose output isn't text.
+  pub fn exec_script_bin(&mut self, cmd: &str, timeout: Option<Duration>) -> Result<Vec<u8>> {
+    let timeout_ms = timeout
+      .map(|x| x.as_millis().min(u32::MAX as u128) as u32)
+      .unwrap_or(0);
+    let deadline = timeout.map(|x| Instant::now() + x);
+    self.sess.set_timeout(timeout_ms);
+    let ret = self
+      .sess
+      .channel_session()
+      .map_err(anyhow::Error::from)
+      .and_then(|mut channel| {
+        let ret = exec_oneshot_bin_in(&mut channel, cmd, |_| (), |x| Box::new(x), deadline);
+        if ret.is_err() {
+          // The script may still be running - don't wait for it while closing the channel.
+          self.sess.set_blocking(false);
+          drop(channel);
+          self.sess.set_blocking(true);
+        }
+        ret
+      });
+    self.sess.set_timeout(op_timeout_ms(self.config));
+    match (ret, timeout, deadline) {
+      (Err(_), Some(timeout), Some(deadline)) if Instant::now() >= deadline => {
+        Err(ScriptTimeout(timeout).into())
+      }
+      // A signal interrupts the wait on the socket.
+      (Err(e), _, _) => interrupt::check().and(Err(e)),
+      (ret, _, _) => ret,
+    }
+  }
+
+  pub fn exec(&mut self, cmd: &str) -> Result<String> {
+    let mut channel = self.sess.channel_session()?;
+    exec_oneshot_in(&mut channel, cmd)
+  }
+
+  pub fn exec_bin<D: for<'b> FnMut(&'b mut dyn Read) -> Box<dyn Read + 'b>>(
+    &mut self,
+    cmd: &str,
+    progress: impl FnMut(usize),
+    decoder_gen: D,
+  ) -> Result<Vec<u8>> {
+    let mut channel = self.sess.channel_session()?;
+    exec_oneshot_bin_in(&mut channel, cmd, progress, decoder_gen, None)
+  }
+
+  /// Sends a keepalive message if one is due.
+  pub fn keepalive(&mut self) -> Result<()> {
+    if self.config.keepalive_interval.is_some() {
+      self.sess.keepalive_send()?;
+    }
+    Ok(())
+  }
+}
+
+/// Whether `e` may not happen again on a new connection.
+fn is_transient(e: &anyhow::Error) -> bool {
+  e.chain()
+    .any(|x| x.is::<ssh2::Error>() || x.is::<std::io::Error>() || x.is::<DeadlineExceeded>())
+}
+
+fn op_timeout_ms(config: &BackupRemoteConfig) -> u32 {
+  config.timeout.unwrap_or(0).saturating_mul(1000)
+}
+
+fn establish(config: &BackupRemoteConfig) -> Result<Session> {
+  let addr = SocketAddr::new(IpAddr::from_str(&config.server)?, config.port.unwrap_or(22));
+  let tcp = match config.timeout {
+    Some(x) => TcpStream::connect_timeout(&addr, Duration::from_secs(x as u64))?,
+    None => TcpStream::connect(addr)?,
+  };
+  if let Some(x) = config.keepalive_interval {
+    // SSH keepalives are only sent between operations, so also let the kernel detect
+    // a dead peer while we are blocked on a read.
+    let interval = Duration::from_secs(x as u64);
+    SockRef::from(&tcp).set_tcp_keepalive(
+      &TcpKeepalive::new()
+        .with_time(interval)
+        .with_interval(interval),
+    )?;
+  }
+  let mut sess = Session::new()?;
+  sess.set_tcp_stream(tcp);
+  sess.set_timeout(op_timeout_ms(config));
+  sess.handshake()?;
+  if let Some(x) = config.keepalive_interval {
+    sess.set_keepalive(true, x);
+  }
+
+  let (host_key, _host_key_type) = sess.host_key().ok_or(NoHostKey)?;
+  match config.verify {
+    HostVerification::Insecure => {
+      log::warn!("`remote.verify` is set to `insecure`, skipping host key verification");
+    }
+    HostVerification::Known => {
+      let mut known_hosts = sess.known_hosts()?;
+      if let Some(home) = dirs::home_dir() {
+        let _ = known_hosts.read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH);
+      }
+      match known_hosts.check(&config.server, host_key) {
+        CheckResult::Match => {}
+        CheckResult::NotFound => {
+          return Err(
+            HostKeyVerifyError("not found - please connect to the remote host once").into(),
+          );
+        }
+        CheckResult::Mismatch => {
+          return Err(HostKeyVerifyError("mismatch - possible mitm").into());
+        }
+        CheckResult::Failure => {
+          return Err(HostKeyVerifyError("unknown").into());
+        }
+      }
+    }
+    HostVerification::Dnssec => {
+      return Err(HostKeyVerifyError("dnssec not yet implemented").into());
+    }
+  }
+
+  if let Some(x) = &config.key {
+    sess.userauth_pubkey_file(&config.user, None, Path::new(x), None)?;
+  } else {
+    sess.userauth_agent(&config.user)?;
+  }
+  Ok(sess)
+}
+
+fn exec_oneshot_in(channel: &mut Channel, cmd: &str) -> Result<String> {
+  exec_oneshot_bin_in(channel, cmd, |_| (), |x| Box::new(x), None)
+    .and_then(|x| String::from_utf8(x).map_err(anyhow::Error::from))
+}
+
+fn exec_oneshot_bin_in<D: for<'a> FnMut(&'a mut dyn Read) -> Box<dyn Read + 'a>>(
+  channel: &mut Channel,
+  cmd: &str,
+  mut progress: impl FnMut(usize),
+  mut decoder_gen: D,
+  deadline: Option<Instant>,
+) -> Result<Vec<u8>> {
+  channel.exec(cmd)?;
+  let mut data = Vec::new();
+  {
+    let mut reader = decoder_gen(&mut *channel);
+    let mut reader = BufReader::new(&mut *reader);
+    loop {
+      let buf = reader.fill_buf()?;
+      if buf.is_empty() {
+        break;
+      }
+      data.extend_from_slice(buf);
+      let len = buf.len();
+      reader.consume(len);
+      progress(len);
+      if deadline.map(|x| Instant::now() >= x).unwrap_or(false) {
+        return Err(DeadlineExceeded.into());
+      }
+    }
+  }
+  channel.wait_close()?;
+
+  let sig = channel.exit_signal()?;
+  let status = channel.exit_status()?;
+  let mut msg = String::new();
+  channel.stderr().read_to_string(&mut msg)?;
+
+  // We get `status == 0` if the program is killed by a signal - so do another check here.
+  if let Some(sig) = sig.exit_signal {
+    log::error!("remote signal: {}, stderr: {}", sig, msg);
+    return Err(RemoteError(1).into());
+  }
+
+  if status != 0 {
+    log::error!("remote returned error {}, stderr: {}", status, msg);
+    return Err(RemoteError(status).into());
+  }
+
+  log::debug!("remote stderr: {}", msg);
+  Ok(data)
+}
@@ -0,0 +1,257 @@
+use chrono::{Local, TimeZone};
+
+use crate::{config::RetentionConfig, db::ConsistentPoint};
+
+impl RetentionConfig {
+  /// Whether the policy keeps anything besides the latest version.
+  pub fn is_empty(&self) -> bool {
+    self.keep_last == 0
+      && self.keep_hourly == 0
+      && self.keep_daily == 0
+      && self.keep_weekly == 0
+      && self.keep_monthly == 0
+      && self.keep_yearly == 0
+  }
+}
+
+/// Returns, for each consistent point in `cp_list` (sorted by LSN), the rules that keep it. A
+/// consistent point without any rule is removed by the policy.
+///
+/// Versions marked suspect by the guard are only kept if they are the latest, and don't take the
+/// place of a good version in any rule.
+pub fn keep_reasons(
+  policy: &RetentionConfig,
+  cp_list: &[ConsistentPoint],
+) -> Vec<Vec<&'static str>> {
+  let mut reasons = vec![vec![]; cp_list.len()];
+  if let Some(x) = reasons.last_mut() {
+    x.push("latest");
+  }
+  for (x, _) in reasons
+    .iter_mut()
+    .zip(cp_list)
+    .rev()
+    .filter(|(_, cp)| cp.suspect.is_none())
+    .take(policy.keep_last as usize)
+  {
+    x.push("last");
+  }
+
+  let periods = [
+    ("hourly", policy.keep_hourly, "%Y-%m-%d %H"),
+    ("daily", policy.keep_daily, "%Y-%m-%d"),
+    ("weekly", policy.keep_weekly, "%G-%V"),
+    ("monthly", policy.keep_monthly, "%Y-%m"),
+    ("yearly", policy.keep_yearly, "%Y"),
+  ];
+  for (name, count, format) in periods {
+    let mut kept = 0;
+    let mut last_period: Option<String> = None;
+    // Newest first, so that the newest version of each period is kept.
+    for (i, cp) in cp_list.iter().enumerate().rev() {
+      if kept >= count {
+        break;
+      }
+      if cp.suspect.is_some() {
+        continue;
+      }
+      let period = Local
+        .timestamp(cp.created_at as i64, 0)
+        .format(format)
+        .to_string();
+      if last_period.as_ref() != Some(&period) {
+        reasons[i].push(name);
+        kept += 1;
+        last_period = Some(period);
+      }
+    }
+  }
+  reasons
+}
+
+/// Returns the `(start_lsn, end_lsn)` pairs to pass to `Database::squash` so that only the
+/// consistent points with `keep` set are left. Each run of removed consistent points takes one
+/// squash.
+pub fn squash_ranges(cp_list: &[ConsistentPoint], keep: &[bool]) -> Vec<(u64, u64)> {
+  let mut ranges = vec![];
+  let mut prev_kept = 0;
+  let mut removed_since = false;
+  for (cp, keep) in cp_list.iter().zip(keep) {
+    if *keep {
+      if removed_since {
+        ranges.push((prev_kept, cp.lsn));
+      }
+      prev_kept = cp.lsn;
+      removed_since = false;
+    } else {
+      removed_since = true;
+    }
+  }
+  ranges
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(yaml: &str) -> RetentionConfig {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  /// A consistent point created at the given local time.
+  fn cp(lsn: u64, (y, m, d, h): (i32, u32, u32, u32)) -> ConsistentPoint {
+    ConsistentPoint {
+      lsn,
+      size: 0,
+      created_at: Local.ymd(y, m, d).and_hms(h, 0, 0).timestamp() as u64,
+      sha256: None,
+      fuzzy: false,
+      suspect: None,
+    }
+  }
+
+  fn suspect(mut cp: ConsistentPoint) -> ConsistentPoint {
+    cp.suspect = Some("test".into());
+    cp
+  }
+
+  /// The LSNs kept by `policy`, with their reasons.
+  fn kept(policy: &RetentionConfig, cp_list: &[ConsistentPoint]) -> Vec<(u64, String)> {
+    keep_reasons(policy, cp_list)
+      .into_iter()
+      .zip(cp_list)
+      .filter(|(reasons, _)| !reasons.is_empty())
+      .map(|(reasons, cp)| (cp.lsn, reasons.join(",")))
+      .collect()
+  }
+
+  fn kept_lsns(policy: &RetentionConfig, cp_list: &[ConsistentPoint]) -> Vec<u64> {
+    kept(policy, cp_list).into_iter().map(|x| x.0).collect()
+  }
+
+  #[test]
+  fn latest_is_always_kept() {
+    let empty = policy("{}");
+    assert!(empty.is_empty());
+    assert!(keep_reasons(&empty, &[]).is_empty());
+    let cp_list = [cp(1, (2026, 1, 1, 0)), cp(2, (2026, 1, 2, 0))];
+    assert_eq!(kept(&empty, &cp_list), [(2, "latest".to_string())]);
+    // Even if it's suspect.
+    let cp_list = [cp(1, (2026, 1, 1, 0)), suspect(cp(2, (2026, 1, 2, 0)))];
+    assert_eq!(kept(&empty, &cp_list), [(2, "latest".to_string())]);
+    assert!(!policy("keep_yearly: 1").is_empty());
+  }
+
+  #[test]
+  fn keep_last() {
+    let cp_list: Vec<_> = (1..=5)
+      .map(|i| cp(i * 10, (2026, 1, 1, i as u32)))
+      .collect();
+    assert_eq!(
+      kept(&policy("keep_last: 2"), &cp_list),
+      [(40, "last".to_string()), (50, "latest,last".to_string())]
+    );
+    assert_eq!(
+      kept_lsns(&policy("keep_last: 10"), &cp_list),
+      [10, 20, 30, 40, 50]
+    );
+  }
+
+  #[test]
+  fn keep_daily() {
+    let cp_list = [
+      cp(1, (2026, 3, 1, 8)),
+      cp(2, (2026, 3, 1, 20)),
+      cp(3, (2026, 3, 2, 8)),
+      cp(4, (2026, 3, 3, 8)),
+      cp(5, (2026, 3, 3, 12)),
+      cp(6, (2026, 3, 3, 20)),
+      // No version on the 4th - days without versions don't count.
+      cp(7, (2026, 3, 5, 1)),
+    ];
+    // The newest version of each of the last 3 days with versions.
+    assert_eq!(
+      kept(&policy("keep_daily: 3"), &cp_list),
+      [
+        (3, "daily".to_string()),
+        (6, "daily".to_string()),
+        (7, "latest,daily".to_string()),
+      ]
+    );
+    assert_eq!(kept_lsns(&policy("keep_daily: 30"), &cp_list), [2, 3, 6, 7]);
+  }
+
+  #[test]
+  fn keep_weekly_starts_on_monday() {
+    let cp_list = [
+      // Week 1 of 2026 runs from Monday 2025-12-29 to Sunday 2026-01-04.
+      cp(1, (2025, 12, 29, 12)),
+      cp(2, (2026, 1, 4, 12)),
+      // Week 2.
+      cp(3, (2026, 1, 5, 12)),
+      cp(4, (2026, 1, 10, 12)),
+      cp(5, (2026, 1, 11, 12)),
+      // Week 3.
+      cp(6, (2026, 1, 12, 0)),
+    ];
+    assert_eq!(kept_lsns(&policy("keep_weekly: 2"), &cp_list), [5, 6]);
+    assert_eq!(kept_lsns(&policy("keep_weekly: 3"), &cp_list), [2, 5, 6]);
+  }
+
+  #[test]
+  fn rules_combine() {
+    let cp_list = [
+      cp(1, (2025, 11, 20, 12)),
+      cp(2, (2025, 12, 20, 12)),
+      cp(3, (2026, 1, 1, 12)),
+      cp(4, (2026, 1, 2, 12)),
+      cp(5, (2026, 1, 2, 13)),
+    ];
+    assert_eq!(
+      kept(
+        &policy("{keep_last: 1, keep_daily: 2, keep_monthly: 3, keep_yearly: 2}"),
+        &cp_list
+      ),
+      [
+        (1, "monthly".to_string()),
+        (2, "monthly,yearly".to_string()),
+        (3, "daily".to_string()),
+        (5, "latest,last,daily,monthly,yearly".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn suspect_versions_are_skipped() {
+    let cp_list = [
+      cp(1, (2026, 3, 1, 8)),
+      cp(2, (2026, 3, 2, 8)),
+      suspect(cp(3, (2026, 3, 3, 8))),
+      suspect(cp(4, (2026, 3, 3, 9))),
+      cp(5, (2026, 3, 4, 8)),
+    ];
+    assert_eq!(kept_lsns(&policy("keep_last: 3"), &cp_list), [1, 2, 5]);
+    // The 3rd only has suspect versions, so the 1st takes its place.
+    assert_eq!(kept_lsns(&policy("keep_daily: 3"), &cp_list), [1, 2, 5]);
+  }
+
+  #[test]
+  fn ranges() {
+    let cp_list: Vec<_> = (1..=8)
+      .map(|i| cp(i * 10, (2026, 1, 1, i as u32)))
+      .collect();
+    let keep = |x: &str| x.chars().map(|c| c == 'k').collect::<Vec<_>>();
+    assert_eq!(squash_ranges(&cp_list, &keep("kkkkkkkk")), []);
+    assert_eq!(
+      squash_ranges(&cp_list, &keep("k--k-k-k")),
+      [(10, 40), (40, 60), (60, 80)]
+    );
+    assert_eq!(squash_ranges(&cp_list, &keep("-------k")), [(0, 80)]);
+    assert_eq!(
+      squash_ranges(&cp_list, &keep("-k-----k")),
+      [(0, 20), (20, 80)]
+    );
+    // Versions after the last kept one are not squashed.
+    assert_eq!(squash_ranges(&cp_list, &keep("k-k-----")), [(10, 30)]);
+  }
+}
@@ -0,0 +1,348 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("invalid schedule `{0}`: {1}")]
+pub struct InvalidSchedule(String, &'static str);
+
+/// A cron-style schedule: `minute hour day-of-month month day-of-week`.
+///
+/// Each field is `*`, a number, a range `a-b`, or a comma-separated list of these, optionally
+/// followed by a step `/n`. Day-of-week is 0-7 where both 0 and 7 are Sunday. As in cron, if both
+/// day-of-month and day-of-week are restricted, a day matches if either matches. `@hourly`,
+/// `@daily`, `@weekly` and `@monthly` are accepted as shorthands.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+  minutes: u64,
+  hours: u64,
+  days_of_month: u64,
+  months: u64,
+  days_of_week: u64,
+  dom_restricted: bool,
+  dow_restricted: bool,
+}
+
+impl FromStr for Schedule {
+  type Err = InvalidSchedule;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let expr = match s.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      x => x,
+    };
+    let err = |msg| InvalidSchedule(s.to_string(), msg);
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    if fields.len() != 5 {
+      return Err(err("expecting 5 fields"));
+    }
+    let mut days_of_week = parse_field(fields[4], 0, 7).ok_or_else(|| err("bad day of week"))?;
+    // Sunday is both 0 and 7.
+    if days_of_week & (1 << 7) != 0 {
+      days_of_week |= 1;
+    }
+    Ok(Self {
+      minutes: parse_field(fields[0], 0, 59).ok_or_else(|| err("bad minute"))?,
+      hours: parse_field(fields[1], 0, 23).ok_or_else(|| err("bad hour"))?,
+      days_of_month: parse_field(fields[2], 1, 31).ok_or_else(|| err("bad day of month"))?,
+      months: parse_field(fields[3], 1, 12).ok_or_else(|| err("bad month"))?,
+      days_of_week,
+      dom_restricted: fields[2] != "*",
+      dow_restricted: fields[4] != "*",
+    })
+  }
+}
+
+impl Schedule {
+  /// Returns the first matching minute strictly after `t`, in local wall clock time.
+  pub fn next_after(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+    let mut t = t.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+    // Every valid schedule matches at least once in 4 years (Feb 29).
+    let limit = t + Duration::days(366 * 4 + 1);
+    while t < limit {
+      if !self.day_matches(t.date()) {
+        t = (t.date() + Duration::days(1)).and_hms(0, 0, 0);
+      } else if self.hours & (1 << t.hour()) == 0 {
+        t = t.with_minute(0)? + Duration::hours(1);
+      } else if self.minutes & (1 << t.minute()) == 0 {
+        t += Duration::minutes(1);
+      } else {
+        return Some(t);
+      }
+    }
+    None
+  }
+
+  fn day_matches(&self, d: NaiveDate) -> bool {
+    if self.months & (1 << d.month()) == 0 {
+      return false;
+    }
+    let dom = self.days_of_month & (1 << d.day()) != 0;
+    let dow = self.days_of_week & (1 << d.weekday().num_days_from_sunday()) != 0;
+    match (self.dom_restricted, self.dow_restricted) {
+      (true, true) => dom || dow,
+      (true, false) => dom,
+      (false, true) => dow,
+      (false, false) => true,
+    }
+  }
+}
+
+/// Parses a cron field into a bitmask of the matching values.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+  let mut mask = 0u64;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u32>().ok().filter(|x| *x > 0)?),
+      None => (part, 1),
+    };
+    let (start, end) = if range == "*" {
+      (min, max)
+    } else if let Some((a, b)) = range.split_once('-') {
+      (a.parse().ok()?, b.parse().ok()?)
+    } else {
+      let x = range.parse().ok()?;
+      // `5/15` means "from 5 to the end, every 15".
+      (x, if step > 1 { max } else { x })
+    };
+    if start < min || end > max || start > end {
+      return None;
+    }
+    for x in (start..=end).step_by(step as usize) {
+      mask |= 1 << x;
+    }
+  }
+  Some(mask)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn dt(s: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+  }
+
+  fn next(expr: &str, after: &str) -> Option<NaiveDateTime> {
+    expr.parse::<Schedule>().unwrap().next_after(dt(after))
+  }
+
+  /// The first `n` matches after `after`.
+  fn upcoming(expr: &str, after: &str, n: usize) -> Vec<String> {
+    let schedule: Schedule = expr.parse().unwrap();
+    let mut t = dt(after);
+    (0..n)
+      .map(|_| {
+        t = schedule.next_after(t).unwrap();
+        t.format("%Y-%m-%d %H:%M").to_string()
+      })
+      .collect()
+  }
+
+  fn mask(values: &[u32]) -> u64 {
+    values.iter().map(|x| 1 << x).sum()
+  }
+
+  #[test]
+  fn field_syntax() {
+    assert_eq!(parse_field("*", 0, 5), Some(mask(&[0, 1, 2, 3, 4, 5])));
+    assert_eq!(parse_field("3", 0, 59), Some(mask(&[3])));
+    assert_eq!(parse_field("2-4", 0, 59), Some(mask(&[2, 3, 4])));
+    assert_eq!(parse_field("1,5,9", 0, 59), Some(mask(&[1, 5, 9])));
+    assert_eq!(parse_field("*/15", 0, 59), Some(mask(&[0, 15, 30, 45])));
+    assert_eq!(parse_field("10-20/5", 0, 59), Some(mask(&[10, 15, 20])));
+    assert_eq!(parse_field("50/3", 0, 59), Some(mask(&[50, 53, 56, 59])));
+    assert_eq!(
+      parse_field("1-2,7,20-30/10", 0, 59),
+      Some(mask(&[1, 2, 7, 20, 30]))
+    );
+    assert_eq!(parse_field("*/1", 1, 3), Some(mask(&[1, 2, 3])));
+    assert_eq!(parse_field("*/5", 1, 12), Some(mask(&[1, 6, 11])));
+  }
+
+  #[test]
+  fn sunday_is_0_and_7() {
+    let a: Schedule = "0 0 * * 0".parse().unwrap();
+    let b: Schedule = "0 0 * * 7".parse().unwrap();
+    assert_eq!(a.days_of_week & 1, 1);
+    assert_eq!(b.days_of_week & 1, 1);
+    // 2026-01-04 is a Sunday.
+    assert_eq!(
+      a.next_after(dt("2026-01-01 00:00")),
+      Some(dt("2026-01-04 00:00"))
+    );
+    assert_eq!(
+      b.next_after(dt("2026-01-01 00:00")),
+      Some(dt("2026-01-04 00:00"))
+    );
+  }
+
+  #[test]
+  fn shorthands() {
+    let parse = |x: &str| x.parse::<Schedule>().unwrap();
+    assert_eq!(parse("@hourly"), parse("0 * * * *"));
+    assert_eq!(parse("@daily"), parse("0 0 * * *"));
+    assert_eq!(parse("@midnight"), parse("0 0 * * *"));
+    assert_eq!(parse("@weekly"), parse("0 0 * * 0"));
+    assert_eq!(parse(" @monthly "), parse("0 0 1 * *"));
+    assert_eq!(parse("  5   4 * *  1 "), parse("5 4 * * 1"));
+  }
+
+  #[test]
+  fn invalid() {
+    for (expr, msg) in [
+      ("", "expecting 5 fields"),
+      ("* * * *", "expecting 5 fields"),
+      ("* * * * * *", "expecting 5 fields"),
+      ("@yearly", "expecting 5 fields"),
+      ("60 * * * *", "bad minute"),
+      ("a * * * *", "bad minute"),
+      ("-1 * * * *", "bad minute"),
+      ("1,,2 * * * *", "bad minute"),
+      ("*/0 * * * *", "bad minute"),
+      ("*/ * * * *", "bad minute"),
+      ("5-3 * * * *", "bad minute"),
+      ("1-2-3 * * * *", "bad minute"),
+      ("* 24 * * *", "bad hour"),
+      ("* * 0 * *", "bad day of month"),
+      ("* * 32 * *", "bad day of month"),
+      ("* * * 0 *", "bad month"),
+      ("* * * 13 *", "bad month"),
+      ("* * * jan *", "bad month"),
+      ("* * * * 8", "bad day of week"),
+      ("* * * * mon", "bad day of week"),
+    ] {
+      let e = expr.parse::<Schedule>().unwrap_err();
+      assert_eq!(e.1, msg, "{:?}", expr);
+      assert_eq!(e.0, expr);
+    }
+  }
+
+  #[test]
+  fn next_is_strictly_after() {
+    assert_eq!(
+      next("* * * * *", "2026-05-01 10:00"),
+      Some(dt("2026-05-01 10:01"))
+    );
+    assert_eq!(
+      next("0 * * * *", "2026-05-01 10:00"),
+      Some(dt("2026-05-01 11:00"))
+    );
+    // Seconds are dropped before adding a minute.
+    let t = dt("2026-05-01 10:00").with_second(59).unwrap();
+    let schedule: Schedule = "* * * * *".parse().unwrap();
+    assert_eq!(schedule.next_after(t), Some(dt("2026-05-01 10:01")));
+  }
+
+  #[test]
+  fn steps_and_lists() {
+    assert_eq!(
+      upcoming("*/20 9-10 * * *", "2026-05-01 08:59", 7),
+      [
+        "2026-05-01 09:00",
+        "2026-05-01 09:20",
+        "2026-05-01 09:40",
+        "2026-05-01 10:00",
+        "2026-05-01 10:20",
+        "2026-05-01 10:40",
+        "2026-05-02 09:00",
+      ]
+    );
+    assert_eq!(
+      upcoming("15 3 * 1,7 *", "2026-01-31 04:00", 2),
+      ["2026-07-01 03:15", "2026-07-02 03:15"]
+    );
+  }
+
+  #[test]
+  fn month_end_rollover() {
+    assert_eq!(
+      upcoming("0 0 31 * *", "2026-01-31 00:00", 4),
+      [
+        "2026-03-31 00:00",
+        "2026-05-31 00:00",
+        "2026-07-31 00:00",
+        "2026-08-31 00:00",
+      ]
+    );
+    assert_eq!(
+      upcoming("59 23 31 12 *", "2026-12-31 23:59", 1),
+      ["2027-12-31 23:59"]
+    );
+    assert_eq!(
+      upcoming("0 12 * * *", "2026-02-28 12:00", 2),
+      ["2026-03-01 12:00", "2026-03-02 12:00"]
+    );
+    // Leap days only exist every 4 years.
+    assert_eq!(
+      upcoming("0 0 29 2 *", "2026-01-01 00:00", 2),
+      ["2028-02-29 00:00", "2032-02-29 00:00"]
+    );
+    // Valid fields, but the date never exists.
+    assert_eq!(next("0 0 30 2 *", "2026-01-01 00:00"), None);
+    assert_eq!(next("0 0 31 4,6,9,11 *", "2026-01-01 00:00"), None);
+  }
+
+  #[test]
+  fn day_of_month_or_day_of_week() {
+    // Both restricted: the 13th or any Friday. 2026-02-13 is both.
+    assert_eq!(
+      upcoming("0 0 13 * 5", "2026-02-01 00:00", 5),
+      [
+        "2026-02-06 00:00",
+        "2026-02-13 00:00",
+        "2026-02-20 00:00",
+        "2026-02-27 00:00",
+        "2026-03-06 00:00",
+      ]
+    );
+    assert_eq!(
+      upcoming("0 0 13 * 5", "2026-03-06 00:00", 2),
+      ["2026-03-13 00:00", "2026-03-20 00:00"]
+    );
+    // Only one restricted: that one alone decides.
+    assert_eq!(
+      upcoming("0 0 13 * *", "2026-02-01 00:00", 2),
+      ["2026-02-13 00:00", "2026-03-13 00:00"]
+    );
+    assert_eq!(
+      upcoming("0 0 * * 5", "2026-02-01 00:00", 2),
+      ["2026-02-06 00:00", "2026-02-13 00:00"]
+    );
+    // The month applies to both.
+    assert_eq!(
+      upcoming("0 0 1 3 1", "2026-02-01 00:00", 3),
+      ["2026-03-01 00:00", "2026-03-02 00:00", "2026-03-09 00:00"]
+    );
+  }
+
+  #[test]
+  fn dst_uses_wall_clock_time() {
+    // Schedules are in local wall clock time, and the daemon starts a job once the local time is at
+    // or after its next run. On the day clocks go forward (2026-03-29 in the EU, 02:00 -> 03:00),
+    // 02:30 never happens, so the job runs once at 03:00 and then on the next day.
+    assert_eq!(
+      next("30 2 * * *", "2026-03-29 01:59"),
+      Some(dt("2026-03-29 02:30"))
+    );
+    assert_eq!(
+      next("30 2 * * *", "2026-03-29 03:00"),
+      Some(dt("2026-03-30 02:30"))
+    );
+    // On the day clocks go back (2026-10-25, 03:00 -> 02:00), 02:30 happens twice. The next run
+    // is computed from the time the pull finished, so the job runs only the first time.
+    assert_eq!(
+      next("30 2 * * *", "2026-10-25 02:31"),
+      Some(dt("2026-10-26 02:30"))
+    );
+    // Hourly schedules don't run twice in the repeated hour either.
+    assert_eq!(
+      upcoming("0 * * * *", "2026-10-25 01:30", 2),
+      ["2026-10-25 02:00", "2026-10-25 03:00"]
+    );
+  }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+use serde::Serialize;
+use shell_escape::unix::escape;
+use thiserror::Error;
+
+use crate::{
+  config::{IdentityField, LOG_BLOCK_SIZE},
+  remote::Remote,
+};
+
+#[derive(Error, Debug)]
+#[error("bad source info from remote: {0}")]
+struct BadSourceInfo(String);
+
+#[derive(Error, Debug)]
+#[error("the remote image is not the device of version {0}: {1}")]
+pub struct SourceIdentityChanged(pub u64, pub String);
+
+/// Size, geometry and identity of the remote image, as reported by transmit. Fields that aren't
+/// available, e.g. because the image is a regular file, are `None`.
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct SourceInfo {
+  pub size: u64,
+  pub logical_sector_size: Option<u32>,
+  pub physical_sector_size: Option<u32>,
+  /// World Wide Identifier of the disk.
+  pub wwid: Option<String>,
+  /// Serial number of the disk.
+  pub serial: Option<String>,
+  /// Partition number, if the image is a partition.
+  pub partition: Option<u32>,
+  /// UUID of the LUKS header, partition table or filesystem at the start of the image, prefixed
+  /// with its type, e.g. `luks:` or `ext:`.
+  pub uuid: Option<String>,
+  /// Device mapper name, e.g. `vg0-data` for an LVM volume.
+  pub dm_name: Option<String>,
+}
+
+impl SourceInfo {
+  /// Asks transmit for the info of `image`.
+  pub fn fetch(sess: &mut Remote, transmit_filename: &str, image: &str) -> Result<Self> {
+    let output = sess.exec(&format!(
+      "~/.bsync/{} {} {} info",
+      escape(Cow::Borrowed(transmit_filename)),
+      escape(Cow::Borrowed(image)),
+      LOG_BLOCK_SIZE,
+    ))?;
+    let info = Self::parse(&output)?;
+    log::info!("Remote image size is {} bytes.", info.size);
+    Ok(info)
+  }
+
+  fn parse(output: &str) -> Result<Self> {
+    let mut info = Self::default();
+    let mut has_size = false;
+    for line in output.lines() {
+      let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| BadSourceInfo(line.to_string()))?;
+      let bad = |_| BadSourceInfo(line.to_string());
+      match key {
+        "size" => {
+          info.size = value.parse().map_err(bad)?;
+          has_size = true;
+        }
+        "logical_sector_size" => info.logical_sector_size = Some(value.parse().map_err(bad)?),
+        "physical_sector_size" => info.physical_sector_size = Some(value.parse().map_err(bad)?),
+        "wwid" => info.wwid = Some(value.to_string()),
+        "serial" => info.serial = Some(value.to_string()),
+        "partition" => info.partition = Some(value.parse().map_err(bad)?),
+        "uuid" => info.uuid = Some(value.to_string()),
+        "dm_name" => info.dm_name = Some(value.to_string()),
+        // Keys added by newer versions of transmit.
+        _ => {}
+      }
+    }
+    if !has_size {
+      return Err(BadSourceInfo(output.to_string()).into());
+    }
+    Ok(info)
+  }
+
+  /// Describes how the identity of the image differs from `prev`. A field only counts if both
+  /// have it and it isn't in `ignore`. The size isn't part of the identity.
+  pub fn identity_changes(&self, prev: &SourceInfo, ignore: &[IdentityField]) -> Vec<String> {
+    fn check<T: PartialEq + std::fmt::Display>(
+      out: &mut Vec<String>,
+      name: &str,
+      prev: &Option<T>,
+      cur: &Option<T>,
+    ) {
+      if let (Some(prev), Some(cur)) = (prev, cur) {
+        if prev != cur {
+          out.push(format!("{} changed from {} to {}", name, prev, cur));
+        }
+      }
+    }
+
+    let mut out = vec![];
+    let fields: [(IdentityField, &str, &Option<String>, &Option<String>); 4] = [
+      (IdentityField::Wwid, "wwid", &prev.wwid, &self.wwid),
+      (IdentityField::Serial, "serial", &prev.serial, &self.serial),
+      (IdentityField::Uuid, "uuid", &prev.uuid, &self.uuid),
+      (
+        IdentityField::DmName,
+        "dm_name",
+        &prev.dm_name,
+        &self.dm_name,
+      ),
+    ];
+    for (field, name, prev, cur) in fields.iter() {
+      if !ignore.contains(field) {
+        check(&mut out, name, prev, cur);
+      }
+    }
+    if !ignore.contains(&IdentityField::Partition) {
+      check(&mut out, "partition", &prev.partition, &self.partition);
+    }
+    if !ignore.contains(&IdentityField::SectorSize) {
+      check(
+        &mut out,
+        "logical_sector_size",
+        &prev.logical_sector_size,
+        &self.logical_sector_size,
+      );
+      check(
+        &mut out,
+        "physical_sector_size",
+        &prev.physical_sector_size,
+        &self.physical_sector_size,
+      );
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn disk() -> SourceInfo {
+    SourceInfo {
+      size: 1 << 30,
+      logical_sector_size: Some(512),
+      physical_sector_size: Some(4096),
+      wwid: Some("naa.5000c500a1b2c3d4".to_string()),
+      serial: Some("ZA1B2C3D".to_string()),
+      partition: Some(2),
+      uuid: Some("ext:01234567-89ab-cdef-fedc-ba9876543210".to_string()),
+      dm_name: Some("vg0-data".to_string()),
+    }
+  }
+
+  #[test]
+  fn parse_all_keys() {
+    let output = "size=1073741824
+logical_sector_size=512
+physical_sector_size=4096
+wwid=naa.5000c500a1b2c3d4
+serial=ZA1B2C3D
+partition=2
+uuid=ext:01234567-89ab-cdef-fedc-ba9876543210
+dm_name=vg0-data
+";
+    assert_eq!(SourceInfo::parse(output).unwrap(), disk());
+  }
+
+  #[test]
+  fn parse_file() {
+    // A regular file only has a size, and maybe a UUID.
+    let info = SourceInfo::parse("size=4096\n").unwrap();
+    assert_eq!(
+      info,
+      SourceInfo {
+        size: 4096,
+        ..Default::default()
+      }
+    );
+  }
+
+  #[test]
+  fn parse_unknown_keys() {
+    let info = SourceInfo::parse("rotational=0\nsize=10\nmodel=a=b\n").unwrap();
+    assert_eq!(info.size, 10);
+  }
+
+  #[test]
+  fn parse_values_with_equals_signs() {
+    let info = SourceInfo::parse("size=10\nserial=a=b\n").unwrap();
+    assert_eq!(info.serial.as_deref(), Some("a=b"));
+  }
+
+  #[test]
+  fn parse_errors() {
+    for output in [
+      "",
+      "logical_sector_size=512\n",
+      "size=abc\n",
+      "size=-1\n",
+      "size=10\npartition=x\n",
+      "size=10\nlogical_sector_size=5000000000\n",
+      "size=10\ngarbage\n",
+    ] {
+      assert!(SourceInfo::parse(output).is_err(), "{:?}", output);
+    }
+  }
+
+  #[test]
+  fn same_device() {
+    assert!(disk().identity_changes(&disk(), &[]).is_empty());
+    // The size isn't part of the identity.
+    let resized = SourceInfo {
+      size: 1 << 31,
+      ..disk()
+    };
+    assert!(resized.identity_changes(&disk(), &[]).is_empty());
+  }
+
+  #[test]
+  fn changed_fields() {
+    let other = SourceInfo {
+      uuid: Some("ext:ffffffff-89ab-cdef-fedc-ba9876543210".to_string()),
+      partition: Some(3),
+      physical_sector_size: Some(512),
+      ..disk()
+    };
+    assert_eq!(
+      other.identity_changes(&disk(), &[]),
+      [
+        "uuid changed from ext:01234567-89ab-cdef-fedc-ba9876543210 to \
+         ext:ffffffff-89ab-cdef-fedc-ba9876543210",
+        "partition changed from 2 to 3",
+        "physical_sector_size changed from 4096 to 512",
+      ]
+    );
+  }
+
+  #[test]
+  fn missing_fields_are_not_changes() {
+    // E.g. the previous version was pulled by an older transmit, or the image became a file.
+    let file = SourceInfo {
+      size: 1 << 30,
+      ..Default::default()
+    };
+    assert!(file.identity_changes(&disk(), &[]).is_empty());
+    assert!(disk().identity_changes(&file, &[]).is_empty());
+  }
+
+  #[test]
+  fn ignored_fields() {
+    let other = SourceInfo {
+      wwid: Some("naa.1".to_string()),
+      serial: Some("X".to_string()),
+      partition: Some(1),
+      uuid: Some("xfs:00000000-0000-0000-0000-000000000000".to_string()),
+      dm_name: Some("vg1-data".to_string()),
+      logical_sector_size: Some(4096),
+      physical_sector_size: Some(512),
+      ..disk()
+    };
+    assert_eq!(other.identity_changes(&disk(), &[]).len(), 7);
+    let all = [
+      IdentityField::Wwid,
+      IdentityField::Serial,
+      IdentityField::Partition,
+      IdentityField::Uuid,
+      IdentityField::DmName,
+      IdentityField::SectorSize,
+    ];
+    assert!(other.identity_changes(&disk(), &all).is_empty());
+    assert_eq!(
+      other.identity_changes(&disk(), &all[1..]),
+      ["wwid changed from naa.5000c500a1b2c3d4 to naa.1"]
+    );
+    // Both sector sizes are ignored together.
+    assert_eq!(
+      other
+        .identity_changes(&disk(), &[IdentityField::SectorSize])
+        .len(),
+      5
+    );
+  }
+}
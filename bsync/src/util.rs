@@ -1,11 +1,14 @@
-use std::borrow::Cow;
+use std::{
+  borrow::Cow,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
 use sha2::{Digest, Sha256};
 
 use crate::config::LOG_BLOCK_SIZE;
 
-pub fn align_block(data: &[u8]) -> Cow<[u8]> {
-  let block_size = LOG_BLOCK_SIZE as usize;
+pub fn align_block(data: &[u8]) -> Cow<'_, [u8]> {
+  let block_size = LOG_BLOCK_SIZE;
   assert!(data.len() <= block_size);
   if data.len() < block_size {
     log::debug!(
@@ -15,7 +18,7 @@ pub fn align_block(data: &[u8]) -> Cow<[u8]> {
     );
     let mut v = Vec::with_capacity(block_size);
     v.extend_from_slice(data);
-    v.extend(std::iter::repeat(0u8).take(block_size - data.len()));
+    v.extend(std::iter::repeat_n(0u8, block_size - data.len()));
     Cow::Owned(v)
   } else {
     Cow::Borrowed(data)
@@ -27,3 +30,56 @@ pub fn sha256hash(data: &[u8]) -> [u8; 32] {
   h.update(data);
   h.finalize().into()
 }
+
+/// Current time in seconds since the epoch.
+pub fn unix_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs()
+}
+
+pub fn hostname() -> String {
+  let mut buf = [0u8; 256];
+  let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+  if ret != 0 {
+    return "unknown".into();
+  }
+  let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+  String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Current time in milliseconds since the epoch.
+pub fn unix_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as u64
+}
+
+/// Merges sorted block IDs into inclusive `(first, last)` ranges.
+pub fn block_ranges(ids: &[u64]) -> Vec<(u64, u64)> {
+  let mut out: Vec<(u64, u64)> = vec![];
+  for &id in ids {
+    match out.last_mut() {
+      Some(x) if x.1 + 1 == id => x.1 = id,
+      _ => out.push((id, id)),
+    }
+  }
+  out
+}
+
+/// Formats ranges from `block_ranges` like `3, 7-9`.
+pub fn format_ranges(ranges: &[(u64, u64)]) -> String {
+  ranges
+    .iter()
+    .map(|&(a, b)| {
+      if a == b {
+        a.to_string()
+      } else {
+        format!("{}-{}", a, b)
+      }
+    })
+    .collect::<Vec<_>>()
+    .join(", ")
+}
@@ -38,6 +38,7 @@ remote:
   key: ./id_ed25519
   verify: insecure
   image: /root/test.img
+  checksum: true
 local:
   db: ./backup.db
 EOF
@@ -54,10 +55,20 @@ if [ "$remote_hash_1" != "$local_hash_1" ]; then
   exit 1
 fi
 
+recorded_hash_1="$(./bsync list --db ./backup.db --json | jq -r ".[-1].sha256")"
+if [ "$remote_hash_1" != "$recorded_hash_1" ]; then
+  echo "[-] lsn_1 recorded checksum mismatch"
+  exit 1
+fi
+
 # Incremental update
 run_ssh "dd if=/dev/urandom of=/root/test.img bs=1M count=100 seek=600 conv=notrunc"
 ./bsync pull -c ./bsync.yaml
 lsn_2="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+if [ "$(./bsync list --db ./backup.db --json | jq ".[-1].meta.changed_blocks")" != "400" ]; then
+  echo "[-] lsn_2 metadata mismatch"
+  exit 1
+fi
 ./bsync replay --db ./backup.db --lsn "$lsn_2" --output ./replay.img
 remote_hash_2="$(run_ssh "sha256sum /root/test.img" | cut -d ' ' -f 1)"
 local_hash_2="$(sha256sum ./replay.img | cut -d ' ' -f 1)"
@@ -118,4 +129,121 @@ if [ "$local_hash_4" != "$remote_hash_4" ]; then
   exit 1
 fi
 
+# Shrink, then grow with zeros. Blocks past the new end must not come back.
+run_ssh "truncate -s 800M /root/test.img"
+./bsync pull -c ./bsync.yaml
+lsn_5="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+./bsync replay --db ./backup.db --lsn "$lsn_5" --output ./replay.img
+remote_hash_5="$(run_ssh "sha256sum /root/test.img" | cut -d ' ' -f 1)"
+local_hash_5="$(sha256sum ./replay.img | cut -d ' ' -f 1)"
+if [ "$local_hash_5" != "$remote_hash_5" ]; then
+  echo "[-] local_hash_5 mismatch"
+  exit 1
+fi
+
+run_ssh "truncate -s 1100M /root/test.img"
+./bsync pull -c ./bsync.yaml
+lsn_6="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+if [ "$lsn_6" = "$lsn_5" ]; then
+  echo "[-] resize did not create a new version"
+  exit 1
+fi
+./bsync replay --db ./backup.db --lsn "$lsn_6" --output ./replay.img
+remote_hash_6="$(run_ssh "sha256sum /root/test.img" | cut -d ' ' -f 1)"
+local_hash_6="$(sha256sum ./replay.img | cut -d ' ' -f 1)"
+if [ "$local_hash_6" != "$remote_hash_6" ]; then
+  echo "[-] local_hash_6 mismatch"
+  exit 1
+fi
+
+# A different device at the same path. Give the image an ext superblock with a UUID, then change it.
+run_ssh "printf '\\123\\357' | dd of=/root/test.img bs=1 seek=1080 conv=notrunc"
+run_ssh "head -c 16 /dev/urandom | dd of=/root/test.img bs=1 seek=1128 conv=notrunc"
+./bsync pull -c ./bsync.yaml
+run_ssh "head -c 16 /dev/urandom | dd of=/root/test.img bs=1 seek=1128 conv=notrunc"
+set +e
+./bsync pull -c ./bsync.yaml
+code=$?
+set -e
+if [ "$code" != "11" ]; then
+  echo "[-] identity change not detected (exit code $code)"
+  exit 1
+fi
+./bsync pull -c ./bsync.yaml --allow-identity-change
+# Later pulls compare against the new identity.
+./bsync pull -c ./bsync.yaml
+
+# The guard aborts a pull that changes far more blocks than the previous ones, and keeps aborting
+# it on retry.
+cp bsync.yaml guard.yaml
+cat >> guard.yaml << EOF
+guard:
+  action: abort
+  history: 3
+  change_factor: 2
+  min_change_fraction: 0.3
+EOF
+lsn_7="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+run_ssh "dd if=/dev/urandom of=/root/test.img bs=1M count=500 seek=100 conv=notrunc"
+for attempt in 1 2; do
+  set +e
+  ./bsync pull -c ./guard.yaml
+  code=$?
+  set -e
+  if [ "$code" != "12" ]; then
+    echo "[-] guard did not abort attempt $attempt (exit code $code)"
+    exit 1
+  fi
+done
+if [ "$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")" != "$lsn_7" ]; then
+  echo "[-] aborted pull created a version"
+  exit 1
+fi
+./bsync pull -c ./bsync.yaml
+lsn_8="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+./bsync replay --db ./backup.db --lsn "$lsn_8" --output ./replay.img
+remote_hash_8="$(run_ssh "sha256sum /root/test.img" | cut -d ' ' -f 1)"
+local_hash_8="$(sha256sum ./replay.img | cut -d ' ' -f 1)"
+if [ "$local_hash_8" != "$remote_hash_8" ]; then
+  echo "[-] local_hash_8 mismatch"
+  exit 1
+fi
+
+# Deleting versions leaves the others intact. Delete the oldest, a middle and the newest version,
+# then check every remaining one against the checksum recorded when it was pulled.
+for pick in '.[0]' '.[length / 2]' '.[-1]'; do
+  lsn="$(./bsync list --db ./backup.db --json | jq "$pick.lsn")"
+  ./bsync delete --db ./backup.db --lsn "$lsn" --data-loss
+done
+for lsn in $(./bsync list --db ./backup.db --json | jq '.[].lsn'); do
+  recorded_hash="$(./bsync list --db ./backup.db --json | jq -r ".[] | select(.lsn == $lsn) | .sha256")"
+  ./bsync replay --db ./backup.db --lsn "$lsn" --output ./replay.img
+  if [ "$(sha256sum ./replay.img | cut -d ' ' -f 1)" != "$recorded_hash" ]; then
+    echo "[-] lsn $lsn hash mismatch after delete"
+    exit 1
+  fi
+done
+
+# Salvage keeps the versions with their metadata, pins, locks and minimum age.
+oldest_lsn="$(./bsync list --db ./backup.db --json | jq ".[0].lsn")"
+latest_lsn="$(./bsync list --db ./backup.db --json | jq ".[-1].lsn")"
+./bsync protect --db ./backup.db --lsn "$oldest_lsn" --pin --reason "before the update"
+./bsync protect --db ./backup.db --lsn "$latest_lsn" --lock-until 2099-01-01 --reason worm
+./bsync protect --db ./backup.db --min-age-days 30
+./bsync salvage --from ./backup.db --to ./salvaged.db
+for cmd in "list --json" "--format json protect"; do
+  expected="$(./bsync $cmd --db ./backup.db | jq -S 'if type == "object" then del(.command, .status) else . end')"
+  actual="$(./bsync $cmd --db ./salvaged.db | jq -S 'if type == "object" then del(.command, .status) else . end')"
+  if [ "$expected" != "$actual" ]; then
+    echo "[-] salvage changed the output of $cmd"
+    exit 1
+  fi
+done
+./bsync replay --db ./salvaged.db --lsn "$latest_lsn" --output ./replay.img
+recorded_hash="$(./bsync list --db ./backup.db --json | jq -r ".[-1].sha256")"
+if [ "$(sha256sum ./replay.img | cut -d ' ' -f 1)" != "$recorded_hash" ]; then
+  echo "[-] salvaged latest version hash mismatch"
+  exit 1
+fi
+
 echo "[+] Test completed."